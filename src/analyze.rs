@@ -0,0 +1,447 @@
+//! Replays a `--record`ing through the detector for `--analyze`, starting
+//! each ping when the log written beside the recording says it started, to
+//! see what other detector settings would have measured.
+
+use crate::pipeline::{
+    write_measurement_line, Filtered, LineFormat, MeasurementFilter, NEGATIVE_DELAY,
+};
+use crate::writer::{self, Writer};
+use crate::{read_wav, write_measurement_json, Status};
+use audioping::{
+    DelayStats, DelayTracker, DetectorConfig, InputState, LatencyDetector, Probe, Summary,
+};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Input fed to the detector per call. Where detection windows fall doesn't
+/// depend on how the input is split up, so any length measures the same.
+const BLOCK_MS: f64 = 10.0;
+
+/// Where the replayed clock starts, as the detector takes a ping starting at
+/// 0 for no ping at all.
+const CLOCK_START_NS: f64 = 1e9;
+
+/// Where the ping log of the recording at `recording` is kept.
+pub fn ping_log_path(recording: &Path) -> PathBuf {
+    let mut path = recording.as_os_str().to_owned();
+    path.push(".pings");
+    PathBuf::from(path)
+}
+
+/// Notes when each ping started, in frames into the recording, for
+/// `--analyze` to start them again at the same point. The log is written on a
+/// thread of its own, so that the input callback only hands over each ping.
+pub struct PingLog {
+    pings: Writer<LoggedPing>,
+    frame_ns: f64,
+    /// When the recording's first frame was captured, once it has been.
+    start_ns: Option<f64>,
+    /// The latest ping start written for each output.
+    written: Vec<u64>,
+}
+
+impl PingLog {
+    /// Creates the log beside the recording at `recording` and starts the
+    /// thread that writes it. Once the `PingLog` is dropped, the thread writes
+    /// what's left and flushes the file, which joining the returned handle
+    /// waits for.
+    pub fn create(
+        recording: &Path,
+        sample_rate: u32,
+    ) -> anyhow::Result<(PingLog, JoinHandle<std::io::Result<()>>)> {
+        let mut file = BufWriter::new(File::create(ping_log_path(recording))?);
+        writeln!(
+            file,
+            "# audioping ping log: output, frame into the recording the ping started at, output channel"
+        )?;
+        let (pings, thread) = writer::spawn(
+            file,
+            |file, ping: &LoggedPing| {
+                let channel = ping
+                    .channel
+                    .map_or("all".to_owned(), |channel| channel.to_string());
+                writeln!(file, "{} {:.3} {}", ping.output, ping.frame, channel)
+            },
+            |mut file| file.flush(),
+        );
+        Ok((
+            PingLog {
+                pings,
+                frame_ns: 1e9 / sample_rate as f64,
+                start_ns: None,
+                written: Vec::new(),
+            },
+            thread,
+        ))
+    }
+
+    /// Notes a block of `frames` recorded by `frame_end_ns`, and queues any
+    /// ping `detectors` started since the last block to be written.
+    pub fn record(&mut self, frames: usize, frame_end_ns: u64, detectors: &[Arc<LatencyDetector>]) {
+        let start_ns = *self
+            .start_ns
+            .get_or_insert(frame_end_ns as f64 - frames as f64 * self.frame_ns);
+        self.written.resize(detectors.len(), 0);
+        for (output, detector) in detectors.iter().enumerate() {
+            let (signal_start_ns, channel) = match detector.ping_start() {
+                Some(start) if start.0 != self.written[output] => start,
+                _ => continue,
+            };
+            self.written[output] = signal_start_ns;
+            let frame = (signal_start_ns as f64 - start_ns) / self.frame_ns;
+            self.pings.send(|ping| {
+                *ping = LoggedPing {
+                    output,
+                    frame,
+                    channel,
+                }
+            });
+        }
+    }
+}
+
+/// One line of a ping log.
+#[derive(Default)]
+struct LoggedPing {
+    output: usize,
+    frame: f64,
+    channel: Option<usize>,
+}
+
+fn read_ping_log(path: &Path) -> anyhow::Result<Vec<LoggedPing>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => anyhow::bail!("can't read the ping log {}: {}", path.display(), err),
+    };
+    let mut pings = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let ping = parse_ping(&fields);
+        match ping {
+            // Pings from before the recording started can't be heard in it.
+            Some(ping) if ping.frame >= 0.0 => pings.push(ping),
+            Some(_) => {}
+            None => anyhow::bail!("line {} of {} isn't a ping", number + 1, path.display()),
+        }
+    }
+    pings.sort_by(|a, b| a.frame.total_cmp(&b.frame));
+    Ok(pings)
+}
+
+/// A ping from its output, frame and channel fields.
+fn parse_ping(fields: &[&str]) -> Option<LoggedPing> {
+    let [output, frame, channel] = fields else {
+        return None;
+    };
+    Some(LoggedPing {
+        output: output.parse().ok()?,
+        frame: frame.parse().ok()?,
+        channel: match *channel {
+            "all" => None,
+            channel => Some(channel.parse().ok()?),
+        },
+    })
+}
+
+pub struct AnalyzeSettings {
+    /// Settings for every output's detector. The sample rate and channels
+    /// are the recording's.
+    pub detector: DetectorConfig,
+    /// Whether the pings were started by what the input heard rather than
+    /// played, so that there's no log to read.
+    pub unlogged: bool,
+    pub timeout: Duration,
+    /// Subtracted from every delay.
+    pub offset_ms: f32,
+    pub max_delay_ms: Option<f32>,
+    pub min_confidence: f32,
+    /// How many delays each one printed is the average of.
+    pub average_count: usize,
+    /// Weight of each new delay in the smoothed delay.
+    pub alpha: f32,
+}
+
+/// Runs the detector over the recording at `path`, printing each delay it
+/// measures as a live run would, and returns their summary.
+pub fn run(
+    path: &Path,
+    settings: AnalyzeSettings,
+    json: bool,
+    status: &Status,
+) -> anyhow::Result<Option<Summary>> {
+    let (samples, spec) = read_wav(path, "recording")?;
+    let channels = spec.channels.max(1) as usize;
+    let config = DetectorConfig {
+        sample_rate: spec.sample_rate as f32,
+        input_channels: channels,
+        on_request: true,
+        ..settings.detector
+    };
+    if let Probe::Wav { sample_rate, .. } = config.probe {
+        if sample_rate != spec.sample_rate {
+            anyhow::bail!(
+                "the probe WAV file is at {}Hz but the recording is at {}Hz",
+                sample_rate,
+                spec.sample_rate
+            );
+        }
+    }
+    for (name, channel) in [
+        Some(("input", config.input_channel)),
+        config
+            .reference_channel
+            .map(|channel| ("reference", channel)),
+        config.sync_channel.map(|channel| ("sync", channel)),
+        config.compare_channel.map(|channel| ("right", channel)),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        if channel >= channels {
+            anyhow::bail!(
+                "{} channel {} does not exist, the recording has {} channels",
+                name,
+                channel,
+                channels
+            );
+        }
+    }
+    let pings = if settings.unlogged {
+        Vec::new()
+    } else {
+        read_ping_log(&ping_log_path(path))?
+    };
+    let outputs = pings.iter().map(|ping| ping.output + 1).max().unwrap_or(1);
+    let detectors: Vec<LatencyDetector> = (0..outputs)
+        .map(|_| LatencyDetector::new(config.clone()))
+        .collect();
+    status.print(&format!(
+        "Replaying {:.1}s of {} at {}Hz with {} pings",
+        (samples.len() / channels) as f64 / spec.sample_rate as f64,
+        path.display(),
+        spec.sample_rate,
+        pings.len()
+    ));
+
+    let frame_ns = 1e9 / spec.sample_rate as f64;
+    let block_frames = ((BLOCK_MS * spec.sample_rate as f64 / 1000.0) as usize).max(1);
+    let timeout_ns = settings.timeout.as_nanos() as u64;
+    let mut pings = pings.into_iter().peekable();
+    let mut filter = MeasurementFilter::new(
+        settings.offset_ms,
+        settings.max_delay_ms,
+        settings.min_confidence,
+        settings.average_count,
+    );
+    let line_format = LineFormat {
+        decimals: if config.precise { 4 } else { 2 },
+        units: "ms".to_owned(),
+        sample_rate: config.sample_rate,
+        clock: false,
+        channel: true,
+        average_count: settings.average_count,
+        window: String::new(),
+    };
+    let mut input_states: Vec<InputState> =
+        detectors.iter().map(LatencyDetector::input_state).collect();
+    let mut trackers: Vec<DelayTracker> = detectors
+        .iter()
+        .map(|_| DelayTracker::new(settings.alpha))
+        .collect();
+    let mut delays = DelayStats::new();
+    let mut line = String::new();
+    for (index, block) in samples.chunks(block_frames * channels).enumerate() {
+        let end_frame = index * block_frames + block.len() / channels;
+        // The detector saw a ping from the first input callback after it
+        // started. Whether the one before it had timed out by then was
+        // checked as it started, as it is live.
+        while let Some(ping) = pings.next_if(|ping| ping.frame < end_frame as f64) {
+            let signal_start_ns = (CLOCK_START_NS + ping.frame * frame_ns) as u64;
+            let detector = &detectors[ping.output];
+            if detector.check_timeout(signal_start_ns, timeout_ns) {
+                print_timeout(status, signal_start_ns);
+            }
+            detector.replay_ping(signal_start_ns, ping.channel);
+        }
+        let frame_end_ns = (CLOCK_START_NS + end_frame as f64 * frame_ns) as u64;
+        for (output, detector) in detectors.iter().enumerate() {
+            if detector.check_timeout(frame_end_ns, timeout_ns) {
+                print_timeout(status, frame_end_ns);
+            }
+            let measurement = match detector.process_input(
+                &mut input_states[output],
+                block,
+                frame_end_ns,
+                None,
+            ) {
+                Some(measurement) => measurement,
+                None => continue,
+            };
+            let at = recording_seconds(measurement.timestamp_ns);
+            let (mut measurement, average_stddev_ms) = match filter.push(measurement) {
+                Filtered::Kept(measurement, average_stddev_ms) => (measurement, average_stddev_ms),
+                Filtered::Averaging => continue,
+                Filtered::Negative => {
+                    status.print(&format!("{:.3}s: {}", at, NEGATIVE_DELAY));
+                    continue;
+                }
+                Filtered::Discarded(reason) => {
+                    status.print(&format!("{:.3}s: {}", at, reason));
+                    continue;
+                }
+            };
+            let trend = trackers[output].push(measurement.delay_ms);
+            delays.push(measurement.timestamp_ns, measurement.delay_ms);
+            line.clear();
+            if json {
+                // Timed from the start of the recording rather than the run.
+                measurement.timestamp_ns -= CLOCK_START_NS as u64;
+                write_measurement_json(
+                    &mut line,
+                    &measurement,
+                    &trend,
+                    None,
+                    average_stddev_ms,
+                    None,
+                    None,
+                );
+            } else {
+                // The ping log doesn't name the outputs, so they go by number.
+                let output_name = output.to_string();
+                let _ = write!(line, "{:.3}s: ", at);
+                write_measurement_line(
+                    &mut line,
+                    &measurement,
+                    &trend,
+                    None,
+                    average_stddev_ms,
+                    (outputs > 1).then_some(("output", output_name.as_str())),
+                    &line_format,
+                );
+            }
+            println!("{}", line);
+        }
+    }
+    Ok(delays.summary())
+}
+
+fn print_timeout(status: &Status, timestamp_ns: u64) {
+    status.print(&format!(
+        "{:.3}s: Timeout: no signal returned",
+        recording_seconds(timestamp_ns)
+    ));
+}
+
+/// Seconds into the recording at `timestamp_ns` on the replayed clock.
+fn recording_seconds(timestamp_ns: u64) -> f64 {
+    (timestamp_ns as f64 - CLOCK_START_NS) / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ping_log_reads_back_the_pings_recorded() {
+        let recording =
+            std::env::temp_dir().join(format!("audioping-test-{}.wav", std::process::id()));
+        let (mut log, thread) = PingLog::create(&recording, 48000).unwrap();
+        let detectors: Vec<Arc<LatencyDetector>> = (0..2)
+            .map(|_| Arc::new(LatencyDetector::new(DetectorConfig::default())))
+            .collect();
+        // The recording starts 10ms, 480 frames, before its first block ends.
+        log.record(480, 1_000_000_000, &detectors);
+        detectors[1].replay_ping(1_010_000_000, Some(1));
+        detectors[0].replay_ping(1_000_000_000, None);
+        log.record(480, 1_010_000_000, &detectors);
+        // Each ping is only logged once.
+        log.record(480, 1_020_000_000, &detectors);
+        drop(log);
+        thread.join().unwrap().unwrap();
+
+        let pings = read_ping_log(&ping_log_path(&recording));
+        let _ = std::fs::remove_file(ping_log_path(&recording));
+        let pings: Vec<(usize, f64, Option<usize>)> = pings
+            .unwrap()
+            .iter()
+            .map(|ping| (ping.output, ping.frame, ping.channel))
+            .collect();
+        assert_eq!(pings, [(0, 480.0, None), (1, 960.0, Some(1))]);
+    }
+
+    #[test]
+    fn a_two_input_run_replays_each_ping_once() {
+        let recording =
+            std::env::temp_dir().join(format!("audioping-inputs-{}.wav", std::process::id()));
+        let (mut log, thread) = PingLog::create(&recording, 48000).unwrap();
+        // As a session with one output and two inputs has them: the output's
+        // detector, then one following its pings on the second input.
+        let detectors: Vec<Arc<LatencyDetector>> = (0..2)
+            .map(|_| Arc::new(LatencyDetector::new(DetectorConfig::default())))
+            .collect();
+        let output_detectors = &detectors[..1];
+        log.record(480, 1_010_000_000, output_detectors);
+        // A ping 4800 frames into the recording, heard 733 frames later.
+        for detector in &detectors {
+            detector.replay_ping(1_100_000_000, None);
+        }
+        log.record(480, 1_110_000_000, output_detectors);
+        drop(log);
+        thread.join().unwrap().unwrap();
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 48000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&recording, spec).unwrap();
+        for frame in 0..48000u64 {
+            let clock = (frame + 1).saturating_sub(4800 + 733);
+            writer
+                .write_sample(0.5 * Probe::Tone.value(440.0, 48000.0, clock))
+                .unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let settings = AnalyzeSettings {
+            detector: DetectorConfig {
+                sensitivity: 0.25,
+                ..DetectorConfig::default()
+            },
+            unlogged: false,
+            timeout: Duration::from_secs(1),
+            offset_ms: 0.0,
+            max_delay_ms: None,
+            min_confidence: 0.0,
+            average_count: 1,
+            alpha: 0.1,
+        };
+        let status = Status {
+            quiet: true,
+            to_stderr: true,
+        };
+        let summary = run(&recording, settings, false, &status);
+        let _ = std::fs::remove_file(&recording);
+        let _ = std::fs::remove_file(ping_log_path(&recording));
+        let summary = summary.unwrap().unwrap();
+        assert_eq!(summary.count, 1);
+        assert!((summary.mean - 733.0 / 48.0).abs() <= 1000.0 / 440.0);
+    }
+
+    #[test]
+    fn ping_log_lines_need_every_field() {
+        assert!(parse_ping(&["0", "480.000", "all"]).is_some());
+        assert!(parse_ping(&["0", "480.000"]).is_none());
+        assert!(parse_ping(&["0", "frame", "all"]).is_none());
+        assert!(parse_ping(&["0", "480.000", "left"]).is_none());
+    }
+}
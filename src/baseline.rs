@@ -0,0 +1,74 @@
+//! A run's summary saved as TOML with `--save-summary`, and compared against
+//! with `--baseline`.
+
+use audioping::Summary;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// The statistics of a saved run, in milliseconds.
+#[derive(Serialize, Deserialize)]
+pub struct Baseline {
+    pub count: usize,
+    pub min_ms: f32,
+    pub max_ms: f32,
+    pub mean_ms: f32,
+    pub median_ms: f32,
+    pub stddev_ms: f32,
+    pub p95_ms: f32,
+    pub p99_ms: f32,
+    pub jitter_ms: f32,
+}
+
+impl Baseline {
+    pub fn from_summary(summary: &Summary) -> Baseline {
+        Baseline {
+            count: summary.count,
+            min_ms: summary.min,
+            max_ms: summary.max,
+            mean_ms: summary.mean,
+            median_ms: summary.median,
+            stddev_ms: summary.stddev,
+            p95_ms: summary.p95,
+            p99_ms: summary.p99,
+            jitter_ms: summary.jitter,
+        }
+    }
+
+    /// Reads a summary saved with `save`.
+    pub fn load(path: &Path) -> anyhow::Result<Baseline> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) => anyhow::bail!("can't read baseline {}: {}", path.display(), err),
+        };
+        Ok(toml::from_str(&text)?)
+    }
+
+    /// Writes the summary to `path`, replacing anything already there.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Prints how far `summary` is from the baseline, as a JSON line if
+    /// `json` is set.
+    pub fn print_comparison(&self, summary: &Summary, json: bool) {
+        let (mean, median, p95) = (
+            summary.mean - self.mean_ms,
+            summary.median - self.median_ms,
+            summary.p95 - self.p95_ms,
+        );
+        if json {
+            println!(
+                "{{\"baseline_count\":{},\"mean_delta_ms\":{},\"median_delta_ms\":{},\"p95_delta_ms\":{}}}",
+                self.count, mean, median, p95
+            );
+            return;
+        }
+        println!("--- versus the baseline of {} measurements ---", self.count);
+        println!(
+            "mean/median/p95 = {:+.2}/{:+.2}/{:+.2}ms",
+            mean, median, p95
+        );
+    }
+}
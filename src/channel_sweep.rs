@@ -0,0 +1,102 @@
+//! Moves the probe across every output channel in turn for
+//! `--sweep-channels`, and tallies which channels it came back from.
+
+use audioping::{DelayStats, LatencyDetector};
+
+/// What the pings played on one channel came to.
+#[derive(Default)]
+struct ChannelResult {
+    /// Pings that came back, including any discarded afterwards.
+    returns: u64,
+    timeouts: u64,
+    delays: DelayStats,
+}
+
+pub struct ChannelSweep {
+    results: Vec<ChannelResult>,
+    /// The channel the current ping is played on.
+    current: usize,
+}
+
+impl ChannelSweep {
+    pub fn new(channels: usize) -> ChannelSweep {
+        ChannelSweep {
+            results: (0..channels).map(|_| ChannelResult::default()).collect(),
+            // So that the first ping goes to the first channel.
+            current: channels - 1,
+        }
+    }
+
+    /// Moves `detector`'s probe on to the next channel, for the ping about to
+    /// be requested.
+    pub fn advance(&mut self, detector: &LatencyDetector) {
+        self.current = (self.current + 1) % self.results.len();
+        detector.set_output_channel(Some(self.current));
+    }
+
+    /// The channel the current ping is played on.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Counts a ping on `channel` as having come back, whether or not its
+    /// delay is kept.
+    pub fn returned(&mut self, channel: usize) {
+        if let Some(result) = self.results.get_mut(channel) {
+            result.returns += 1;
+        }
+    }
+
+    pub fn record(&mut self, channel: usize, timestamp_ns: u64, delay_ms: f32) {
+        if let Some(result) = self.results.get_mut(channel) {
+            result.delays.push(timestamp_ns, delay_ms);
+        }
+    }
+
+    pub fn timed_out(&mut self) {
+        self.results[self.current].timeouts += 1;
+    }
+
+    /// Prints each channel's median delay, flagging any channel that was
+    /// pinged but never heard back from.
+    pub fn print(&self, json: bool) {
+        if json {
+            let channels: Vec<String> = self
+                .results
+                .iter()
+                .enumerate()
+                .map(|(channel, result)| {
+                    let median = match result.delays.summary() {
+                        Some(summary) => summary.median.to_string(),
+                        None => "null".to_string(),
+                    };
+                    format!(
+                        "{{\"channel\":{},\"returns\":{},\"timeouts\":{},\"median_ms\":{}}}",
+                        channel, result.returns, result.timeouts, median
+                    )
+                })
+                .collect();
+            println!("{{\"channels\":[{}]}}", channels.join(","));
+            return;
+        }
+
+        println!("--- {} output channels ---", self.results.len());
+        for (channel, result) in self.results.iter().enumerate() {
+            match result.delays.summary() {
+                Some(summary) => println!(
+                    "channel {}: {} returned, {} timed out, median {:.2}ms",
+                    channel, result.returns, result.timeouts, summary.median
+                ),
+                None if result.returns > 0 => println!(
+                    "channel {}: {} returned, {} timed out, none kept",
+                    channel, result.returns, result.timeouts
+                ),
+                None if result.timeouts > 0 => println!(
+                    "channel {}: NO RETURN in {} pings, check that it's wired and routed",
+                    channel, result.timeouts
+                ),
+                None => println!("channel {}: not pinged", channel),
+            }
+        }
+    }
+}
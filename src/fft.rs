@@ -0,0 +1,216 @@
+//! Windowed FFT analysis for [`Detector::Fft`](super::Detector::Fft), which
+//! measures the probe by the energy in its bin relative to the bins around
+//! it, so that other sound near the probe frequency doesn't trigger it.
+
+use super::{history_sample, window_ends, without_reference, Detection};
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// Bins either side of the probe's main lobe averaged for the level the
+/// probe has to stand out from.
+const NEIGHBOUR_BINS: usize = 8;
+
+/// How many times the neighbouring bins' average magnitude the probe's bin
+/// must reach, about 12dB, for a window to count as holding the probe.
+const MIN_NEIGHBOUR_RATIO: f32 = 4.0;
+
+pub struct FftAnalyzer {
+    fft: Arc<dyn Fft<f32>>,
+    /// Frames between the ends of consecutive windows.
+    hop: usize,
+    /// Bin nearest the probe frequency.
+    bin: usize,
+    /// Probe frequency in radians per frame.
+    omega: f64,
+    /// Frames in half a period of the probe.
+    half_period: usize,
+    /// Interleaved frames from before the block being scanned, so that a
+    /// window can reach back across a block shorter than it.
+    history: Vec<f32>,
+    /// Frames of input before the block being scanned.
+    frames_before: u64,
+    buffer: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl FftAnalyzer {
+    pub fn new(size: usize, hop: usize, frequency: f32, sample_rate: f32) -> FftAnalyzer {
+        let size = size.max(4);
+        let fft = FftPlanner::new().plan_fft_forward(size);
+        let bin = (frequency * size as f32 / sample_rate).round() as usize;
+        FftAnalyzer {
+            scratch: vec![Complex::default(); fft.get_inplace_scratch_len()],
+            fft,
+            hop: hop.max(1),
+            bin: bin.clamp(1, size / 2 - 1),
+            omega: std::f64::consts::TAU * frequency as f64 / sample_rate as f64,
+            half_period: ((sample_rate / frequency / 2.0).round() as usize).max(1),
+            history: Vec::new(),
+            frames_before: 0,
+            buffer: vec![Complex::default(); size],
+        }
+    }
+
+    /// Scans the interleaved block `data` with windows ending where
+    /// `window_ends` places them, `hop` frames apart, and triggers on the
+    /// first window whose probe amplitude exceeds `threshold`. The onset may
+    /// be in an earlier block, as a long window can take more than a block to
+    /// fill with enough of the probe.
+    pub fn detect(
+        &mut self,
+        data: &[f32],
+        channels: usize,
+        channel: usize,
+        reference_channel: Option<usize>,
+        threshold: f32,
+    ) -> Option<Detection> {
+        let history_frames = self.history.len() / channels;
+        let frames = data.len() / channels;
+        let total_frames = history_frames + frames;
+        let mut trigger = None;
+        let mut amplitude = 0f32;
+        for window_end in window_ends(self.frames_before, history_frames, frames, self.hop) {
+            let window_start = window_end.saturating_sub(self.buffer.len());
+            let mut window =
+                self.window_amplitude(data, channels, channel, window_start, window_end);
+            if let Some(reference) = reference_channel {
+                let reference_window =
+                    self.window_amplitude(data, channels, reference, window_start, window_end);
+                window = without_reference(window, reference_window);
+            }
+            amplitude = amplitude.max(window);
+            if trigger.is_none() && window > threshold {
+                trigger = Some(window_end);
+            }
+        }
+
+        let onset = self.onset(data, channels, channel, trigger?);
+        Some(Detection {
+            signal_count: (total_frames - onset) as u32,
+            amplitude,
+        })
+    }
+
+    /// Where the probe heard in the window ending at `trigger_end` started.
+    /// Other sound would throw out a search for the first loud sample, so
+    /// this goes by the probe frequency alone: its amplitude over a
+    /// rectangular window of the FFT's length grows in proportion to how much
+    /// of the window the probe fills, so the onset is as far before the point
+    /// where that amplitude was half what it is at `trigger_end` as that point
+    /// is before `trigger_end`.
+    fn onset(&self, data: &[f32], channels: usize, channel: usize, trigger_end: usize) -> usize {
+        let size = self.buffer.len();
+        let smoothing = self.half_period;
+        let first = trigger_end.saturating_sub(2 * size + smoothing);
+        // Running sums of the input shifted down by the probe frequency, from
+        // which the probe's amplitude over any window is a difference.
+        let mut sums = Vec::with_capacity(trigger_end - first + 1);
+        let mut sum = Complex::<f32>::default();
+        sums.push(sum);
+        for frame in first..trigger_end {
+            let phase = (self.omega * (frame - first) as f64) % std::f64::consts::TAU;
+            let sample = history_sample(&self.history, data, channels, channel, frame);
+            sum += Complex::from_polar(sample, -phase as f32);
+            sums.push(sum);
+        }
+        let window =
+            |end: usize| sums[end - first] - sums[end.saturating_sub(size).max(first) - first];
+        // A real tone also leaves a ripple at twice its frequency, which
+        // cancels out over half a period, delaying the amplitude by half
+        // that.
+        let amplitude_at = |end: usize| {
+            (0..smoothing)
+                .map(|back| window(end - back))
+                .sum::<Complex<f32>>()
+                .norm()
+        };
+        let delay = (smoothing - 1) as f32 / 2.0;
+
+        let last = (first + smoothing).max(trigger_end.saturating_sub(size));
+        let half = amplitude_at(trigger_end) / 2.0;
+        let mut later_amplitude = 2.0 * half;
+        for end in (last..trigger_end).rev() {
+            let amplitude = amplitude_at(end);
+            if amplitude <= half {
+                let fraction =
+                    (later_amplitude - half) / (later_amplitude - amplitude).max(f32::EPSILON);
+                let half_end = end as f32 + 1.0 - fraction - delay;
+                let onset = 2.0 * half_end - (trigger_end as f32 - delay);
+                return onset.max(0.0).round() as usize;
+            }
+            later_amplitude = amplitude;
+        }
+        last
+    }
+
+    /// Keeps the end of the interleaved block `data`, once every detection
+    /// on it is done, for windows in the next block to reach back into.
+    pub fn remember(&mut self, data: &[f32], channels: usize) {
+        self.frames_before += (data.len() / channels) as u64;
+        self.history.extend_from_slice(data);
+        let keep = (self.buffer.len() + self.hop) * channels;
+        let excess = self.history.len().saturating_sub(keep);
+        self.history.drain(..excess);
+    }
+
+    /// Peak-to-peak amplitude of a tone at the probe frequency on `channel`
+    /// in frames `start` to `end`, counted from the start of the history, or
+    /// 0.0 if the probe's bin doesn't stand out from its neighbours. A window
+    /// shorter than the FFT is zero padded.
+    fn window_amplitude(
+        &mut self,
+        data: &[f32],
+        channels: usize,
+        channel: usize,
+        start: usize,
+        end: usize,
+    ) -> f32 {
+        let count = end - start;
+        if count == 0 {
+            return 0.0;
+        }
+        let mut window_sum = 0f32;
+        for (i, slot) in self.buffer.iter_mut().enumerate() {
+            let frame = start + i;
+            *slot = if i < count {
+                let hann = (PI * (i as f32 + 0.5) / count as f32).sin().powi(2);
+                window_sum += hann;
+                Complex::new(
+                    history_sample(&self.history, data, channels, channel, frame) * hann,
+                    0.0,
+                )
+            } else {
+                Complex::default()
+            };
+        }
+        self.fft
+            .process_with_scratch(&mut self.buffer, &mut self.scratch);
+
+        let size = self.buffer.len();
+        let nyquist = size / 2;
+        let magnitude = |bin: usize| self.buffer[bin].norm();
+        // The probe may fall between bins, so its peak is looked for in the
+        // bins either side too.
+        let peak = (self.bin - 1..=self.bin + 1)
+            .map(magnitude)
+            .fold(0f32, f32::max);
+        // A Hann window's main lobe is two of its own bins wide either side,
+        // more of the FFT's when zero padded.
+        let lobe = (2 * size).div_ceil(count) + 1;
+        let below = self.bin.saturating_sub(lobe + NEIGHBOUR_BINS).max(1)
+            ..self.bin.saturating_sub(lobe).max(1);
+        let above =
+            (self.bin + lobe + 1).min(nyquist)..(self.bin + lobe + 1 + NEIGHBOUR_BINS).min(nyquist);
+        let neighbours = below.len() + above.len();
+        if neighbours > 0 {
+            let neighbour_level =
+                below.chain(above).map(magnitude).sum::<f32>() / neighbours as f32;
+            if peak < MIN_NEIGHBOUR_RATIO * neighbour_level {
+                return 0.0;
+            }
+        }
+        4.0 * peak / window_sum
+    }
+}
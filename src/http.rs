@@ -0,0 +1,97 @@
+//! A minimal HTTP server for `--http-addr`, serving the latest metrics on
+//! `/metrics` and the latest measurement and summary on `/status.json`.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long a client may take to send its request or read the response
+/// before it's dropped, so that one stalled client can't hold up the others.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The documents served, replaced as each measurement is recorded.
+pub struct Pages {
+    /// Prometheus text exposition of the latest measurement.
+    pub metrics: String,
+    pub status_json: String,
+}
+
+/// Starts serving on `addr`, `HOST:PORT`, and returns the pages to keep up
+/// to date. Requests are answered one at a time on their own thread, from
+/// whatever the pages held when they arrived.
+pub fn spawn(addr: &str) -> anyhow::Result<Arc<Mutex<Pages>>> {
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(err) => anyhow::bail!("can't serve HTTP on {}: {}", addr, err),
+    };
+    let pages = Arc::new(Mutex::new(Pages {
+        metrics: String::new(),
+        status_json: "{\"count\":0}".to_string(),
+    }));
+    let served_pages = Arc::clone(&pages);
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = respond(stream, &served_pages);
+        }
+    });
+    Ok(pages)
+}
+
+/// Fails if `addr` isn't a `HOST:PORT` that resolves, without binding it,
+/// for `--dry-run`.
+pub fn check(addr: &str) -> anyhow::Result<()> {
+    if let Err(err) = addr.to_socket_addrs() {
+        anyhow::bail!("can't serve HTTP on {}: {}", addr, err);
+    }
+    Ok(())
+}
+
+/// Reads one request from `stream` and answers it, closing the connection
+/// afterwards.
+fn respond(mut stream: TcpStream, pages: &Mutex<Pages>) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers say nothing these pages depend on, but have to be read
+    // before the response is sent.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let (status, content_type, body) = match (method, path) {
+        ("GET" | "HEAD", "/metrics") => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            pages.lock().unwrap().metrics.clone(),
+        ),
+        ("GET" | "HEAD", "/status.json") => (
+            "200 OK",
+            "application/json",
+            pages.lock().unwrap().status_json.clone(),
+        ),
+        ("GET" | "HEAD", _) => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n".to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    if method != "HEAD" {
+        stream.write_all(body.as_bytes())?;
+    }
+    stream.flush()
+}
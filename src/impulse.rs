@@ -0,0 +1,74 @@
+//! Impulse responses deconvolved from a recorded sweep, for a latency taken
+//! from the response's first arrival.
+
+use super::parabolic_offset;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+/// Power, relative to the sweep's strongest bin, added to every bin before
+/// dividing by it, so that frequencies the sweep never reached don't turn
+/// what little noise the capture has there into a roar.
+const REGULARIZATION: f32 = 1e-6;
+
+/// Fraction of a response's peak magnitude whose first crossing marks the
+/// lobe of its first arrival, so that a reflection louder than the direct
+/// sound doesn't hide it.
+const FIRST_ARRIVAL_FRACTION: f32 = 0.5;
+
+/// How many times a response's RMS its peak must reach to be told from
+/// noise.
+const MIN_CREST_FACTOR: f32 = 10.0;
+
+/// Impulse response of whatever turned `excitation` into `capture`, both
+/// starting at the same frame, found by dividing their spectra. It's as long
+/// as `capture`, and scaled so that a loopback at unity gain peaks at 1.0.
+pub fn impulse_response(excitation: &[f32], capture: &[f32]) -> Vec<f32> {
+    // Long enough that the division's circular convolution doesn't wrap.
+    let size = (excitation.len() + capture.len()).next_power_of_two();
+    let mut planner = FftPlanner::new();
+    let forward = planner.plan_fft_forward(size);
+    let spectrum = |samples: &[f32]| {
+        let mut buffer: Vec<Complex<f32>> = samples
+            .iter()
+            .map(|sample| Complex::new(*sample, 0.0))
+            .collect();
+        buffer.resize(size, Complex::default());
+        forward.process(&mut buffer);
+        buffer
+    };
+    let excitation = spectrum(excitation);
+    let mut response = spectrum(capture);
+    let floor = REGULARIZATION
+        * excitation
+            .iter()
+            .map(|bin| bin.norm_sqr())
+            .fold(0.0, f32::max);
+    for (bin, excitation) in response.iter_mut().zip(&excitation) {
+        *bin = *bin * excitation.conj() / (excitation.norm_sqr() + floor);
+    }
+    planner.plan_fft_inverse(size).process(&mut response);
+    response
+        .iter()
+        .take(capture.len())
+        .map(|bin| bin.re / size as f32)
+        .collect()
+}
+
+/// Fractional frame of the first arrival in `response`: the top of the first
+/// lobe reaching half its peak magnitude. Returns `None` if the peak doesn't
+/// stand out from the rest of the response as an arrival would.
+pub fn first_arrival(response: &[f32]) -> Option<f64> {
+    let magnitude: Vec<f32> = response.iter().map(|sample| sample.abs()).collect();
+    let peak = magnitude.iter().copied().fold(0f32, f32::max);
+    let rms = (magnitude.iter().map(|m| m * m).sum::<f32>() / magnitude.len().max(1) as f32).sqrt();
+    if peak <= 0.0 || peak < MIN_CREST_FACTOR * rms {
+        return None;
+    }
+    let mut top = magnitude
+        .iter()
+        .position(|m| *m >= FIRST_ARRIVAL_FRACTION * peak)?;
+    while top + 1 < magnitude.len() && magnitude[top + 1] > magnitude[top] {
+        top += 1;
+    }
+    Some(top as f64 + parabolic_offset(&magnitude, top))
+}
@@ -0,0 +1,196 @@
+//! Plays one sweep for `--impulse`, captures everything that comes back and
+//! deconvolves it into the loopback's impulse response.
+
+use audioping::{
+    first_arrival, impulse_response, start_input_stream, start_output_stream, StreamSpec,
+};
+use cpal::traits::StreamTrait;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Silence played before the sweep, so that the input is running and settled
+/// by the time it starts.
+const LEAD_IN: Duration = Duration::from_millis(250);
+
+/// How long past the end of the capture window to wait for the streams
+/// before giving up on them.
+const GRACE: Duration = Duration::from_secs(2);
+
+pub struct ImpulseSettings {
+    /// The sweep as played, volume and gain included.
+    pub excitation: Vec<f32>,
+    /// Output channel to play it on, or every channel if `None`.
+    pub output_channel: Option<usize>,
+    pub input_channel: usize,
+    pub input_gain: f32,
+    /// How long to keep capturing after the sweep has been played, which is
+    /// as long as the impulse response can be.
+    pub tail: Duration,
+    /// Subtracted from the latency, as from every delay.
+    pub offset_ms: f32,
+}
+
+/// Measures the impulse response from `output` to `input`, prints its first
+/// arrival as the latency, and writes the response to `wav_path` if given.
+pub fn run(
+    output: &StreamSpec,
+    input: &StreamSpec,
+    settings: ImpulseSettings,
+    json: bool,
+    wav_path: Option<&Path>,
+) -> anyhow::Result<()> {
+    let sample_rate = input.config.sample_rate.0;
+    let frame_ns = 1e9 / sample_rate as f64;
+    let tail_frames = (settings.tail.as_secs_f64() * sample_rate as f64).round() as usize;
+    let window_frames = settings.excitation.len() + tail_frames;
+    let start_time = Instant::now();
+
+    // When the sweep's first frame played, once it has.
+    let sweep_start_ns = Arc::new(AtomicU64::new(u64::MAX));
+    let output_sweep_start_ns = Arc::clone(&sweep_start_ns);
+    let excitation = Arc::new(settings.excitation);
+    let output_excitation = Arc::clone(&excitation);
+    let output_channels = (output.config.channels as usize).max(1);
+    let output_channel = settings.output_channel;
+    let mut clock = 0;
+    let output_fn = Arc::new(Mutex::new(
+        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+            let frame_start_ns = start_time.elapsed().as_nanos() as u64;
+            if frame_start_ns < LEAD_IN.as_nanos() as u64 {
+                data.fill(0f32);
+                return;
+            }
+            if clock == 0 {
+                output_sweep_start_ns.store(frame_start_ns, Ordering::SeqCst);
+            }
+            for frame in data.chunks_mut(output_channels) {
+                let value = output_excitation.get(clock).copied().unwrap_or_default();
+                clock += 1;
+                for (channel, sample) in frame.iter_mut().enumerate() {
+                    *sample = if output_channel.is_none_or(|c| c == channel) {
+                        value
+                    } else {
+                        0f32
+                    };
+                }
+            }
+        },
+    ));
+
+    // The input channel from its first callback on, and when its first
+    // frame was captured.
+    let capture = Arc::new(Mutex::new((None::<f64>, Vec::<f32>::new())));
+    let input_capture = Arc::clone(&capture);
+    let input_channels = (input.config.channels as usize).max(1);
+    let (input_channel, input_gain) = (settings.input_channel, settings.input_gain);
+    let input_fn = Arc::new(Mutex::new(
+        move |data: &[f32], _: &cpal::InputCallbackInfo| {
+            // Callback times mark the end of the block just captured.
+            let frame_end_ns = start_time.elapsed().as_nanos() as f64;
+            let frames = data.len() / input_channels;
+            let mut capture = input_capture.lock().unwrap();
+            let (start_ns, samples) = &mut *capture;
+            start_ns.get_or_insert(frame_end_ns - frames as f64 * frame_ns);
+            samples.extend(
+                data.chunks_exact(input_channels)
+                    .map(|frame| frame[input_channel] * input_gain),
+            );
+        },
+    ));
+
+    // A failing stream shows up as a capture that never fills.
+    let (errors, _) = channel();
+    let input_stream = start_input_stream(
+        &input.device,
+        &input.config,
+        input.format,
+        &input_fn,
+        &errors,
+    )?;
+    let output_stream = start_output_stream(
+        &output.device,
+        &output.config,
+        output.format,
+        &output_fn,
+        &errors,
+        false,
+    )?;
+    input_stream.play()?;
+    output_stream.play()?;
+
+    let window = Duration::from_secs_f64(window_frames as f64 * frame_ns / 1e9);
+    let deadline = start_time + LEAD_IN + window + GRACE;
+    let offset = loop {
+        std::thread::sleep(Duration::from_millis(10));
+        let sweep_start_ns = sweep_start_ns.load(Ordering::SeqCst);
+        let (start_ns, samples) = &*capture.lock().unwrap();
+        if let (Some(start_ns), true) = (start_ns, sweep_start_ns != u64::MAX) {
+            // Frames into the capture at which the sweep started playing.
+            let offset = (sweep_start_ns as f64 - start_ns) / frame_ns;
+            if offset < 0.0 {
+                anyhow::bail!("the input only started after the sweep had begun");
+            }
+            if samples.len() >= offset as usize + window_frames {
+                break offset;
+            }
+        }
+        if Instant::now() >= deadline {
+            anyhow::bail!("the streams stopped before the sweep and its tail were captured");
+        }
+    };
+    drop(output_stream);
+    drop(input_stream);
+
+    let first = offset as usize;
+    let samples = &capture.lock().unwrap().1;
+    let mut response = impulse_response(&excitation, &samples[first..first + window_frames]);
+    // Past the tail, the response would need capture from after the window.
+    response.truncate(tail_frames);
+    let peak = response
+        .iter()
+        .fold(0f32, |peak, sample| peak.max(sample.abs()));
+    if let Some(path) = wav_path {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        for sample in &response {
+            writer.write_sample(*sample)?;
+        }
+        writer.finalize()?;
+    }
+    let arrival = match first_arrival(&response) {
+        Some(arrival) => arrival,
+        None => anyhow::bail!(
+            "no arrival stands out of the impulse response; check the cabling and the volume"
+        ),
+    };
+    // The capture was cut at a whole frame, short of where the sweep began.
+    let latency_ms = (arrival - offset.fract()) * frame_ns / 1e6 - settings.offset_ms as f64;
+    let peak_dbfs = 20.0 * peak.log10();
+    if json {
+        println!(
+            "{{\"impulse_latency_ms\":{},\"peak_dbfs\":{},\"response_ms\":{}}}",
+            latency_ms,
+            peak_dbfs,
+            settings.tail.as_secs_f64() * 1000.0
+        );
+    } else {
+        println!(
+            "Impulse response: first arrival at {:.3}ms, peak {:.1}dBFS, {:.0}ms long",
+            latency_ms,
+            peak_dbfs,
+            settings.tail.as_secs_f64() * 1000.0
+        );
+    }
+    if let Some(path) = wav_path {
+        println!("Wrote the impulse response to {}", path.display());
+    }
+    Ok(())
+}
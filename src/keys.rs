@@ -0,0 +1,108 @@
+//! Keyboard controls for a run, enabled with `--keys`.
+
+use crate::StopReason;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::terminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How often the key thread checks whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What a key press asks the run to do.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    /// `p`: stop or resume pinging.
+    TogglePause,
+    /// `r`: forget the measurements so far.
+    Reset,
+    /// Space: fire one ping while paused.
+    Ping,
+}
+
+/// Reads key presses on a thread while the terminal is in raw mode, until
+/// dropped. Ctrl-C sends `StopReason::Interrupted` on `stop`, since raw mode
+/// keeps it from raising a signal.
+pub struct Keys {
+    thread: Option<JoinHandle<()>>,
+    stopping: Arc<AtomicBool>,
+}
+
+impl Keys {
+    pub fn spawn(stop: Sender<StopReason>) -> anyhow::Result<(Keys, Receiver<Command>)> {
+        terminal::enable_raw_mode()?;
+        keep_output_processing();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let stopping = Arc::new(AtomicBool::new(false));
+        let thread_stopping = Arc::clone(&stopping);
+        let thread = std::thread::spawn(move || {
+            while !thread_stopping.load(Ordering::SeqCst) {
+                if !event::poll(POLL_INTERVAL).unwrap_or(false) {
+                    continue;
+                }
+                let key = match event::read() {
+                    Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => key,
+                    _ => continue,
+                };
+                let command = match key.code {
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                        let _ = stop.send(StopReason::Interrupted);
+                        continue;
+                    }
+                    KeyCode::Char('p') => Command::TogglePause,
+                    KeyCode::Char('r') => Command::Reset,
+                    KeyCode::Char(' ') => Command::Ping,
+                    _ => continue,
+                };
+                if tx.send(command).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok((
+            Keys {
+                thread: Some(thread),
+                stopping,
+            },
+            rx,
+        ))
+    }
+}
+
+impl Drop for Keys {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Raw mode also stops the terminal turning each "\n" into "\r\n", which the
+/// line by line output relies on, so turn that back on.
+#[cfg(unix)]
+fn keep_output_processing() {
+    use std::os::fd::AsRawFd;
+
+    let tty = match std::fs::File::open("/dev/tty") {
+        Ok(tty) => tty,
+        Err(_) => return,
+    };
+    // SAFETY: `termios` is plain data that `tcgetattr` fills in before it's
+    // read, and the descriptor stays open for both calls.
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(tty.as_raw_fd(), &mut termios) == 0 {
+            termios.c_oflag |= libc::OPOST;
+            libc::tcsetattr(tty.as_raw_fd(), libc::TCSANOW, &termios);
+        }
+    }
+}
+
+/// Raw mode leaves output processing alone on Windows.
+#[cfg(not(unix))]
+fn keep_output_processing() {}
@@ -0,0 +1,2890 @@
+//! Audio loopback latency measurement.
+//!
+//! A [`LatencyDetector`] is shared between an output callback, which emits a
+//! probe tone via [`LatencyDetector::fill_output`], and an input callback,
+//! which listens for it to come back via [`LatencyDetector::process_input`].
+//! Neither method touches audio hardware, so both can be driven with
+//! synthetic buffers. A [`Session`] runs detectors on real devices instead.
+
+#[cfg(feature = "fft")]
+mod fft;
+#[cfg(feature = "fft")]
+mod impulse;
+mod probe;
+mod session;
+mod silence;
+mod timecode;
+
+#[cfg(feature = "fft")]
+pub use impulse::{first_arrival, impulse_response};
+pub use probe::{Probe, SweepScale};
+pub use session::{
+    start_input_stream, start_output_stream, Direction, InputTap, Session, SessionConfig,
+    StreamFailure, StreamSpec,
+};
+pub use silence::{SilenceNoise, SILENCE_CREST_FACTOR};
+pub use timecode::TIMECODE_HZ;
+
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use silence::NoiseFloor;
+
+/// Handshake between the output and input callbacks for a single ping.
+///
+/// `Idle -> Emitting` is only performed by the output callback, after it has
+/// stamped the ping's start time. `Emitting -> Detected` and `Detected -> Idle`
+/// are only performed by the input callback, so each start timestamp is
+/// written exactly once and consumed exactly once.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum PingState {
+    /// Input is silent; the output should start emitting the tone.
+    Idle,
+    /// The output is emitting the tone and `signal_start` is valid.
+    Emitting,
+    /// The tone was heard; the output stays muted until the input is silent.
+    Detected,
+}
+
+impl PingState {
+    /// Atomically moves `state` from `from` to `to`, returning whether it did.
+    fn transition(state: &AtomicU8, from: PingState, to: PingState) -> bool {
+        state
+            .compare_exchange(from as u8, to as u8, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+}
+
+/// A single round-trip latency measurement.
+#[derive(Clone, Copy, Debug)]
+pub struct Measurement {
+    /// When the returning tone was detected, relative to the detector's clock.
+    pub timestamp_ns: u64,
+    /// Round-trip delay from the tone being emitted to it being detected,
+    /// measured by when the audio callbacks ran. If a timecode was decoded,
+    /// this is instead the one-way delay from when the timecode says it was
+    /// emitted. Negative if the tone was detected before it was emitted.
+    pub delay_ms: f32,
+    /// Round-trip delay measured by the playback and capture timestamps the
+    /// audio host reported, if it reported both.
+    pub stream_delay_ms: Option<f32>,
+    /// Peak-to-peak amplitude of the tone in the input block it was detected
+    /// in, as measured by the active detector.
+    pub amplitude: f32,
+    /// Round-trip delay on `DetectorConfig::compare_channel`, measured by
+    /// when the audio callbacks ran, if the tone reached it in the same input
+    /// block.
+    pub compare_delay_ms: Option<f32>,
+    /// When a timecode probe was emitted, in microseconds since the UNIX
+    /// epoch, if its timecode could be decoded.
+    pub timecode_us: Option<u64>,
+    /// How late the latest input callback during the ping arrived, compared
+    /// to the length of its buffer, if any was late by more than
+    /// `LATE_CALLBACK_FRACTION` of it. The delay then includes the OS's
+    /// scheduling noise as well as the latency.
+    pub scheduling_jitter_ms: Option<f32>,
+    /// Index of the [`Session`] output that played the probe, always 0 when
+    /// a detector is used on its own.
+    pub output: usize,
+    /// Index of the [`Session`] input that heard the probe, always 0 when a
+    /// detector is used on its own.
+    pub input: usize,
+    /// How trustworthy the measurement is, from 0.0 to 1.0: how far the
+    /// amplitude cleared the sensitivity and, for a held tone, how sharply it
+    /// rose at the onset. A probe only just over the threshold scores near 0.
+    pub confidence: f32,
+    /// Whether the probe came back with its polarity reversed. Only known
+    /// for probes played once per ping and found by cross-correlation, such
+    /// as clicks, sweeps, noise and timecodes: a held tone or square wave
+    /// inverted is the same wave half a period later, so it's never flagged.
+    pub inverted: bool,
+    /// Output channel the probe was played on, or `None` if it was played on
+    /// every channel.
+    pub output_channel: Option<usize>,
+}
+
+/// How the input is scanned for the returning probe tone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Detector {
+    /// RMS amplitude of any signal.
+    Rms,
+    /// Peak-to-peak amplitude of any signal.
+    Peak,
+    /// Energy at the probe frequency, measured with the Goertzel algorithm.
+    Goertzel,
+    /// Energy in the probe frequency's bin of a Hann windowed FFT over
+    /// `size` frames, taken every `hop` frames, counted only where it stands
+    /// out from the bins around it. Heavier than Goertzel, but not fooled by
+    /// other sound near the probe frequency.
+    #[cfg(feature = "fft")]
+    Fft { size: usize, hop: usize },
+}
+
+impl Detector {
+    /// Whether the detector only hears the probe frequency, so needs a probe
+    /// that stays on it.
+    pub fn is_tuned(self) -> bool {
+        !matches!(self, Detector::Rms | Detector::Peak)
+    }
+}
+
+/// Stream and probe parameters for a [`LatencyDetector`].
+#[derive(Clone, Debug)]
+pub struct DetectorConfig {
+    /// Sample rate of both streams. Delays are counted in frames of one
+    /// clock, so the input and output must run at the same rate.
+    pub sample_rate: f32,
+    pub input_channels: usize,
+    pub output_channels: usize,
+    /// Input channel listened to for the returning tone.
+    pub input_channel: usize,
+    /// A second input channel whose delay is measured alongside the input
+    /// channel's for each ping. Only supported with a held tone.
+    pub compare_channel: Option<usize>,
+    /// An input channel hearing only the ambient noise, not the probe. Its
+    /// energy is subtracted from the energy of the channel listened to before
+    /// thresholding, so that steady background noise doesn't trigger.
+    pub reference_channel: Option<usize>,
+    /// An input channel carrying a sync marker from whatever plays the probe,
+    /// such as a function generator's sync output. A marker heard on it after
+    /// silence starts a ping in place of the detector's own output, which
+    /// stays muted, and the delay is measured from the marker's onset.
+    pub sync_channel: Option<usize>,
+    /// Output channel the tone is played on, or every channel if `None`,
+    /// until moved with [`LatencyDetector::set_output_channel`].
+    pub output_channel: Option<usize>,
+    /// Peak-to-peak amplitude, from 0.0 to 2.0, required to trigger.
+    pub sensitivity: f32,
+    /// Fraction of `sensitivity`, from 0.0 to 1.0, that a probe once heard
+    /// must fall below to count as gone, so that a signal hovering around
+    /// the threshold doesn't trigger over and over. 1.0 uses one threshold.
+    pub hysteresis: f32,
+    /// Length of each analysis window, in periods of `frequency`. The probe
+    /// must be present for roughly this long before the detector triggers,
+    /// so a longer window rides out bursts of noise and a shorter one
+    /// triggers sooner.
+    pub window_periods: f32,
+    /// Probe tone frequency in Hz.
+    pub frequency: f32,
+    pub probe: Probe,
+    /// Probe tone amplitude multiplier, from 0.0 to 1.0.
+    pub volume: f32,
+    /// Length of the raised-cosine fade a held tone starts and stops with,
+    /// so that its edges don't click, or 0.0 to switch it straight on and
+    /// off. The onset is found partway up the fade, which adds a fixed part
+    /// of it to every delay, so keep it short.
+    pub ramp_ms: f32,
+    /// Time each ping's output is held muted for, rounded to whole frames,
+    /// before the probe starts. Delays are still measured from the start of
+    /// the ping, so they grow by exactly this much, which checks the
+    /// measurement against a known change.
+    pub probe_delay_ms: f32,
+    /// Multiplier applied to the input before detection, to make up for
+    /// attenuation or boost between the output and input.
+    pub input_gain: f32,
+    /// Multiplier applied to the probe after `volume`. The probe is then
+    /// clipped to -1.0 to 1.0.
+    pub output_gain: f32,
+    /// RMS amplitude of the noise played under the output, so that it's
+    /// never silent between pings, or 0.0 for silence. Its peaks reach
+    /// `SILENCE_CREST_FACTOR` times this, which `sensitivity` must stay
+    /// above once it has come back.
+    pub silence_level: f32,
+    pub silence_noise: SilenceNoise,
+    pub detector: Detector,
+    /// Refine each detected onset to sub-sample precision by
+    /// cross-correlating the input with the probe tone.
+    pub precise: bool,
+    /// Remove any DC offset from the input with a high-pass filter before
+    /// looking for the probe.
+    pub dc_block: bool,
+    /// Flip the input's polarity before detection, for a loopback known to
+    /// reverse it.
+    pub invert_detection: bool,
+    /// Minimum time between one ping completing and the next one starting.
+    pub interval_ns: u64,
+    /// Only start a ping once it has been asked for with
+    /// [`LatencyDetector::request_ping`], rather than as soon as the last one
+    /// completed.
+    pub on_request: bool,
+    /// Wall clock time, in nanoseconds since the UNIX epoch, at which the
+    /// callback clock read 0. Only used for timecodes.
+    pub epoch_ns: u64,
+    /// Never emit a probe, and measure probes played by something else:
+    /// timecode probes by their timecodes alone, and any other probe by the
+    /// time since the onset of the one before it.
+    pub listen_only: bool,
+    /// Number of bursts played in each ping, `TRAIN_INTERVAL_MS` apart. Their
+    /// returns are averaged before the delay is measured, to pull a weak
+    /// signal out of noise. Only used with `Probe::Burst`; 1 plays one burst.
+    pub averages: usize,
+}
+
+impl Default for DetectorConfig {
+    fn default() -> DetectorConfig {
+        DetectorConfig {
+            sample_rate: 48000.0,
+            input_channels: 1,
+            output_channels: 1,
+            input_channel: 0,
+            compare_channel: None,
+            reference_channel: None,
+            sync_channel: None,
+            output_channel: None,
+            sensitivity: 1.0,
+            hysteresis: 1.0,
+            window_periods: WINDOW_PERIODS,
+            frequency: 440.0,
+            probe: Probe::Tone,
+            volume: 0.5,
+            ramp_ms: 0.0,
+            probe_delay_ms: 0.0,
+            input_gain: 1.0,
+            output_gain: 1.0,
+            silence_level: 0.0,
+            silence_noise: SilenceNoise::Pink,
+            detector: Detector::Rms,
+            precise: false,
+            dc_block: true,
+            invert_detection: false,
+            interval_ns: 0,
+            on_request: false,
+            epoch_ns: 0,
+            listen_only: false,
+            averages: 1,
+        }
+    }
+}
+
+/// Where in an input block the probe tone was found.
+struct Detection {
+    /// Number of frames from the tone's onset to the end of the block, more
+    /// than the block's length if a window reaching back before the block
+    /// found the onset there.
+    signal_count: u32,
+    amplitude: f32,
+}
+
+/// Number of probe tone periods in each analysis window unless
+/// `DetectorConfig::window_periods` says otherwise. Being counted in periods
+/// rather than frames, the trigger time in ms only depends on the probe
+/// frequency, not on the device's sample rate.
+pub const WINDOW_PERIODS: f32 = 4.0;
+
+/// Number of positions each analysis window slides through per window length.
+const WINDOW_HOPS: usize = 4;
+
+/// How long the input must stay silent before the first ping starts.
+const STARTUP_SILENCE_NS: u64 = 200_000_000;
+
+/// Cutoff of the DC blocking filter in Hz, well below any probe frequency.
+const DC_BLOCK_HZ: f32 = 10.0;
+
+/// Fraction of its buffer's length an input callback may arrive late by
+/// before measurements are flagged as affected by it.
+const LATE_CALLBACK_FRACTION: f64 = 0.5;
+
+/// Time between the starts of the bursts in a train, unless the burst is
+/// longer than half of it. Delays must be shorter than this, less the
+/// burst's length, for each return to land in its own slot.
+pub const TRAIN_INTERVAL_MS: f32 = 250.0;
+
+/// Gain and DC blocking applied to each input channel before detection. DC
+/// blocking is a one-pole high-pass filter, which removes a constant offset
+/// from the input without noticeably changing the probe.
+struct InputFilter {
+    gain: f32,
+    /// Pole of the DC blocking filter, just inside the unit circle, or `None`
+    /// to leave any offset in.
+    pole: Option<f32>,
+    /// Previous input and output sample of each channel.
+    state: Vec<(f32, f32)>,
+    /// The last block filtered, kept to reuse its allocation.
+    filtered: Vec<f32>,
+}
+
+impl InputFilter {
+    fn new(sample_rate: f32, channels: usize, gain: f32, dc_block: bool) -> InputFilter {
+        InputFilter {
+            gain,
+            pole: dc_block.then(|| 1.0 - 2.0 * PI * DC_BLOCK_HZ / sample_rate),
+            state: vec![(0.0, 0.0); channels],
+            filtered: Vec::new(),
+        }
+    }
+
+    /// Filters an interleaved block, continuing from the end of the last one.
+    fn filter(&mut self, data: &[f32]) -> &[f32] {
+        self.filtered.clear();
+        let channels = self.state.len();
+        for frame in data.chunks_exact(channels) {
+            for (sample, (previous_in, previous_out)) in frame.iter().zip(self.state.iter_mut()) {
+                let sample = sample * self.gain;
+                *previous_out = match self.pole {
+                    Some(pole) => sample - *previous_in + pole * *previous_out,
+                    None => sample,
+                };
+                *previous_in = sample;
+                self.filtered.push(*previous_out);
+            }
+        }
+        &self.filtered
+    }
+}
+
+/// What the input callback carries from one
+/// [`LatencyDetector::process_input`] call to the next. It's kept by the
+/// callback rather than shared with the output callback through the
+/// detector, so that none of it has to be locked. Made with
+/// [`LatencyDetector::input_state`].
+pub struct InputState {
+    /// `None` if the input is used as it is.
+    filter: Option<InputFilter>,
+    /// The input before the block being scanned, for the RMS, peak and
+    /// Goertzel detectors' windows.
+    history: InputHistory,
+}
+
+/// Input from before the block being scanned, so that analysis windows can
+/// reach back across callbacks and fall on the same frames however the host
+/// splits the input into buffers.
+#[derive(Default)]
+struct InputHistory {
+    /// Interleaved frames ending where the block being scanned starts.
+    samples: Vec<f32>,
+    /// Frames of input before the block being scanned.
+    frames_before: u64,
+    /// The peak detector's running extremes on the input channel and on the
+    /// reference channel, kept to reuse their allocations.
+    peak_to_peak: [SlidingPeakToPeak; 2],
+}
+
+impl InputHistory {
+    /// Keeps the end of the interleaved block `data`, up to `keep_frames`
+    /// frames of it and the history before it, once every detection on it is
+    /// done.
+    fn remember(&mut self, data: &[f32], channels: usize, keep_frames: usize) {
+        self.frames_before += (data.len() / channels) as u64;
+        self.samples.extend_from_slice(data);
+        let excess = self.samples.len().saturating_sub(keep_frames * channels);
+        self.samples.drain(..excess);
+    }
+}
+
+/// Input kept across blocks for cross-correlation.
+#[derive(Default)]
+struct Capture {
+    /// Recent samples from the input channel, oldest first, followed by the
+    /// block being processed.
+    samples: Vec<f32>,
+    /// A detected one-shot probe that hasn't been fully captured yet.
+    pending: Option<PendingProbe>,
+    /// Frames from the coarse onset of a held tone to the end of `samples`,
+    /// while its ping waits for a period of the tone to be captured.
+    held_frames: Option<u32>,
+    /// The returns of the current ping's burst train.
+    train: Option<TrainCapture>,
+}
+
+/// The input heard during a burst train, summed over the train's slots.
+struct TrainCapture {
+    /// The ping the train was played in.
+    signal_start_ns: u64,
+    /// Frames since the train started of the next sample, which is negative
+    /// while the input is still catching up with it.
+    position: i64,
+    /// Callback and stream clock times of position 0.
+    start_ns: f64,
+    stream_start_ns: Option<f64>,
+    /// Sum of the samples heard at each frame into a slot.
+    sum: Vec<f32>,
+    /// Whether every slot has been heard.
+    done: bool,
+}
+
+/// The returning one-shot probe of a detected ping, captured from before its
+/// coarse onset until there is enough to measure it against the reference.
+struct PendingProbe {
+    samples: Vec<f32>,
+    /// Callback clock time of `samples[0]`.
+    start_ns: f64,
+    /// Stream clock time of `samples[0]`, if known.
+    stream_start_ns: Option<f64>,
+    signal_start_ns: u64,
+    stream_signal_start_ns: u64,
+    timestamp_ns: u64,
+    amplitude: f32,
+}
+
+/// Emits probe tones and detects their return.
+///
+/// Callback timestamps passed in must come from the same monotonic clock, in
+/// nanoseconds. Stream timestamps are the host's playback and capture times
+/// of the first frame in each buffer, in nanoseconds on a second clock shared
+/// by both streams, or `None` if the host doesn't provide them.
+pub struct LatencyDetector {
+    config: DetectorConfig,
+    goertzel_coeff: f32,
+    window_frames: usize,
+    period_frames: usize,
+    /// How far the real onset may be from the detector's coarse estimate.
+    search_frames: usize,
+    /// How far before the coarse onset a one-shot probe's capture starts. A
+    /// burst's slow rise may only trigger the detector halfway through it.
+    lookback_frames: usize,
+    /// Length of a one-shot probe, or of one repetition of a looped WAV file,
+    /// either of which is measured whole, or `None` for a held tone.
+    probe_frames: Option<u64>,
+    /// Whether the probe plays until it's detected rather than once.
+    held: bool,
+    /// Frames from the start of one burst to the next in a train, or `None`
+    /// unless averaging bursts.
+    train_interval_frames: Option<u64>,
+    /// Length of a held tone's fades, or 0 without them.
+    ramp_frames: u64,
+    /// Frames each ping is held muted for before its probe starts.
+    probe_delay_frames: u64,
+    /// The probe as emitted at the start of each ping: one period of a held
+    /// tone, or the whole of a one-shot probe. Passed through the DC blocking
+    /// filter if the input is.
+    reference: Vec<f32>,
+    /// Only kept in precise mode and for one-shot probes.
+    capture: Mutex<Capture>,
+    /// Only kept for the FFT detector.
+    #[cfg(feature = "fft")]
+    fft: Option<Mutex<fft::FftAnalyzer>>,
+    /// `None` if the output is silent between pings.
+    noise_floor: Option<NoiseFloor>,
+    ping_state: AtomicU8,
+    signal_start: AtomicU64,
+    /// Stream timestamp of the current ping's first frame, or 0 if unknown.
+    stream_signal_start: AtomicU64,
+    /// When the last ping was detected or timed out.
+    signal_end: AtomicU64,
+    /// When the input last became silent, or `u64::MAX` if it isn't.
+    silent_since: AtomicU64,
+    /// Frames of the probe emitted so far in the current ping, or `u64::MAX`
+    /// before the first ping.
+    probe_clock: AtomicU64,
+    /// Frames the current ping is still held muted for.
+    probe_delay_left: AtomicU64,
+    /// Frames left of a held tone's fade-out once it has stopped emitting.
+    fade_out_frames: AtomicU64,
+    /// Data carried by the current ping's timecode.
+    timecode: AtomicU64,
+    /// Whether a ping has been asked for and not started yet.
+    ping_requested: AtomicBool,
+    /// Output channel the next ping is played on, or `usize::MAX` for every
+    /// channel.
+    output_channel: AtomicUsize,
+    /// Output channel the current ping is played on, as `output_channel`.
+    ping_channel: AtomicUsize,
+    /// When the last input callback ran, or `u64::MAX` before the first.
+    last_input_ns: AtomicU64,
+    /// Most an input callback has been late during the current ping, or 0 if
+    /// none was late enough to count.
+    callback_lateness_ns: AtomicU64,
+    /// Frames the sync channel has been silent for up to the block being
+    /// scanned.
+    sync_silent_frames: AtomicU64,
+    /// When the last probe heard started, while listening for the time
+    /// between probes.
+    last_onset_ns: Mutex<Option<f64>>,
+}
+
+impl LatencyDetector {
+    pub fn new(config: DetectorConfig) -> LatencyDetector {
+        let omega = 2.0 * PI * config.frequency / config.sample_rate;
+        let window_frames =
+            ((config.window_periods * config.sample_rate / config.frequency).round() as usize)
+                .max(1);
+        let period_frames = ((config.sample_rate / config.frequency).round() as usize).max(1);
+        let held = config.probe.duration_frames(config.sample_rate).is_none();
+        let probe_frames = match &config.probe {
+            Probe::Wav { samples, .. } => Some(samples.len() as u64),
+            probe => probe.duration_frames(config.sample_rate),
+        };
+        // Only the start of a timecode is known in advance.
+        let reference_frames = match config.probe {
+            Probe::Timecode => timecode::sync_frames(config.sample_rate),
+            _ => probe_frames.unwrap_or(period_frames as u64),
+        };
+        let mut reference: Vec<f32> = (1..=reference_frames)
+            .map(|clock| {
+                config
+                    .probe
+                    .value(config.frequency, config.sample_rate, clock)
+            })
+            .collect();
+        if config.dc_block {
+            // Compare the input against the probe as it comes out of the same
+            // filter, whose slight phase shift would otherwise bias the onset.
+            reference = InputFilter::new(config.sample_rate, 1, 1.0, true)
+                .filter(&reference)
+                .to_vec();
+        }
+        let train_interval_frames = match config.probe {
+            Probe::Burst { .. } if config.averages > 1 => {
+                let interval_frames = (TRAIN_INTERVAL_MS * config.sample_rate / 1000.0) as u64;
+                Some(interval_frames.max(2 * reference_frames))
+            }
+            _ => None,
+        };
+        let search_frames = window_frames + period_frames;
+        let lookback_frames = match config.probe {
+            Probe::Burst { .. } => search_frames + reference_frames as usize / 2,
+            _ => search_frames,
+        };
+        LatencyDetector {
+            goertzel_coeff: 2.0 * omega.cos(),
+            window_frames,
+            period_frames,
+            search_frames,
+            lookback_frames,
+            probe_frames,
+            held,
+            train_interval_frames,
+            ramp_frames: match probe_frames {
+                None => (config.ramp_ms.max(0.0) * config.sample_rate / 1000.0) as u64,
+                Some(_) => 0,
+            },
+            probe_delay_frames: (config.probe_delay_ms.max(0.0) * config.sample_rate / 1000.0)
+                .round() as u64,
+            reference,
+            capture: Mutex::new(Capture::default()),
+            #[cfg(feature = "fft")]
+            fft: match config.detector {
+                Detector::Fft { size, hop } => Some(Mutex::new(fft::FftAnalyzer::new(
+                    size,
+                    hop,
+                    config.frequency,
+                    config.sample_rate,
+                ))),
+                _ => None,
+            },
+            noise_floor: (config.silence_level > 0.0)
+                .then(|| NoiseFloor::new(config.silence_noise, config.silence_level)),
+            // Start out waiting for silence so the first ping has a clean baseline.
+            ping_state: AtomicU8::new(PingState::Detected as u8),
+            signal_start: AtomicU64::new(0),
+            stream_signal_start: AtomicU64::new(0),
+            signal_end: AtomicU64::new(0),
+            silent_since: AtomicU64::new(u64::MAX),
+            probe_clock: AtomicU64::new(u64::MAX),
+            probe_delay_left: AtomicU64::new(0),
+            fade_out_frames: AtomicU64::new(0),
+            timecode: AtomicU64::new(0),
+            ping_requested: AtomicBool::new(false),
+            output_channel: AtomicUsize::new(config.output_channel.unwrap_or(usize::MAX)),
+            ping_channel: AtomicUsize::new(config.output_channel.unwrap_or(usize::MAX)),
+            last_input_ns: AtomicU64::new(u64::MAX),
+            callback_lateness_ns: AtomicU64::new(0),
+            // A marker already under way when listening starts has no onset
+            // to measure from.
+            sync_silent_frames: AtomicU64::new(0),
+            last_onset_ns: Mutex::new(None),
+            config,
+        }
+    }
+
+    /// A fresh state for the callback that passes this detector its input.
+    pub fn input_state(&self) -> InputState {
+        let config = &self.config;
+        InputState {
+            filter: (config.dc_block || config.input_gain != 1.0 || config.invert_detection).then(
+                || {
+                    let gain = if config.invert_detection {
+                        -config.input_gain
+                    } else {
+                        config.input_gain
+                    };
+                    InputFilter::new(
+                        config.sample_rate,
+                        config.input_channels,
+                        gain,
+                        config.dc_block,
+                    )
+                },
+            ),
+            history: InputHistory::default(),
+        }
+    }
+
+    /// Scans an interleaved input buffer captured at `frame_start_ns` and
+    /// returns a measurement if it contains the returning probe tone. `state`
+    /// must be the one made for this detector's input, passed to every call.
+    pub fn process_input(
+        &self,
+        state: &mut InputState,
+        data: &[f32],
+        frame_start_ns: u64,
+        stream_ns: Option<u64>,
+    ) -> Option<Measurement> {
+        // Some hosts deliver empty buffers, which say nothing about whether
+        // the input is silent.
+        if data.is_empty() || self.config.input_channels == 0 {
+            return None;
+        }
+        // Each callback should follow the last by the length of its buffer.
+        let frames = data.len() / self.config.input_channels;
+        let buffer_ns = frames as f64 * 1e9 / self.config.sample_rate as f64;
+        let last_input_ns = self.last_input_ns.swap(frame_start_ns, Ordering::SeqCst);
+        if last_input_ns != u64::MAX
+            && self.ping_state.load(Ordering::SeqCst) == PingState::Emitting as u8
+        {
+            let lateness_ns = frame_start_ns.saturating_sub(last_input_ns) as f64 - buffer_ns;
+            if lateness_ns > buffer_ns * LATE_CALLBACK_FRACTION {
+                self.callback_lateness_ns
+                    .fetch_max(lateness_ns as u64, Ordering::SeqCst);
+            }
+        }
+        self.filtered(state, data, |history, data| {
+            self.process_filtered(history, data, frame_start_ns, stream_ns)
+        })
+    }
+
+    /// The loudest the configured detector hears the interleaved block
+    /// `data` to be, with the input gain applied and any DC offset removed as
+    /// for `process_input`, but without starting or ending a ping. Used to
+    /// measure the input's noise floor in the units the sensitivity is in.
+    /// `state` must be the one made for this detector's input, passed to
+    /// every call.
+    pub fn noise_amplitude(&self, state: &mut InputState, data: &[f32]) -> f32 {
+        if data.is_empty() || self.config.input_channels == 0 {
+            return 0.0;
+        }
+        self.filtered(state, data, |history, data| {
+            // Nothing stays under this threshold, so the detector triggers on
+            // the first window and still measures every other.
+            self.detect(history, data, self.config.input_channel, f32::NEG_INFINITY)
+                .map_or(0.0, |detection| detection.amplitude)
+        })
+    }
+
+    /// Filters `data` through `state`, passes it to `scan` along with the
+    /// input before it, and then keeps its end for the next block's windows.
+    fn filtered<T>(
+        &self,
+        state: &mut InputState,
+        data: &[f32],
+        scan: impl FnOnce(&mut InputHistory, &[f32]) -> T,
+    ) -> T {
+        let InputState { filter, history } = state;
+        let data = match filter {
+            Some(filter) => filter.filter(data),
+            None => data,
+        };
+        let result = scan(history, data);
+        history.remember(
+            data,
+            self.config.input_channels,
+            self.window_frames + self.window_hop(),
+        );
+        #[cfg(feature = "fft")]
+        if let Some(fft) = &self.fft {
+            fft.lock()
+                .unwrap()
+                .remember(data, self.config.input_channels);
+        }
+        result
+    }
+
+    /// Does the work of `process_input` once the input gain has been applied
+    /// to `data` and any DC offset removed.
+    fn process_filtered(
+        &self,
+        history: &mut InputHistory,
+        data: &[f32],
+        frame_start_ns: u64,
+        stream_ns: Option<u64>,
+    ) -> Option<Measurement> {
+        if let Some(sync_channel) = self.config.sync_channel {
+            self.detect_sync(data, sync_channel, frame_start_ns, stream_ns);
+        }
+        if !self.config.precise && self.probe_frames.is_none() {
+            return self.measure_input(history, data, frame_start_ns, stream_ns, None);
+        }
+
+        let mut capture = self.capture.lock().unwrap();
+        let channels = self.config.input_channels;
+        let input_channel = self.config.input_channel;
+        let block = data
+            .chunks_exact(channels)
+            .map(|frame| frame[input_channel]);
+        if self.train_interval_frames.is_some()
+            && self.ping_state.load(Ordering::SeqCst) == PingState::Emitting as u8
+        {
+            // A train is measured by its averaged returns rather than as soon
+            // as anything is heard.
+            let block: Vec<f32> = block.collect();
+            return self.average_train(&mut capture, &block, frame_start_ns, stream_ns);
+        }
+        let measurement = if let Some(pending) = &mut capture.pending {
+            pending.samples.extend(block);
+            self.finish_probe(&mut capture)
+        } else {
+            capture.samples.extend(block);
+            self.measure_input(history, data, frame_start_ns, stream_ns, Some(&mut capture))
+        };
+        let excess = capture
+            .samples
+            .len()
+            .saturating_sub(self.lookback_frames + self.search_frames);
+        capture.samples.drain(..excess);
+        measurement
+    }
+
+    /// Starts a ping at the onset of a marker on `sync_channel`, if one
+    /// follows a window's length of silence on it, as though the marker were
+    /// the probe being played. A marker is often a pulse rather than a tone,
+    /// so its onset is the first sample reaching half the sensitivity, the
+    /// peak of a tone that would just trigger.
+    fn detect_sync(
+        &self,
+        data: &[f32],
+        sync_channel: usize,
+        frame_start_ns: u64,
+        stream_ns: Option<u64>,
+    ) {
+        let loud = |frame: &[f32]| frame[sync_channel].abs() >= self.config.sensitivity / 2.0;
+        let frames = data.len() / self.config.input_channels;
+        let marker = data.chunks_exact(self.config.input_channels).position(loud);
+        // Counted in frames rather than blocks, so that short buffers don't
+        // make a pause in the marker look like silence.
+        let silent_frames = match data
+            .chunks_exact(self.config.input_channels)
+            .rposition(loud)
+        {
+            Some(last) => (frames - 1 - last) as u64,
+            None => frames as u64,
+        };
+        let silent_before = match marker {
+            Some(_) => self
+                .sync_silent_frames
+                .swap(silent_frames, Ordering::SeqCst),
+            None => self
+                .sync_silent_frames
+                .fetch_add(silent_frames, Ordering::SeqCst),
+        };
+        let marker = match marker {
+            Some(marker) if silent_before + marker as u64 >= self.window_frames as u64 => marker,
+            _ => return,
+        };
+        let frame_ns = 1e9 / self.config.sample_rate as f64;
+        let onset_ns = frame_start_ns as f64 - (frames - marker) as f64 * frame_ns;
+        let stream_onset_ns = stream_ns.map_or(0, |stream_ns| {
+            (stream_ns as f64 + marker as f64 * frame_ns) as u64
+        });
+        // A marker that comes before the last ping's probe has died away is
+        // ignored, as its probe would be.
+        if self.ping_state.load(Ordering::SeqCst) == PingState::Idle as u8 {
+            self.signal_start
+                .store(onset_ns.max(0.0) as u64, Ordering::SeqCst);
+            self.stream_signal_start
+                .store(stream_onset_ns, Ordering::SeqCst);
+            self.callback_lateness_ns.store(0, Ordering::SeqCst);
+            PingState::transition(&self.ping_state, PingState::Idle, PingState::Emitting);
+        }
+    }
+
+    /// Whether probes are measured from the one before them rather than from
+    /// a ping.
+    fn measures_intervals(&self) -> bool {
+        self.config.listen_only && self.config.probe != Probe::Timecode
+    }
+
+    /// Milliseconds from the onset of the last probe heard to `onset_ns`,
+    /// which takes its place, or `None` for the first probe heard.
+    fn interval_ms(&self, onset_ns: f64) -> Option<f64> {
+        let last_onset_ns = self.last_onset_ns.lock().unwrap().replace(onset_ns)?;
+        Some((onset_ns - last_onset_ns) / 1_000_000.0)
+    }
+
+    /// Whether the detector plays its own probe, rather than only listening
+    /// for one played by something else.
+    fn plays(&self) -> bool {
+        !self.config.listen_only && self.config.sync_channel.is_none()
+    }
+
+    /// Does the work of `process_filtered`. In precise mode and for one-shot
+    /// probes, `capture` holds the input channel's history followed by the
+    /// samples in `data`.
+    fn measure_input(
+        &self,
+        history: &mut InputHistory,
+        data: &[f32],
+        frame_start_ns: u64,
+        stream_ns: Option<u64>,
+        mut capture: Option<&mut Capture>,
+    ) -> Option<Measurement> {
+        // Once a probe has been heard, it has to fall below the lower
+        // threshold before the input counts as silent again.
+        let threshold = if self.ping_state.load(Ordering::SeqCst) == PingState::Detected as u8 {
+            self.config.sensitivity * self.config.hysteresis
+        } else {
+            self.config.sensitivity
+        };
+        let detection = self.detect(history, data, self.config.input_channel, threshold);
+        let Detection {
+            signal_count,
+            amplitude,
+        } = match detection {
+            Some(detection) => detection,
+            None => {
+                if let Some(capture) = capture {
+                    capture.held_frames = None;
+                }
+                if self.settled(frame_start_ns) {
+                    PingState::transition(&self.ping_state, PingState::Detected, PingState::Idle);
+                }
+                return None;
+            }
+        };
+        self.silent_since.store(u64::MAX, Ordering::SeqCst);
+        // When only listening, any probe heard after silence is a new ping.
+        let from = if self.config.listen_only {
+            PingState::Idle
+        } else {
+            PingState::Emitting
+        };
+        let frames = data.len() / self.config.input_channels;
+        let mut signal_count = signal_count;
+        // A held tone is placed by correlating a period of it from its
+        // onset, so the ping waits for that much to be captured past the
+        // coarse onset, which a short block may not reach.
+        if let Some(capture) = capture.as_mut().filter(|_| self.probe_frames.is_none()) {
+            if let Some(held_frames) = capture.held_frames.take() {
+                signal_count = held_frames + frames as u32;
+            }
+            if (signal_count as usize) < self.reference.len() + 2
+                && self.ping_state.load(Ordering::SeqCst) == from as u8
+            {
+                capture.held_frames = Some(signal_count);
+                return None;
+            }
+        }
+        if !PingState::transition(&self.ping_state, from, PingState::Detected) {
+            // A signal that isn't this detector's ping, such as the ping of
+            // another output, must die away before this one's can start.
+            PingState::transition(&self.ping_state, PingState::Idle, PingState::Detected);
+            return None;
+        }
+        self.signal_end.store(frame_start_ns, Ordering::SeqCst);
+
+        let signal_start_ns = self.signal_start.load(Ordering::SeqCst);
+        let stream_signal_start = self.stream_signal_start.load(Ordering::SeqCst);
+
+        // Frames into the block at which the tone starts, which may be
+        // negative if it started in the previous block.
+        let coarse_onset = frames as isize - signal_count as isize;
+        let frame_ns = 1e9 / self.config.sample_rate as f64;
+
+        let mut onset = coarse_onset as f64;
+        if let Some(capture) = capture {
+            if self.probe_frames.is_some() {
+                // Keep capturing until the whole probe can be measured.
+                let block_start = capture.samples.len() - frames;
+                let start = (block_start as isize + coarse_onset).max(0) as usize;
+                let start = start.saturating_sub(self.lookback_frames);
+                capture.pending = Some(PendingProbe {
+                    samples: capture.samples[start..].to_vec(),
+                    start_ns: frame_start_ns as f64
+                        - (capture.samples.len() - start) as f64 * frame_ns,
+                    stream_start_ns: stream_ns.map(|stream_ns| {
+                        stream_ns as f64 + (start as f64 - block_start as f64) * frame_ns
+                    }),
+                    signal_start_ns,
+                    stream_signal_start_ns: stream_signal_start,
+                    timestamp_ns: frame_start_ns,
+                    amplitude,
+                });
+                return self.finish_probe(capture);
+            }
+            onset = self.refine_onset(&capture.samples, frames, coarse_onset);
+        }
+
+        // Signed, since a tone heard before it was emitted can't have come
+        // from this detector and should show up as such.
+        let mut delay_ms = (frame_start_ns as f64 - signal_start_ns as f64) / 1_000_000.0;
+        delay_ms -= (frames as f64 - onset) * frame_ns / 1_000_000.0;
+        if self.measures_intervals() {
+            let onset_ns = frame_start_ns as f64 - (frames as f64 - onset) * frame_ns;
+            delay_ms = self.interval_ms(onset_ns)?;
+        }
+
+        let stream_delay_ms = stream_ns
+            .filter(|_| stream_signal_start != 0)
+            .map(|stream_ns| {
+                let onset_ns = stream_ns as f64 + onset * frame_ns;
+                ((onset_ns - stream_signal_start as f64) / 1_000_000.0) as f32
+            });
+        let compare_delay_ms = self
+            .config
+            .compare_channel
+            .and_then(|channel| self.detect(history, data, channel, self.config.sensitivity))
+            .map(|detection| {
+                let compare_onset = frames as f64 - detection.signal_count as f64;
+                (delay_ms + (compare_onset - coarse_onset as f64) * frame_ns / 1_000_000.0) as f32
+            });
+        Some(Measurement {
+            timestamp_ns: frame_start_ns,
+            delay_ms: delay_ms as f32,
+            stream_delay_ms,
+            amplitude,
+            compare_delay_ms,
+            timecode_us: None,
+            scheduling_jitter_ms: self.scheduling_jitter_ms(),
+            output: 0,
+            input: 0,
+            confidence: self.amplitude_margin(amplitude)
+                * self.sharpness(data, coarse_onset.max(0) as usize),
+            // A held probe's polarity can't be told, see `refine_onset`.
+            inverted: false,
+            output_channel: self.ping_channel(),
+        })
+    }
+
+    /// Adds `block`, the input channel's samples captured at `frame_start_ns`,
+    /// to the current ping's burst train, and once every slot of the train
+    /// has been heard, measures the burst in their average. A train too quiet
+    /// to clear the sensitivity even then is left to time out.
+    fn average_train(
+        &self,
+        capture: &mut Capture,
+        block: &[f32],
+        frame_start_ns: u64,
+        stream_ns: Option<u64>,
+    ) -> Option<Measurement> {
+        let interval_frames = self.train_interval_frames? as usize;
+        let train_frames = interval_frames * self.config.averages;
+        let signal_start_ns = self.signal_start.load(Ordering::SeqCst);
+        let frame_ns = 1e9 / self.config.sample_rate as f64;
+        let block_start_ns = frame_start_ns as f64 - block.len() as f64 * frame_ns;
+
+        if capture
+            .train
+            .as_ref()
+            .is_none_or(|train| train.signal_start_ns != signal_start_ns)
+        {
+            // Samples are counted from here on, so the slots stay aligned
+            // however late later callbacks run.
+            let position = ((block_start_ns - signal_start_ns as f64) / frame_ns).round() as i64;
+            let start_ns = block_start_ns - position as f64 * frame_ns;
+            capture.train = Some(TrainCapture {
+                signal_start_ns,
+                position,
+                start_ns,
+                stream_start_ns: stream_ns
+                    .map(|stream_ns| stream_ns as f64 + (start_ns - block_start_ns)),
+                sum: vec![0f32; interval_frames],
+                done: false,
+            });
+        }
+        let train = capture.train.as_mut()?;
+        if train.done {
+            return None;
+        }
+        for sample in block {
+            if let Ok(position) = usize::try_from(train.position) {
+                if position < train_frames {
+                    train.sum[position % interval_frames] += sample;
+                }
+            }
+            train.position += 1;
+        }
+        if train.position < train_frames as i64 {
+            return None;
+        }
+        train.done = true;
+
+        let averaged: Vec<f32> = train
+            .sum
+            .iter()
+            .map(|sum| sum / self.config.averages as f32)
+            .collect();
+        let amplitude = peak_to_peak(&averaged);
+        if amplitude <= self.config.sensitivity {
+            return None;
+        }
+        let crossing = envelope_crossing(&averaged, self.period_frames)?;
+        let lag = crossing - envelope_crossing(&self.reference, self.period_frames)?;
+        if !PingState::transition(&self.ping_state, PingState::Emitting, PingState::Detected) {
+            return None;
+        }
+        self.silent_since.store(u64::MAX, Ordering::SeqCst);
+        self.signal_end.store(frame_start_ns, Ordering::SeqCst);
+
+        let stream_signal_start = self.stream_signal_start.load(Ordering::SeqCst);
+        let stream_delay_ms = train
+            .stream_start_ns
+            .filter(|_| stream_signal_start != 0)
+            .map(|stream_start_ns| {
+                let onset_ns = stream_start_ns + lag * frame_ns;
+                ((onset_ns - stream_signal_start as f64) / 1_000_000.0) as f32
+            });
+        let onset_ns = train.start_ns + lag * frame_ns;
+        Some(Measurement {
+            timestamp_ns: frame_start_ns,
+            delay_ms: ((onset_ns - signal_start_ns as f64) / 1_000_000.0) as f32,
+            stream_delay_ms,
+            amplitude,
+            compare_delay_ms: None,
+            timecode_us: None,
+            scheduling_jitter_ms: self.scheduling_jitter_ms(),
+            output: 0,
+            input: 0,
+            confidence: self.amplitude_margin(amplitude),
+            inverted: false,
+            output_channel: self.ping_channel(),
+        })
+    }
+
+    /// How far `amplitude` cleared the sensitivity, from 0.0 right at it
+    /// towards 1.0 for a probe many times louder.
+    fn amplitude_margin(&self, amplitude: f32) -> f32 {
+        if amplitude <= 0.0 {
+            return 0.0;
+        }
+        (1.0 - self.config.sensitivity / amplitude).clamp(0.0, 1.0)
+    }
+
+    /// How sharply the input channel of `data` rises from `onset` frames in
+    /// to half of its peak after it, from 1.0 for a step to 0.0 for a rise
+    /// taking a detector window or longer.
+    fn sharpness(&self, data: &[f32], onset: usize) -> f32 {
+        let channels = self.config.input_channels;
+        let after_onset: Vec<f32> = data[onset * channels..]
+            .chunks_exact(channels)
+            .map(|frame| frame[self.config.input_channel].abs())
+            .collect();
+        let peak = after_onset.iter().copied().fold(0f32, f32::max);
+        let rise_frames = after_onset
+            .iter()
+            .position(|sample| *sample >= peak / 2.0)
+            .unwrap_or(0);
+        (1.0 - rise_frames as f32 / self.window_frames as f32).clamp(0.0, 1.0)
+    }
+
+    /// `Measurement::scheduling_jitter_ms` for the current ping.
+    fn scheduling_jitter_ms(&self) -> Option<f32> {
+        match self.callback_lateness_ns.load(Ordering::SeqCst) {
+            0 => None,
+            lateness_ns => Some(lateness_ns as f32 / 1_000_000.0),
+        }
+    }
+
+    /// Whether the input, silent in the block captured at `frame_start_ns`,
+    /// has been silent for long enough to start a ping. Before the first ping
+    /// that takes `STARTUP_SILENCE_NS` of silence, in case the input starts
+    /// out hearing something left playing, and after it one silent block.
+    fn settled(&self, frame_start_ns: u64) -> bool {
+        if self.probe_clock.load(Ordering::SeqCst) != u64::MAX {
+            return true;
+        }
+        // Likewise once something has been heard, when nothing is played.
+        if !self.plays() && self.signal_end.load(Ordering::SeqCst) != 0 {
+            return true;
+        }
+        let silent_since = match self.silent_since.compare_exchange(
+            u64::MAX,
+            frame_start_ns,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => frame_start_ns,
+            Err(silent_since) => silent_since,
+        };
+        frame_start_ns.saturating_sub(silent_since) >= STARTUP_SILENCE_NS
+    }
+
+    /// Measures a pending one-shot probe once enough of it has been captured.
+    /// A sweep is placed at the lag where it best correlates with the
+    /// reference, and a burst by where its envelope rises through half of its
+    /// peak, compared to the same point on the reference. A timecode is placed
+    /// by its preamble and then decoded from there.
+    fn finish_probe(&self, capture: &mut Capture) -> Option<Measurement> {
+        let pending = capture.pending.as_ref()?;
+        let span = self.lookback_frames + self.probe_frames? as usize + self.search_frames;
+        if pending.samples.len() < span {
+            return None;
+        }
+        let pending = capture.pending.take()?;
+
+        let (lag, inverted) = if let Probe::Burst { .. } = self.config.probe {
+            let crossing = envelope_crossing(&pending.samples, self.period_frames)?;
+            let lag = crossing - envelope_crossing(&self.reference, self.period_frames)?;
+            (lag, false)
+        } else {
+            let correlation = correlate(
+                &pending.samples,
+                &self.reference,
+                0,
+                self.lookback_frames + self.search_frames,
+            );
+            // An inverted return correlates just as strongly, only negatively.
+            let magnitude: Vec<f32> = correlation.iter().map(|c| c.abs()).collect();
+            let peak = (1..magnitude.len() - 1)
+                .max_by(|a, b| magnitude[*a].total_cmp(&magnitude[*b]))
+                .unwrap_or(0);
+            let lag = peak as f64 + parabolic_offset(&magnitude, peak);
+            (lag, correlation[peak] < 0.0)
+        };
+
+        let frame_ns = 1e9 / self.config.sample_rate as f64;
+        let onset_ns = pending.start_ns + lag * frame_ns;
+        let timecode_us = match self.config.probe {
+            Probe::Timecode => timecode::decode(
+                &pending.samples[lag.round().max(0.0) as usize..],
+                self.config.sample_rate,
+            ),
+            _ => None,
+        };
+        let delay_ms = match timecode_us {
+            Some(timecode_us) => {
+                // Kept as integers until the difference is small enough for
+                // an f64 to hold to the nanosecond.
+                let epoch_offset_ns = self.config.epoch_ns as i128 - timecode_us as i128 * 1000;
+                (epoch_offset_ns as f64 + onset_ns) / 1_000_000.0
+            }
+            None if self.measures_intervals() => self.interval_ms(onset_ns)?,
+            // Without a timecode there's nothing to measure a ping from
+            // another detector against.
+            None if self.config.listen_only => return None,
+            None => (onset_ns - pending.signal_start_ns as f64) / 1_000_000.0,
+        };
+        let stream_delay_ms = pending
+            .stream_start_ns
+            .filter(|_| pending.stream_signal_start_ns != 0)
+            .map(|stream_start_ns| {
+                let onset_ns = stream_start_ns + lag * frame_ns;
+                ((onset_ns - pending.stream_signal_start_ns as f64) / 1_000_000.0) as f32
+            });
+        Some(Measurement {
+            timestamp_ns: pending.timestamp_ns,
+            delay_ms: delay_ms as f32,
+            stream_delay_ms,
+            amplitude: pending.amplitude,
+            compare_delay_ms: None,
+            timecode_us,
+            scheduling_jitter_ms: self.scheduling_jitter_ms(),
+            output: 0,
+            input: 0,
+            // The onset comes from the whole capture rather than the
+            // detector's rise, so only the capture's amplitude counts.
+            confidence: self.amplitude_margin(peak_to_peak(&pending.samples)),
+            inverted,
+            output_channel: self.ping_channel(),
+        })
+    }
+
+    /// Looks for the probe tone on `channel` with the configured detector. A
+    /// click is too short for a windowed detector to see, so it's always
+    /// detected by its peak.
+    fn detect(
+        &self,
+        history: &mut InputHistory,
+        data: &[f32],
+        channel: usize,
+        threshold: f32,
+    ) -> Option<Detection> {
+        if self.config.probe == Probe::Click {
+            return self.detect_peak(history, data, channel, threshold);
+        }
+        match self.config.detector {
+            Detector::Rms => self.detect_windowed(history, data, channel, threshold, rms_amplitude),
+            Detector::Peak => self.detect_peak(history, data, channel, threshold),
+            Detector::Goertzel => {
+                let coeff = self.goertzel_coeff;
+                self.detect_windowed(history, data, channel, threshold, |samples| {
+                    goertzel_amplitude(coeff, samples)
+                })
+            }
+            #[cfg(feature = "fft")]
+            Detector::Fft { .. } => self.fft.as_ref()?.lock().unwrap().detect(
+                data,
+                self.config.input_channels,
+                channel,
+                self.config.reference_channel,
+                threshold,
+            ),
+        }
+    }
+
+    /// Triggers at the first frame of the block whose peak-to-peak amplitude
+    /// over the window ending there exceeds `threshold`.
+    fn detect_peak(
+        &self,
+        history: &mut InputHistory,
+        data: &[f32],
+        channel: usize,
+        threshold: f32,
+    ) -> Option<Detection> {
+        let channels = self.config.input_channels;
+        let InputHistory {
+            samples,
+            peak_to_peak: [peaks, reference_peaks],
+            ..
+        } = history;
+        let history_frames = samples.len() / channels;
+        let total_frames = history_frames + data.len() / channels;
+        let sample =
+            |frame: usize, channel: usize| history_sample(samples, data, channels, channel, frame);
+        // Far enough back for the block's first window to be whole.
+        let first = history_frames.saturating_sub(self.window_frames - 1);
+        peaks.clear();
+        reference_peaks.clear();
+        let mut trigger = None;
+        let mut amplitude = 0f32;
+        for frame in first..total_frames {
+            let mut window = peaks.push(frame, sample(frame, channel), self.window_frames);
+            if let Some(reference) = self.config.reference_channel {
+                let reference_window =
+                    reference_peaks.push(frame, sample(frame, reference), self.window_frames);
+                window = without_reference(window, reference_window);
+            }
+            if frame < history_frames {
+                continue;
+            }
+            amplitude = amplitude.max(window);
+            if trigger.is_none() && window > threshold {
+                trigger = Some(frame);
+            }
+        }
+        Some(Detection {
+            signal_count: (total_frames - trigger?) as u32,
+            amplitude,
+        })
+    }
+
+    /// Frames between the ends of consecutive RMS or Goertzel windows.
+    fn window_hop(&self) -> usize {
+        (self.window_frames / WINDOW_HOPS).max(1)
+    }
+
+    /// Scans the windows ending in the block, as `window_ends` places them,
+    /// and triggers on the first whose amplitude, as measured by
+    /// `window_amplitude`, exceeds `threshold`. The onset is then taken to be
+    /// the first sample in that window reaching half the peak amplitude
+    /// needed to trigger, so it isn't quantized to the window length.
+    fn detect_windowed<F>(
+        &self,
+        history: &InputHistory,
+        data: &[f32],
+        channel: usize,
+        threshold: f32,
+        window_amplitude: F,
+    ) -> Option<Detection>
+    where
+        F: Fn(&mut dyn Iterator<Item = f32>) -> f32,
+    {
+        let channels = self.config.input_channels;
+        let history_frames = history.samples.len() / channels;
+        let frames = data.len() / channels;
+        let sample = |frame: usize, channel: usize| {
+            history_sample(&history.samples, data, channels, channel, frame)
+        };
+        let mut trigger = None;
+        let mut amplitude = 0f32;
+        let ends = window_ends(
+            history.frames_before,
+            history_frames,
+            frames,
+            self.window_hop(),
+        );
+        for window_end in ends {
+            let window_start = window_end.saturating_sub(self.window_frames);
+            let mut window = window_amplitude(
+                &mut (window_start..window_end).map(|frame| sample(frame, channel)),
+            );
+            if let Some(reference) = self.config.reference_channel {
+                let reference_window = window_amplitude(
+                    &mut (window_start..window_end).map(|frame| sample(frame, reference)),
+                );
+                window = without_reference(window, reference_window);
+            }
+            amplitude = amplitude.max(window);
+            if trigger.is_none() && window > threshold {
+                trigger = Some((window_start, window_end));
+            }
+        }
+
+        let (window_start, window_end) = trigger?;
+        let onset = (window_start..window_end)
+            .find(|frame| sample(*frame, channel).abs() >= threshold / 4.0)
+            .unwrap_or(window_start);
+        Some(Detection {
+            signal_count: (history_frames + frames - onset) as u32,
+            amplitude,
+        })
+    }
+
+    /// Refines a coarse onset, `coarse_onset` frames into the last `frames`
+    /// samples of `capture`, by cross-correlating the capture with one period
+    /// of the probe tone. A continuous tone correlates equally well at every
+    /// whole period after the real onset, so the earliest strong correlation
+    /// peak is taken and then interpolated with a parabola through its
+    /// neighbours. Only positive peaks count: an inverted tone correlates as
+    /// strongly half a period on, so it can't be told from one that came back
+    /// upright half a period later, and is taken to be the latter. Returns
+    /// the onset in fractional frames into the block.
+    fn refine_onset(&self, capture: &[f32], frames: usize, coarse_onset: isize) -> f64 {
+        let block_start = capture.len() - frames;
+        let coarse = (block_start as isize + coarse_onset).max(0) as usize;
+        let first_lag = coarse.saturating_sub(self.search_frames);
+        let last_lag =
+            (coarse + self.window_frames).min(capture.len().saturating_sub(self.reference.len()));
+        if last_lag < first_lag + 2 {
+            return coarse_onset as f64;
+        }
+
+        let correlation = correlate(capture, &self.reference, first_lag, last_lag);
+        let max = correlation.iter().fold(0f32, |max, c| max.max(*c));
+        if max <= 0.0 {
+            return coarse_onset as f64;
+        }
+        let peak = (1..correlation.len() - 1).find(|&i| {
+            correlation[i] >= 0.5 * max
+                && correlation[i] >= correlation[i - 1]
+                && correlation[i] >= correlation[i + 1]
+        });
+        match peak {
+            Some(peak) => {
+                (first_lag + peak) as f64 + parabolic_offset(&correlation, peak)
+                    - block_start as f64
+            }
+            None => coarse_onset as f64,
+        }
+    }
+
+    /// Asks for a ping to start when `DetectorConfig::on_request` is set. It
+    /// starts as soon as the last one has completed and its interval passed;
+    /// asking again before then has no further effect.
+    pub fn request_ping(&self) {
+        self.ping_requested.store(true, Ordering::SeqCst);
+    }
+
+    /// Times the pings `leader` plays, for a detector on another input that
+    /// hears the same output. Call after each of `leader`'s `fill_output`
+    /// calls. A ping that starts before this detector's input has gone
+    /// silent is skipped, as its probe couldn't be told from what's there.
+    pub fn follow(&self, leader: &LatencyDetector) {
+        if leader.ping_state.load(Ordering::SeqCst) != PingState::Emitting as u8 {
+            return;
+        }
+        let started = self.start_followed_ping(
+            leader.signal_start.load(Ordering::SeqCst),
+            leader.stream_signal_start.load(Ordering::SeqCst),
+            leader.ping_channel.load(Ordering::SeqCst),
+        );
+        if started {
+            PingState::transition(&self.ping_state, PingState::Idle, PingState::Emitting);
+        }
+    }
+
+    /// Times a ping that started playing at `signal_start_ns` on output
+    /// `channel`, or every channel if `None`, for replaying a recording of
+    /// the input that heard it. The ping starts whatever the detector was
+    /// doing, as the detector that played it was ready for it.
+    pub fn replay_ping(&self, signal_start_ns: u64, channel: Option<usize>) {
+        if self.start_followed_ping(signal_start_ns, 0, channel.unwrap_or(usize::MAX)) {
+            self.ping_state
+                .store(PingState::Emitting as u8, Ordering::SeqCst);
+        }
+    }
+
+    /// Takes on a ping started elsewhere, returning whether it's a new one.
+    fn start_followed_ping(
+        &self,
+        signal_start: u64,
+        stream_signal_start: u64,
+        ping_channel: usize,
+    ) -> bool {
+        if self.signal_start.swap(signal_start, Ordering::SeqCst) == signal_start {
+            return false;
+        }
+        self.stream_signal_start
+            .store(stream_signal_start, Ordering::SeqCst);
+        self.ping_channel.store(ping_channel, Ordering::SeqCst);
+        self.probe_clock.store(0, Ordering::SeqCst);
+        self.callback_lateness_ns.store(0, Ordering::SeqCst);
+        true
+    }
+
+    /// Moves the probe to output `channel`, or every channel if `None`, from
+    /// the next ping on.
+    pub fn set_output_channel(&self, channel: Option<usize>) {
+        self.output_channel
+            .store(channel.unwrap_or(usize::MAX), Ordering::SeqCst);
+    }
+
+    /// Output channel the current ping is played on, or `None` for every
+    /// channel.
+    fn ping_channel(&self) -> Option<usize> {
+        match self.ping_channel.load(Ordering::SeqCst) {
+            usize::MAX => None,
+            channel => Some(channel),
+        }
+    }
+
+    /// When the latest ping started playing, on the clock `fill_output` is
+    /// called with, and the output channel it played on, or `None` before
+    /// the first ping.
+    pub fn ping_start(&self) -> Option<(u64, Option<usize>)> {
+        match self.signal_start.load(Ordering::SeqCst) {
+            0 => None,
+            signal_start => Some((signal_start, self.ping_channel())),
+        }
+    }
+
+    /// Whether a requested ping hasn't started yet, or has started and not
+    /// yet been detected or timed out.
+    pub fn ping_pending(&self) -> bool {
+        self.ping_requested.load(Ordering::SeqCst)
+            || self.ping_state.load(Ordering::SeqCst) == PingState::Emitting as u8
+    }
+
+    /// Abandons the current ping if its tone was emitted more than
+    /// `timeout_ns` before `now_ns` without being detected, so that the next
+    /// ping can fire. A burst train times out from its last burst, and a
+    /// delayed probe from when it started. Returns whether the ping timed
+    /// out.
+    pub fn check_timeout(&self, now_ns: u64, timeout_ns: u64) -> bool {
+        if self.ping_state.load(Ordering::SeqCst) != PingState::Emitting as u8 {
+            return false;
+        }
+        let mut signal_start = self.signal_start.load(Ordering::SeqCst);
+        signal_start +=
+            (self.probe_delay_frames as f64 * 1e9 / self.config.sample_rate as f64) as u64;
+        if let Some(interval_frames) = self.train_interval_frames {
+            let last_burst_frames = interval_frames * (self.config.averages as u64 - 1);
+            signal_start +=
+                (last_burst_frames as f64 * 1e9 / self.config.sample_rate as f64) as u64;
+        }
+        if now_ns.saturating_sub(signal_start) < timeout_ns {
+            return false;
+        }
+        if !PingState::transition(&self.ping_state, PingState::Emitting, PingState::Detected) {
+            return false;
+        }
+        self.signal_end.store(now_ns, Ordering::SeqCst);
+        true
+    }
+
+    /// Fills an interleaved output buffer played at `frame_start_ns` with
+    /// either the probe or silence, and the noise floor under it if there is
+    /// one, never going past -1.0 to 1.0.
+    pub fn fill_output(&self, data: &mut [f32], frame_start_ns: u64, stream_ns: Option<u64>) {
+        if data.is_empty() || self.config.output_channels == 0 {
+            return;
+        }
+        self.fill_probe(data, frame_start_ns, stream_ns);
+        if let Some(noise_floor) = &self.noise_floor {
+            noise_floor.add(data, self.config.output_channels);
+        }
+    }
+
+    fn fill_probe(&self, data: &mut [f32], frame_start_ns: u64, stream_ns: Option<u64>) {
+        let since_last_ping = frame_start_ns.saturating_sub(self.signal_end.load(Ordering::SeqCst));
+        if self.plays()
+            && self.ping_state.load(Ordering::SeqCst) == PingState::Idle as u8
+            && since_last_ping >= self.config.interval_ns
+            && (!self.config.on_request || self.ping_requested.swap(false, Ordering::SeqCst))
+        {
+            self.signal_start.store(frame_start_ns, Ordering::SeqCst);
+            let wall_clock_us = (self.config.epoch_ns + frame_start_ns + 500) / 1000;
+            self.timecode
+                .store(timecode::encode(wall_clock_us), Ordering::SeqCst);
+            self.stream_signal_start
+                .store(stream_ns.unwrap_or(0), Ordering::SeqCst);
+            self.probe_clock.store(0, Ordering::SeqCst);
+            self.probe_delay_left
+                .store(self.probe_delay_frames, Ordering::SeqCst);
+            self.callback_lateness_ns.store(0, Ordering::SeqCst);
+            self.ping_channel
+                .store(self.output_channel.load(Ordering::SeqCst), Ordering::SeqCst);
+            PingState::transition(&self.ping_state, PingState::Idle, PingState::Emitting);
+        }
+
+        // The start of a delayed ping is held muted, leaving the rest of the
+        // buffer for the probe.
+        let delay_left = self.probe_delay_left.load(Ordering::SeqCst);
+        let frames = data.len() / self.config.output_channels;
+        let muted_frames = delay_left.min(frames as u64);
+        self.probe_delay_left
+            .store(delay_left - muted_frames, Ordering::SeqCst);
+        let (held, data) = data.split_at_mut(muted_frames as usize * self.config.output_channels);
+        held.fill(0f32);
+        if data.is_empty() {
+            return;
+        }
+
+        // A held tone plays until it's detected, a one-shot probe plays in
+        // full, and a train plays each of its bursts in full.
+        let mut clock = self.probe_clock.load(Ordering::SeqCst);
+        let emitting = match (self.probe_frames, self.train_interval_frames) {
+            (Some(probe_frames), None) if !self.held => clock < probe_frames,
+            (Some(probe_frames), Some(interval_frames)) => {
+                clock < interval_frames * (self.config.averages as u64 - 1) + probe_frames
+            }
+            // A looped WAV file plays through at least once, to be measured
+            // whole.
+            (probe_frames, _) => {
+                clock < probe_frames.unwrap_or_default()
+                    || self.plays()
+                        && self.ping_state.load(Ordering::SeqCst) == PingState::Emitting as u8
+            }
+        };
+        // A held tone that has just been detected fades out first.
+        let mut fade_out = None;
+        if !emitting {
+            match self.fade_out_frames.load(Ordering::SeqCst) {
+                0 => {
+                    // Mute
+                    for sample in data.iter_mut() {
+                        *sample = 0f32;
+                    }
+                    return;
+                }
+                frames => fade_out = Some(frames),
+            }
+        }
+
+        let output_channel = self.ping_channel();
+        let timecode = self.timecode.load(Ordering::SeqCst);
+        for frame in data.chunks_mut(self.config.output_channels) {
+            clock = clock.saturating_add(1);
+            let gain = match &mut fade_out {
+                Some(0) => 0.0,
+                // Retraces the fade-in, from wherever it had got to.
+                Some(frames) => {
+                    *frames -= 1;
+                    self.ramp_gain(*frames)
+                }
+                None => self.ramp_gain(clock),
+            };
+            let value = match (&self.config.probe, self.train_interval_frames) {
+                (Probe::Timecode, _) => timecode::value(timecode, self.config.sample_rate, clock),
+                (probe, Some(interval_frames)) => {
+                    let slot_clock = (clock - 1) % interval_frames + 1;
+                    match self.probe_frames {
+                        Some(probe_frames) if slot_clock <= probe_frames => {
+                            probe.value(self.config.frequency, self.config.sample_rate, slot_clock)
+                        }
+                        _ => 0.0,
+                    }
+                }
+                (probe, None) => probe.value(self.config.frequency, self.config.sample_rate, clock),
+            } * self.config.volume
+                * self.config.output_gain
+                * gain;
+            let value = value.clamp(-1.0, 1.0);
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                *sample = if output_channel.is_none_or(|c| c == channel) {
+                    value
+                } else {
+                    0f32
+                };
+            }
+        }
+        self.probe_clock.store(clock, Ordering::SeqCst);
+        let fade_out_frames = fade_out.unwrap_or(clock.min(self.ramp_frames));
+        self.fade_out_frames
+            .store(fade_out_frames, Ordering::SeqCst);
+    }
+
+    /// Gain of a held tone `frames` into its fade-in.
+    fn ramp_gain(&self, frames: u64) -> f32 {
+        if frames >= self.ramp_frames {
+            return 1.0;
+        }
+        0.5 - 0.5 * (PI * frames as f32 / self.ramp_frames as f32).cos()
+    }
+}
+
+/// Cross-correlates `reference` against `samples` at every lag from
+/// `first_lag` to `last_lag` inclusive, which must all fit within `samples`.
+fn correlate(samples: &[f32], reference: &[f32], first_lag: usize, last_lag: usize) -> Vec<f32> {
+    (first_lag..=last_lag)
+        .map(|lag| {
+            samples[lag..lag + reference.len()]
+                .iter()
+                .zip(reference.iter())
+                .map(|(sample, reference)| sample * reference)
+                .sum()
+        })
+        .collect()
+}
+
+/// Fractional offset of the true peak from `correlation[peak]`, found by
+/// fitting a parabola through it and its neighbours.
+fn parabolic_offset(correlation: &[f32], peak: usize) -> f64 {
+    if peak == 0 || peak + 1 >= correlation.len() {
+        return 0.0;
+    }
+    let (before, at, after) = (
+        correlation[peak - 1] as f64,
+        correlation[peak] as f64,
+        correlation[peak + 1] as f64,
+    );
+    let curvature = before - 2.0 * at + after;
+    if curvature < 0.0 {
+        0.5 * (before - after) / curvature
+    } else {
+        0.0
+    }
+}
+
+/// Fractional index into `samples` at which its envelope, the RMS over
+/// `period_frames` centred on each sample, last rises through half of its
+/// peak before reaching it. Returns `None` if `samples` is silent.
+fn envelope_crossing(samples: &[f32], period_frames: usize) -> Option<f64> {
+    if samples.len() < period_frames {
+        return None;
+    }
+    let mut sum_squares: f64 = samples[..period_frames]
+        .iter()
+        .map(|sample| (sample * sample) as f64)
+        .sum();
+    let mut envelope = vec![sum_squares.sqrt()];
+    for (i, sample) in samples.iter().enumerate().skip(period_frames) {
+        let oldest = samples[i - period_frames];
+        sum_squares += (sample * sample) as f64 - (oldest * oldest) as f64;
+        envelope.push(sum_squares.max(0.0).sqrt());
+    }
+
+    let peak = (0..envelope.len()).max_by(|a, b| envelope[*a].total_cmp(&envelope[*b]))?;
+    let half = envelope[peak] / 2.0;
+    if half <= 0.0 {
+        return None;
+    }
+    let below = (0..peak).rev().find(|&i| envelope[i] < half)?;
+    let fraction = (half - envelope[below]) / (envelope[below + 1] - envelope[below]);
+    Some(below as f64 + fraction + (period_frames - 1) as f64 / 2.0)
+}
+
+/// Difference between the largest and smallest of `samples`.
+fn peak_to_peak(samples: &[f32]) -> f32 {
+    let (min, max) = samples
+        .iter()
+        .fold((f32::MAX, f32::MIN), |(min, max), &sample| {
+            (min.min(sample), max.max(sample))
+        });
+    (max - min).max(0.0)
+}
+
+/// RMS of `samples`, scaled to the peak-to-peak amplitude of a pure tone with
+/// the same RMS so the same sensitivity values apply to every detector.
+fn rms_amplitude(samples: &mut dyn Iterator<Item = f32>) -> f32 {
+    let (mut sum_squares, mut count) = (0f32, 0usize);
+    for sample in samples {
+        sum_squares += sample * sample;
+        count += 1;
+    }
+    if count == 0 {
+        return 0.0;
+    }
+    2.0 * std::f32::consts::SQRT_2 * (sum_squares / count as f32).sqrt()
+}
+
+/// Energy of `samples` at the frequency `coeff` was computed for, scaled to
+/// the peak-to-peak amplitude of a pure tone at that frequency.
+fn goertzel_amplitude(coeff: f32, samples: &mut dyn Iterator<Item = f32>) -> f32 {
+    let (mut s1, mut s2, mut count) = (0f32, 0f32, 0usize);
+    for sample in samples {
+        let s0 = sample + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s0;
+        count += 1;
+    }
+    if count == 0 {
+        return 0.0;
+    }
+    let power = s1 * s1 + s2 * s2 - coeff * s1 * s2;
+    4.0 * power.max(0.0).sqrt() / count as f32
+}
+
+/// Running estimates over the delays reported so far, in milliseconds.
+#[derive(Clone, Copy, Debug)]
+pub struct DelayTrend {
+    /// Exponentially weighted moving average, weighted by the alpha its
+    /// `DelayTracker` was made with.
+    pub smoothed_ms: f32,
+    /// Interarrival jitter as defined by RFC 3550: a running average of the
+    /// difference between consecutive delays.
+    pub jitter_ms: f32,
+}
+
+/// Weight of each new difference in the RFC 3550 jitter estimate.
+const JITTER_GAIN: f32 = 1.0 / 16.0;
+
+/// Folds the difference between two consecutive delays into `jitter_ms`.
+fn next_jitter(jitter_ms: f32, previous_ms: f32, delay_ms: f32) -> f32 {
+    jitter_ms + ((delay_ms - previous_ms).abs() - jitter_ms) * JITTER_GAIN
+}
+
+/// Follows the trend of the delays measured by one detector. Fed by whatever
+/// reports the measurements, once warmup, discards and averaging are done
+/// with them, so the trend is only of the delays actually reported.
+pub struct DelayTracker {
+    /// Weight of each new delay in the moving average, from 0.0 to 1.0.
+    alpha: f32,
+    /// The last delay and the trend up to it, or `None` before the first
+    /// one.
+    last: Option<(f32, DelayTrend)>,
+}
+
+impl DelayTracker {
+    pub fn new(alpha: f32) -> DelayTracker {
+        DelayTracker { alpha, last: None }
+    }
+
+    /// Folds a reported delay into the moving average and jitter estimate and
+    /// returns them. The first delay is taken as the average, with no jitter.
+    pub fn push(&mut self, delay_ms: f32) -> DelayTrend {
+        let alpha = self.alpha;
+        let next = match self.last {
+            Some((previous_ms, trend)) => DelayTrend {
+                smoothed_ms: alpha * delay_ms + (1.0 - alpha) * trend.smoothed_ms,
+                jitter_ms: next_jitter(trend.jitter_ms, previous_ms, delay_ms),
+            },
+            None => DelayTrend {
+                smoothed_ms: delay_ms,
+                jitter_ms: 0.0,
+            },
+        };
+        self.last = Some((delay_ms, next));
+        next
+    }
+
+    /// Forgets the delays tracked so far, so the next one starts a new
+    /// average with no jitter.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+/// Aggregate statistics over a run's measured delays, in milliseconds.
+pub struct Summary {
+    pub count: usize,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub median: f32,
+    pub stddev: f32,
+    pub p95: f32,
+    pub p99: f32,
+    /// Half-width of the 95% confidence interval on the mean, or `None` for
+    /// a single delay.
+    pub ci95: Option<f32>,
+    /// RFC 3550 jitter over the delays in the order they were measured.
+    pub jitter: f32,
+    /// How fast the delay has been changing over the last `DRIFT_WINDOW_NS`,
+    /// in ms per hour, or `None` until the delays span `DRIFT_MIN_SPAN_NS`
+    /// or when they weren't timestamped.
+    pub drift_ms_per_hour: Option<f32>,
+}
+
+impl Summary {
+    /// Computes the summary, or returns `None` if there were no measurements.
+    pub fn new(delays: &[f32]) -> Option<Summary> {
+        if delays.is_empty() {
+            return None;
+        }
+
+        let mut sorted = delays.to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        Some(Summary::from_sorted(&sorted, delays.iter().copied()))
+    }
+
+    /// Summarizes the delays in `sorted`, in order of size, which `measured`
+    /// gives in the order they were measured in for the jitter. `sorted`
+    /// mustn't be empty.
+    fn from_sorted(sorted: &[f32], measured: impl Iterator<Item = f32>) -> Summary {
+        let count = sorted.len();
+        let mean = sorted.iter().sum::<f32>() / count as f32;
+        let variance = sorted.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / count as f32;
+        let mut measured = measured;
+        let mut jitter = 0.0;
+        if let Some(mut previous) = measured.next() {
+            for delay in measured {
+                jitter = next_jitter(jitter, previous, delay);
+                previous = delay;
+            }
+        }
+        Summary {
+            count,
+            min: sorted[0],
+            max: sorted[count - 1],
+            mean,
+            median: median(sorted),
+            stddev: variance.sqrt(),
+            p95: percentile(sorted, 95.0),
+            p99: percentile(sorted, 99.0),
+            ci95: confidence_interval(variance.sqrt(), count),
+            jitter,
+            drift_ms_per_hour: None,
+        }
+    }
+}
+
+/// Number of delays a [`DelayStats`] keeps a sample of for its median and
+/// percentiles.
+const RESERVOIR_SIZE: usize = 10_000;
+
+/// Span of the most recent delays that the drift is fitted over.
+pub const DRIFT_WINDOW_NS: u64 = 3_600_000_000_000;
+
+/// How long the delays must span before their drift is reported, as a slope
+/// fitted over a minute or two of noisy delays says little about an hour.
+const DRIFT_MIN_SPAN_NS: u64 = 600_000_000_000;
+
+/// A least-squares line through the delays measured over the last
+/// `DRIFT_WINDOW_NS`, kept as running sums that each delay is added to and
+/// taken back out of as the window moves on.
+#[derive(Default)]
+struct DriftFit {
+    /// Timestamp and delay of each measurement in the window, oldest first.
+    points: VecDeque<(u64, f32)>,
+    /// Timestamp the others are counted from, so the sums keep their
+    /// precision however long the run goes.
+    origin_ns: Option<u64>,
+    sum_hours: f64,
+    sum_delays: f64,
+    sum_hours_squared: f64,
+    sum_products: f64,
+}
+
+impl DriftFit {
+    fn push(&mut self, timestamp_ns: u64, delay_ms: f32) {
+        self.points.push_back((timestamp_ns, delay_ms));
+        self.add(timestamp_ns, delay_ms, 1.0);
+        let oldest_ns = timestamp_ns.saturating_sub(DRIFT_WINDOW_NS);
+        while let Some(&(timestamp_ns, delay_ms)) = self.points.front() {
+            if timestamp_ns >= oldest_ns {
+                break;
+            }
+            self.points.pop_front();
+            self.add(timestamp_ns, delay_ms, -1.0);
+        }
+    }
+
+    /// Adds a point to the sums with `sign` 1.0, or takes it out with -1.0.
+    fn add(&mut self, timestamp_ns: u64, delay_ms: f32, sign: f64) {
+        let origin_ns = *self.origin_ns.get_or_insert(timestamp_ns);
+        let hours = (timestamp_ns as f64 - origin_ns as f64) / 3.6e12;
+        let delay_ms = delay_ms as f64;
+        self.sum_hours += sign * hours;
+        self.sum_delays += sign * delay_ms;
+        self.sum_hours_squared += sign * hours * hours;
+        self.sum_products += sign * hours * delay_ms;
+    }
+
+    /// The line's slope in ms per hour.
+    fn slope(&self) -> Option<f32> {
+        let (first_ns, _) = self.points.front()?;
+        let (last_ns, _) = self.points.back()?;
+        if last_ns - first_ns < DRIFT_MIN_SPAN_NS {
+            return None;
+        }
+        let count = self.points.len() as f64;
+        let spread = count * self.sum_hours_squared - self.sum_hours * self.sum_hours;
+        if spread <= 0.0 {
+            return None;
+        }
+        let slope = (count * self.sum_products - self.sum_hours * self.sum_delays) / spread;
+        Some(slope as f32)
+    }
+}
+
+/// Statistics over a run's delays, kept up to date as each one is measured
+/// and, apart from the last `DRIFT_WINDOW_NS` of delays kept for the drift, in
+/// bounded memory however long the run goes. The mean and variance are kept
+/// with Welford's algorithm, and the median and percentiles taken from a
+/// uniform random sample of `RESERVOIR_SIZE` delays, which holds every delay
+/// until there are more than that.
+pub struct DelayStats {
+    count: usize,
+    mean: f64,
+    /// Sum of the squared differences from the mean.
+    m2: f64,
+    min: f32,
+    max: f32,
+    jitter: f32,
+    last: Option<f32>,
+    reservoir: Vec<f32>,
+    /// SplitMix64 state picking which delays the reservoir keeps.
+    rng: u64,
+    drift: DriftFit,
+}
+
+impl Default for DelayStats {
+    fn default() -> DelayStats {
+        DelayStats::new()
+    }
+}
+
+impl DelayStats {
+    pub fn new() -> DelayStats {
+        DelayStats {
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+            min: f32::INFINITY,
+            max: f32::NEG_INFINITY,
+            jitter: 0.0,
+            last: None,
+            reservoir: Vec::new(),
+            rng: 0,
+            drift: DriftFit::default(),
+        }
+    }
+
+    /// Adds a delay measured at `timestamp_ns`.
+    pub fn push(&mut self, timestamp_ns: u64, delay_ms: f32) {
+        self.drift.push(timestamp_ns, delay_ms);
+        self.count += 1;
+        let delta = delay_ms as f64 - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (delay_ms as f64 - self.mean);
+        self.min = self.min.min(delay_ms);
+        self.max = self.max.max(delay_ms);
+        if let Some(last) = self.last {
+            self.jitter = next_jitter(self.jitter, last, delay_ms);
+        }
+        self.last = Some(delay_ms);
+
+        if self.reservoir.len() < RESERVOIR_SIZE {
+            self.reservoir.push(delay_ms);
+            return;
+        }
+        // Each of the delays so far stays in the reservoir with the same
+        // chance.
+        let slot = (self.next_random() % self.count as u64) as usize;
+        if slot < RESERVOIR_SIZE {
+            self.reservoir[slot] = delay_ms;
+        }
+    }
+
+    /// The summary so far, or `None` before the first delay.
+    pub fn summary(&self) -> Option<Summary> {
+        if self.count == 0 {
+            return None;
+        }
+        let mut sorted = self.reservoir.clone();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let stddev = (self.m2 / self.count as f64).sqrt() as f32;
+        Some(Summary {
+            count: self.count,
+            min: self.min,
+            max: self.max,
+            mean: self.mean as f32,
+            median: median(&sorted),
+            stddev,
+            p95: percentile(&sorted, 95.0),
+            p99: percentile(&sorted, 99.0),
+            ci95: confidence_interval(stddev, self.count),
+            jitter: self.jitter,
+            drift_ms_per_hour: self.drift_ms_per_hour(),
+        })
+    }
+
+    /// `Summary::drift_ms_per_hour` so far, without the rest of the summary.
+    pub fn drift_ms_per_hour(&self) -> Option<f32> {
+        self.drift.slope()
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e3779b97f4a7c15);
+        let mut x = self.rng;
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+        x ^ (x >> 31)
+    }
+}
+
+/// Combines each run of `count` consecutive measurements into one, to report
+/// fewer but steadier delays.
+pub struct MeasurementAverager {
+    count: usize,
+    pending: Vec<Measurement>,
+}
+
+impl MeasurementAverager {
+    pub fn new(count: usize) -> MeasurementAverager {
+        MeasurementAverager {
+            count: count.max(1),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Adds a measurement. Once `count` have been added, returns their
+    /// average, timestamped with the last of them, and the sample standard
+    /// deviation of their delays, and starts over. Optional delays are only
+    /// averaged if every measurement has one, and the worst scheduling jitter
+    /// and lowest confidence of any of them are kept, as is whether any came
+    /// back inverted.
+    pub fn push(&mut self, measurement: Measurement) -> Option<(Measurement, f32)> {
+        self.pending.push(measurement);
+        if self.pending.len() < self.count {
+            return None;
+        }
+        let n = self.pending.len() as f32;
+        let mean = |value: fn(&Measurement) -> f32| self.pending.iter().map(value).sum::<f32>() / n;
+        let mean_of_all = |value: fn(&Measurement) -> Option<f32>| {
+            let values: Option<Vec<f32>> = self.pending.iter().map(value).collect();
+            values.map(|values| values.iter().sum::<f32>() / n)
+        };
+        let delay_ms = mean(|m| m.delay_ms);
+        let stddev_ms = if self.pending.len() > 1 {
+            let sum_squares: f32 = self
+                .pending
+                .iter()
+                .map(|m| (m.delay_ms - delay_ms).powi(2))
+                .sum();
+            (sum_squares / (n - 1.0)).sqrt()
+        } else {
+            0.0
+        };
+        let last = self.pending[self.pending.len() - 1];
+        let average = Measurement {
+            timestamp_ns: last.timestamp_ns,
+            delay_ms,
+            stream_delay_ms: mean_of_all(|m| m.stream_delay_ms),
+            amplitude: mean(|m| m.amplitude),
+            compare_delay_ms: mean_of_all(|m| m.compare_delay_ms),
+            timecode_us: last.timecode_us,
+            scheduling_jitter_ms: self
+                .pending
+                .iter()
+                .filter_map(|m| m.scheduling_jitter_ms)
+                .reduce(f32::max),
+            output: last.output,
+            input: last.input,
+            confidence: self
+                .pending
+                .iter()
+                .map(|m| m.confidence)
+                .fold(1.0, f32::min),
+            inverted: self.pending.iter().any(|m| m.inverted),
+            output_channel: last.output_channel,
+        };
+        self.pending.clear();
+        Some((average, stddev_ms))
+    }
+
+    /// Drops the measurements added since the last average, so the next
+    /// average starts from the next one.
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+}
+
+/// The delays measured over a sliding window of time, for rolling statistics.
+pub struct DelayWindow {
+    window_ns: u64,
+    /// Timestamp and delay of each measurement in the window, oldest first.
+    delays: VecDeque<(u64, f32)>,
+    /// The same delays in order of size, kept in order as they come and go
+    /// rather than sorted for each summary.
+    sorted: Vec<f32>,
+}
+
+impl DelayWindow {
+    pub fn new(window_ns: u64) -> DelayWindow {
+        DelayWindow {
+            window_ns,
+            delays: VecDeque::new(),
+            sorted: Vec::new(),
+        }
+    }
+
+    /// Adds a delay measured at `timestamp_ns`, drops any measured more than
+    /// the window before it, and summarizes those left.
+    pub fn push(&mut self, timestamp_ns: u64, delay_ms: f32) -> Summary {
+        self.delays.push_back((timestamp_ns, delay_ms));
+        // Placed and found by the same ordering, so that every delay added
+        // can be removed again.
+        let index = self
+            .sorted
+            .partition_point(|sorted| sorted.total_cmp(&delay_ms).is_lt());
+        self.sorted.insert(index, delay_ms);
+        let oldest_ns = timestamp_ns.saturating_sub(self.window_ns);
+        while let Some(&(timestamp_ns, delay_ms)) = self.delays.front() {
+            if timestamp_ns >= oldest_ns {
+                break;
+            }
+            self.delays.pop_front();
+            if let Ok(index) = self
+                .sorted
+                .binary_search_by(|sorted| sorted.total_cmp(&delay_ms))
+            {
+                self.sorted.remove(index);
+            }
+        }
+        // Always holds at least the delay just added.
+        Summary::from_sorted(
+            &self.sorted,
+            self.delays.iter().map(|(_, delay_ms)| *delay_ms),
+        )
+    }
+
+    /// Empties the window, as though no delay had been measured yet.
+    pub fn reset(&mut self) {
+        self.delays.clear();
+        self.sorted.clear();
+    }
+}
+
+/// Ends of the windows to scan a block of `frames` frames with, counted from
+/// the start of the `history_frames` frames before it: every `hop`th frame
+/// since the input started that falls in the block, or the last one before
+/// it if none does. Placed by the input's frames rather than the block's,
+/// the windows, and so the frame a detector triggers at, don't depend on how
+/// the host splits the input into buffers, as long as the history keeps a
+/// window and a hop's worth of frames.
+fn window_ends(
+    frames_before: u64,
+    history_frames: usize,
+    frames: usize,
+    hop: usize,
+) -> std::iter::StepBy<std::ops::Range<usize>> {
+    let hop = hop.max(1) as u64;
+    let history_start = frames_before - history_frames as u64;
+    let block_end = frames_before + frames as u64;
+    let first = (frames_before / hop + 1) * hop;
+    let (first, last) = if first <= block_end {
+        (first, block_end / hop * hop)
+    } else {
+        let before = frames_before / hop * hop;
+        (before, before)
+    };
+    if first == 0 || first <= history_start {
+        return (0..0).step_by(1);
+    }
+    ((first - history_start) as usize..(last - history_start) as usize + 1).step_by(hop as usize)
+}
+
+/// The sample on `channel` `frame` frames from the start of `history`, the
+/// interleaved frames before the block `data`.
+fn history_sample(
+    history: &[f32],
+    data: &[f32],
+    channels: usize,
+    channel: usize,
+    frame: usize,
+) -> f32 {
+    let history_frames = history.len() / channels;
+    if frame < history_frames {
+        history[frame * channels + channel]
+    } else {
+        data[(frame - history_frames) * channels + channel]
+    }
+}
+
+/// Peak-to-peak amplitude over a window sliding along a run of samples.
+#[derive(Default)]
+struct SlidingPeakToPeak {
+    /// Indices of the samples that may yet be the window's maximum or
+    /// minimum, the extreme first.
+    maxima: VecDeque<(usize, f32)>,
+    minima: VecDeque<(usize, f32)>,
+}
+
+impl SlidingPeakToPeak {
+    /// Starts a new run.
+    fn clear(&mut self) {
+        self.maxima.clear();
+        self.minima.clear();
+    }
+
+    /// Adds `sample`, at `index` in the run, and returns the peak-to-peak
+    /// amplitude of the `window` samples up to and including it, or of as
+    /// many as came before it.
+    fn push(&mut self, index: usize, sample: f32, window: usize) -> f32 {
+        while self.maxima.back().is_some_and(|(_, max)| *max <= sample) {
+            self.maxima.pop_back();
+        }
+        self.maxima.push_back((index, sample));
+        while self.minima.back().is_some_and(|(_, min)| *min >= sample) {
+            self.minima.pop_back();
+        }
+        self.minima.push_back((index, sample));
+        for extremes in [&mut self.maxima, &mut self.minima] {
+            while extremes.front().is_some_and(|(i, _)| i + window <= index) {
+                extremes.pop_front();
+            }
+        }
+        self.maxima[0].1 - self.minima[0].1
+    }
+}
+
+/// What's left of `amplitude` once the energy of `reference`, an amplitude
+/// of the ambient noise measured the same way, is taken out of it. Every
+/// detector, and the noise floor measured for `--auto-sensitivity`, goes
+/// through this, so that they all take the reference out alike.
+pub(crate) fn without_reference(amplitude: f32, reference: f32) -> f32 {
+    (amplitude * amplitude - reference * reference)
+        .max(0.0)
+        .sqrt()
+}
+
+/// Median of an already sorted, non-empty slice.
+fn median(sorted: &[f32]) -> f32 {
+    let count = sorted.len();
+    if count.is_multiple_of(2) {
+        (sorted[count / 2 - 1] + sorted[count / 2]) / 2.0
+    } else {
+        sorted[count / 2]
+    }
+}
+
+/// Two-sided 95% critical values of Student's t distribution for 1 to 30
+/// degrees of freedom.
+const T_95: [f32; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+
+/// Half-width of the 95% confidence interval on the mean of `count` delays
+/// whose population standard deviation is `stddev`: t times the sample
+/// standard deviation over the square root of `count`. Past the table, t is
+/// the normal 1.96 corrected for the degrees of freedom, which is within
+/// 0.2% of it.
+fn confidence_interval(stddev: f32, count: usize) -> Option<f32> {
+    const Z_95: f32 = 1.959964;
+    let degrees = count.checked_sub(1).filter(|degrees| *degrees > 0)?;
+    let t = match T_95.get(degrees - 1) {
+        Some(t) => *t,
+        None => Z_95 + (Z_95.powi(3) + Z_95) / (4.0 * degrees as f32),
+    };
+    let sample_stddev = stddev * (count as f32 / degrees as f32).sqrt();
+    Some(t * sample_stddev / (count as f32).sqrt())
+}
+
+/// Nearest-rank percentile of an already sorted, non-empty slice.
+fn percentile(sorted: &[f32], p: f32) -> f32 {
+    let rank = (p / 100.0 * sorted.len() as f32).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48000.0;
+
+    /// Frames in each output block, and the input's unless told otherwise.
+    const BLOCK_FRAMES: usize = 480;
+
+    /// A tone at half volume over a unity gain loopback, triggering at a
+    /// quarter of its peak-to-peak amplitude.
+    fn config(detector: Detector) -> DetectorConfig {
+        DetectorConfig {
+            sample_rate: SAMPLE_RATE,
+            sensitivity: 0.25,
+            detector,
+            ..DetectorConfig::default()
+        }
+    }
+
+    fn detectors() -> Vec<Detector> {
+        vec![
+            Detector::Rms,
+            Detector::Peak,
+            Detector::Goertzel,
+            #[cfg(feature = "fft")]
+            Detector::Fft {
+                size: 1024,
+                hop: 256,
+            },
+        ]
+    }
+
+    fn frames_ms(frames: usize) -> f32 {
+        frames as f32 * 1000.0 / SAMPLE_RATE
+    }
+
+    fn measurement(timestamp_ns: u64, delay_ms: f32) -> Measurement {
+        Measurement {
+            timestamp_ns,
+            delay_ms,
+            stream_delay_ms: Some(delay_ms + 1.0),
+            amplitude: 1.0,
+            compare_delay_ms: None,
+            timecode_us: None,
+            scheduling_jitter_ms: None,
+            confidence: delay_ms / 10.0,
+            inverted: false,
+            output: 0,
+            input: 0,
+            output_channel: None,
+        }
+    }
+
+    /// Captures `duration_ns` of silence from `start_ns`, one block at a time.
+    fn feed_silence(
+        detector: &LatencyDetector,
+        state: &mut InputState,
+        start_ns: u64,
+        duration_ns: u64,
+    ) {
+        let block_ns = (BLOCK_FRAMES as f64 * 1e9 / SAMPLE_RATE as f64) as u64;
+        for block in 1..=duration_ns / block_ns {
+            detector.process_input(
+                state,
+                &[0f32; BLOCK_FRAMES],
+                start_ns + block * block_ns,
+                None,
+            );
+        }
+    }
+
+    /// Plays the detector's output back into its input `delay_frames` late,
+    /// heard in blocks cycling through the lengths in `input_blocks`, and
+    /// returns the first `pings` measurements, or as many as were made in
+    /// two seconds per ping.
+    fn loopback(
+        detector: &LatencyDetector,
+        delay_frames: usize,
+        input_blocks: &[usize],
+        pings: usize,
+    ) -> Vec<Measurement> {
+        let frame_ns = 1e9 / SAMPLE_RATE as f64;
+        let mut state = detector.input_state();
+        let mut played = vec![0f32; delay_frames];
+        let mut output = vec![0f32; BLOCK_FRAMES];
+        let mut output_blocks = 0;
+        let mut input_frames = 0;
+        let mut measurements = Vec::new();
+        for input_block in input_blocks.iter().cycle() {
+            let input_end = input_frames + input_block;
+            if input_end > pings * 2 * SAMPLE_RATE as usize || measurements.len() == pings {
+                break;
+            }
+            while output_blocks * BLOCK_FRAMES < input_end {
+                let start_ns = (output_blocks * BLOCK_FRAMES) as f64 * frame_ns;
+                detector.fill_output(&mut output, start_ns as u64, Some(start_ns as u64));
+                played.extend_from_slice(&output);
+                output_blocks += 1;
+            }
+            measurements.extend(detector.process_input(
+                &mut state,
+                &played[input_frames..input_end],
+                (input_end as f64 * frame_ns) as u64,
+                Some((input_frames as f64 * frame_ns) as u64),
+            ));
+            input_frames = input_end;
+        }
+        measurements
+    }
+
+    #[test]
+    fn each_detector_measures_every_ping() {
+        for detector in detectors() {
+            let measurements = loopback(
+                &LatencyDetector::new(config(detector)),
+                733,
+                &[BLOCK_FRAMES],
+                3,
+            );
+            // Onsets are only accurate to about a period, a whole window for
+            // the windowed detectors or a hop for the FFT.
+            let periods = match detector {
+                Detector::Peak => 1.0,
+                Detector::Rms | Detector::Goertzel => WINDOW_PERIODS,
+                #[cfg(feature = "fft")]
+                Detector::Fft { hop, .. } => hop as f32 * 440.0 / SAMPLE_RATE,
+            };
+            let tolerance_ms = periods * 1000.0 / 440.0;
+            assert_eq!(measurements.len(), 3);
+            for measurement in measurements {
+                assert!(
+                    (measurement.delay_ms - frames_ms(733)).abs() <= tolerance_ms,
+                    "{:?} measured {}ms",
+                    detector,
+                    measurement.delay_ms
+                );
+                let stream_delay_ms = measurement.stream_delay_ms.unwrap();
+                assert!(
+                    (stream_delay_ms - frames_ms(733)).abs() <= tolerance_ms,
+                    "{:?} measured {}ms from stream timestamps",
+                    detector,
+                    stream_delay_ms
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn precise_mode_measures_the_exact_delay() {
+        for detector in detectors() {
+            let config = DetectorConfig {
+                precise: true,
+                ..config(detector)
+            };
+            let measurements = loopback(&LatencyDetector::new(config), 733, &[BLOCK_FRAMES], 3);
+            assert_eq!(measurements.len(), 3);
+            for measurement in measurements {
+                assert!(
+                    (measurement.delay_ms - frames_ms(733)).abs() < 0.001,
+                    "{:?} measured {}ms",
+                    detector,
+                    measurement.delay_ms
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn one_shot_probes_are_measured_by_correlation() {
+        let probes = [
+            Probe::Click,
+            Probe::Noise {
+                duration_ms: 50.0,
+                seed: 0,
+            },
+            Probe::Sweep {
+                start_hz: 100.0,
+                end_hz: 1000.0,
+                duration_ms: 50.0,
+                scale: SweepScale::Linear,
+            },
+        ];
+        for probe in probes {
+            let config = DetectorConfig {
+                probe: probe.clone(),
+                ..config(Detector::Rms)
+            };
+            let measurements = loopback(&LatencyDetector::new(config), 1234, &[BLOCK_FRAMES], 1);
+            assert_eq!(measurements.len(), 1);
+            assert!(
+                (measurements[0].delay_ms - frames_ms(1234)).abs() <= 0.05,
+                "{:?} measured {}ms",
+                probe,
+                measurements[0].delay_ms
+            );
+        }
+    }
+
+    #[test]
+    fn uneven_input_blocks_measure_the_same_delay() {
+        for detector in detectors() {
+            for precise in [false, true] {
+                let config = DetectorConfig {
+                    precise,
+                    ..config(detector)
+                };
+                let even = loopback(
+                    &LatencyDetector::new(config.clone()),
+                    733,
+                    &[BLOCK_FRAMES],
+                    1,
+                )[0];
+                let uneven = loopback(
+                    &LatencyDetector::new(config),
+                    733,
+                    &[13, BLOCK_FRAMES, 160, 1, 701],
+                    1,
+                )[0];
+                assert!(
+                    (uneven.delay_ms - even.delay_ms).abs() <= 0.001,
+                    "{:?} measured {}ms from even blocks and {}ms from uneven ones",
+                    detector,
+                    even.delay_ms,
+                    uneven.delay_ms
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ping_state_only_moves_from_the_expected_state() {
+        let state = AtomicU8::new(PingState::Idle as u8);
+        assert!(!PingState::transition(
+            &state,
+            PingState::Emitting,
+            PingState::Detected
+        ));
+        assert!(PingState::transition(
+            &state,
+            PingState::Idle,
+            PingState::Emitting
+        ));
+        assert!(!PingState::transition(
+            &state,
+            PingState::Idle,
+            PingState::Emitting
+        ));
+        assert!(PingState::transition(
+            &state,
+            PingState::Emitting,
+            PingState::Detected
+        ));
+        assert!(PingState::transition(
+            &state,
+            PingState::Detected,
+            PingState::Idle
+        ));
+        assert_eq!(state.load(Ordering::SeqCst), PingState::Idle as u8);
+    }
+
+    #[test]
+    fn first_ping_waits_for_the_input_to_go_silent() {
+        let detector = LatencyDetector::new(config(Detector::Peak));
+        let mut state = detector.input_state();
+        let mut output = vec![1f32; BLOCK_FRAMES];
+        feed_silence(&detector, &mut state, 0, STARTUP_SILENCE_NS / 2);
+        detector.fill_output(&mut output, STARTUP_SILENCE_NS / 2, None);
+        assert!(output.iter().all(|sample| *sample == 0.0));
+        feed_silence(
+            &detector,
+            &mut state,
+            STARTUP_SILENCE_NS / 2,
+            STARTUP_SILENCE_NS,
+        );
+        detector.fill_output(&mut output, 3 * STARTUP_SILENCE_NS / 2, None);
+        assert!(output.iter().any(|sample| *sample != 0.0));
+    }
+
+    #[test]
+    fn inverted_returns_are_flagged() {
+        let probes = [
+            Probe::Click,
+            Probe::Noise {
+                duration_ms: 50.0,
+                seed: 0,
+            },
+            Probe::Sweep {
+                start_hz: 100.0,
+                end_hz: 1000.0,
+                duration_ms: 50.0,
+                scale: SweepScale::Linear,
+            },
+        ];
+        for probe in probes {
+            for invert_detection in [false, true] {
+                let config = DetectorConfig {
+                    probe: probe.clone(),
+                    invert_detection,
+                    ..config(Detector::Rms)
+                };
+                let measurement =
+                    loopback(&LatencyDetector::new(config), 1234, &[BLOCK_FRAMES], 1)[0];
+                assert_eq!(measurement.inverted, invert_detection, "{:?}", probe);
+                assert!(
+                    (measurement.delay_ms - frames_ms(1234)).abs() <= 0.05,
+                    "{:?} measured {}ms",
+                    probe,
+                    measurement.delay_ms
+                );
+            }
+        }
+        // An inverted held tone looks like one upright half a period later.
+        let config = DetectorConfig {
+            precise: true,
+            invert_detection: true,
+            ..config(Detector::Rms)
+        };
+        let measurement = loopback(&LatencyDetector::new(config), 1234, &[BLOCK_FRAMES], 1)[0];
+        assert!(!measurement.inverted);
+    }
+
+    #[test]
+    fn probes_barely_over_the_sensitivity_score_low_confidence() {
+        for detector in detectors() {
+            let confidence = |sensitivity| {
+                let config = DetectorConfig {
+                    sensitivity,
+                    ..config(detector)
+                };
+                loopback(&LatencyDetector::new(config), 733, &[BLOCK_FRAMES], 1)[0].confidence
+            };
+            assert!(confidence(0.25) > 0.4, "{:?}", detector);
+            assert!(confidence(0.9) < 0.2, "{:?}", detector);
+        }
+    }
+
+    #[test]
+    fn late_input_callbacks_are_flagged() {
+        let detector = LatencyDetector::new(config(Detector::Peak));
+        let mut state = detector.input_state();
+        let mut output = vec![0f32; BLOCK_FRAMES];
+        let start_ns = 2 * STARTUP_SILENCE_NS;
+        feed_silence(&detector, &mut state, 0, start_ns);
+        detector.fill_output(&mut output, start_ns, None);
+        // The block is captured 30ms after the last one, 20ms later than its
+        // length.
+        let measurement = detector
+            .process_input(&mut state, &output, start_ns + 30_000_000, None)
+            .unwrap();
+        assert_eq!(measurement.scheduling_jitter_ms, Some(20.0));
+        let detector = LatencyDetector::new(config(Detector::Peak));
+        assert!(loopback(&detector, 733, &[BLOCK_FRAMES], 1)[0]
+            .scheduling_jitter_ms
+            .is_none());
+    }
+
+    #[test]
+    fn pings_move_to_the_output_channel_set() {
+        let detector = LatencyDetector::new(DetectorConfig {
+            output_channels: 2,
+            ..config(Detector::Peak)
+        });
+        let mut state = detector.input_state();
+        let mut output = vec![0f32; 2 * BLOCK_FRAMES];
+        let start_ns = 2 * STARTUP_SILENCE_NS;
+        feed_silence(&detector, &mut state, 0, start_ns);
+        detector.set_output_channel(Some(1));
+        detector.fill_output(&mut output, start_ns, None);
+        let (left, right): (Vec<f32>, Vec<f32>) = output
+            .chunks_exact(2)
+            .map(|frame| (frame[0], frame[1]))
+            .unzip();
+        assert!(left.iter().all(|sample| *sample == 0.0));
+        let measurement = detector
+            .process_input(&mut state, &right, start_ns + 10_000_000, None)
+            .unwrap();
+        assert_eq!(measurement.output_channel, Some(1));
+    }
+
+    #[test]
+    fn followers_time_the_leaders_pings_once_silent() {
+        let leader = LatencyDetector::new(config(Detector::Peak));
+        let mut leader_state = leader.input_state();
+        let follower = LatencyDetector::new(config(Detector::Peak));
+        let mut follower_state = follower.input_state();
+        let late_follower = LatencyDetector::new(config(Detector::Peak));
+        let mut late_follower_state = late_follower.input_state();
+        let mut output = vec![0f32; BLOCK_FRAMES];
+        let start_ns = 2 * STARTUP_SILENCE_NS;
+        feed_silence(&leader, &mut leader_state, 0, start_ns);
+        feed_silence(&follower, &mut follower_state, 0, start_ns);
+        leader.fill_output(&mut output, start_ns, None);
+        follower.follow(&leader);
+        late_follower.follow(&leader);
+        // Heard straight away, the block ends a block's length later.
+        let measurement = follower
+            .process_input(&mut follower_state, &output, start_ns + 10_000_000, None)
+            .unwrap();
+        assert!(measurement.delay_ms.abs() <= 1000.0 / 440.0);
+        assert!(late_follower
+            .process_input(
+                &mut late_follower_state,
+                &output,
+                start_ns + 10_000_000,
+                None
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn ping_start_is_kept_until_the_next_ping() {
+        let detector = LatencyDetector::new(config(Detector::Peak));
+        let mut state = detector.input_state();
+        let mut output = vec![0f32; BLOCK_FRAMES];
+        let start_ns = 2 * STARTUP_SILENCE_NS;
+        feed_silence(&detector, &mut state, 0, start_ns);
+        assert_eq!(detector.ping_start(), None);
+        assert!(!detector.ping_pending());
+        detector.fill_output(&mut output, start_ns, None);
+        assert_eq!(detector.ping_start(), Some((start_ns, None)));
+        assert!(detector.ping_pending());
+        assert!(detector.check_timeout(start_ns + 1_000_000_000, 1_000_000_000));
+        assert_eq!(detector.ping_start(), Some((start_ns, None)));
+        assert!(!detector.ping_pending());
+    }
+
+    #[test]
+    fn replayed_ping_is_timed_from_where_it_started() {
+        // As --analyze replays a recording: the probe comes in 733 frames
+        // after the ping the log says started at frame 9600.
+        let detector = LatencyDetector::new(DetectorConfig {
+            on_request: true,
+            precise: true,
+            ..config(Detector::Rms)
+        });
+        let mut state = detector.input_state();
+        let frame_ns = 1e9 / SAMPLE_RATE as f64;
+        let start_frame = 9600;
+        let recording: Vec<f32> = (0..2 * SAMPLE_RATE as usize)
+            .map(|frame| match (frame + 1).checked_sub(start_frame + 733) {
+                Some(clock) if clock > 0 => {
+                    0.5 * Probe::Tone.value(440.0, SAMPLE_RATE, clock as u64)
+                }
+                _ => 0.0,
+            })
+            .collect();
+        let mut measurement = None;
+        for (index, block) in recording.chunks(BLOCK_FRAMES).enumerate() {
+            if index * BLOCK_FRAMES == start_frame {
+                detector.replay_ping((1e9 + start_frame as f64 * frame_ns) as u64, None);
+            }
+            let end_ns = 1e9 + ((index + 1) * BLOCK_FRAMES) as f64 * frame_ns;
+            if let Some(found) = detector.process_input(&mut state, block, end_ns as u64, None) {
+                measurement = Some(found);
+                break;
+            }
+        }
+        let measurement = measurement.expect("no signal detected");
+        assert!((measurement.delay_ms - frames_ms(733)).abs() <= 0.05);
+    }
+
+    #[test]
+    fn on_request_pings_wait_to_be_asked_for() {
+        let detector = LatencyDetector::new(DetectorConfig {
+            on_request: true,
+            ..config(Detector::Peak)
+        });
+        let mut state = detector.input_state();
+        let mut output = vec![0f32; BLOCK_FRAMES];
+        feed_silence(&detector, &mut state, 0, 2 * STARTUP_SILENCE_NS);
+        detector.fill_output(&mut output, 2 * STARTUP_SILENCE_NS, None);
+        assert!(output.iter().all(|sample| *sample == 0.0));
+        detector.request_ping();
+        detector.request_ping();
+        detector.fill_output(&mut output, 2 * STARTUP_SILENCE_NS + 10_000_000, None);
+        assert!(output.iter().any(|sample| *sample != 0.0));
+    }
+
+    #[test]
+    fn a_ping_times_out_once() {
+        let detector = LatencyDetector::new(config(Detector::Peak));
+        let mut state = detector.input_state();
+        let mut output = vec![0f32; BLOCK_FRAMES];
+        let start_ns = 2 * STARTUP_SILENCE_NS;
+        feed_silence(&detector, &mut state, 0, start_ns);
+        detector.fill_output(&mut output, start_ns, None);
+        assert!(!detector.check_timeout(start_ns + 999_000_000, 1_000_000_000));
+        assert!(detector.check_timeout(start_ns + 1_000_000_000, 1_000_000_000));
+        assert!(!detector.check_timeout(start_ns + 2_000_000_000, 1_000_000_000));
+        // The output stays muted until the input has gone silent again.
+        detector.fill_output(&mut output, start_ns + 1_010_000_000, None);
+        assert!(output.iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn summary_of_known_delays() {
+        let summary = Summary::new(&[3.0, 1.0, 4.0, 2.0]).unwrap();
+        assert_eq!(summary.count, 4);
+        assert_eq!((summary.min, summary.max), (1.0, 4.0));
+        assert_eq!((summary.mean, summary.median), (2.5, 2.5));
+        assert!((summary.stddev - 1.25f32.sqrt()).abs() < 1e-6);
+        assert_eq!((summary.p95, summary.p99), (4.0, 4.0));
+        // t for 3 degrees of freedom times the sample standard deviation
+        // over the square root of the count.
+        let ci95 = 3.182 * (5.0f32 / 3.0).sqrt() / 2.0;
+        assert!((summary.ci95.unwrap() - ci95).abs() < 1e-4);
+        let jitter = [2.0, 3.0, 2.0].iter().fold(0.0, |jitter, difference| {
+            jitter + (difference - jitter) * JITTER_GAIN
+        });
+        assert!((summary.jitter - jitter).abs() < 1e-6);
+        assert!(Summary::new(&[]).is_none());
+        assert!(Summary::new(&[1.0]).unwrap().ci95.is_none());
+    }
+
+    #[test]
+    fn tracked_delays_are_smoothed_with_jitter() {
+        let mut tracker = DelayTracker::new(0.5);
+        let first = tracker.push(10.0);
+        assert_eq!((first.smoothed_ms, first.jitter_ms), (10.0, 0.0));
+        let second = tracker.push(14.0);
+        assert_eq!(
+            (second.smoothed_ms, second.jitter_ms),
+            (12.0, 4.0 * JITTER_GAIN)
+        );
+    }
+
+    #[test]
+    fn delay_window_only_summarizes_recent_delays() {
+        let mut window = DelayWindow::new(1_500_000_000);
+        window.push(0, 1.0);
+        assert_eq!(window.push(1_000_000_000, 3.0).median, 2.0);
+        let summary = window.push(2_000_000_000, 5.0);
+        assert_eq!((summary.count, summary.median), (2, 4.0));
+        // The delay dropped is found wherever it sorts.
+        let summary = window.push(3_000_000_000, 0.5);
+        assert_eq!((summary.count, summary.min, summary.max), (2, 0.5, 5.0));
+        window.reset();
+        assert_eq!(window.push(4_000_000_000, 7.0).count, 1);
+    }
+
+    #[test]
+    fn delay_window_drops_every_delay_it_adds() {
+        let mut window = DelayWindow::new(1_500_000_000);
+        window.push(0, 1.0);
+        window.push(1_000_000_000, f32::NAN);
+        window.push(2_000_000_000, 2.0);
+        window.push(3_000_000_000, 3.0);
+        let summary = window.push(4_000_000_000, 4.0);
+        assert_eq!((summary.count, summary.min, summary.max), (2, 3.0, 4.0));
+    }
+
+    #[test]
+    fn averager_combines_each_run_of_measurements() {
+        let mut averager = MeasurementAverager::new(3);
+        assert!(averager.push(measurement(1, 1.0)).is_none());
+        assert!(averager.push(measurement(2, 2.0)).is_none());
+        let (average, stddev_ms) = averager.push(measurement(3, 3.0)).unwrap();
+        assert_eq!(average.timestamp_ns, 3);
+        assert_eq!(average.delay_ms, 2.0);
+        assert_eq!(average.stream_delay_ms, Some(3.0));
+        assert_eq!(average.confidence, 0.1);
+        assert_eq!(stddev_ms, 1.0);
+        averager.push(measurement(4, 4.0));
+        averager.reset();
+        assert!(averager.push(measurement(5, 5.0)).is_none());
+    }
+
+    #[test]
+    fn delay_stats_match_the_summary_of_every_delay() {
+        let delays = [5.0, 7.5, 6.0, 9.0, 5.5, 6.5];
+        let mut stats = DelayStats::new();
+        assert!(stats.summary().is_none());
+        for (index, delay_ms) in delays.iter().enumerate() {
+            stats.push(index as u64 * 1_000_000_000, *delay_ms);
+        }
+        let running = stats.summary().unwrap();
+        let summary = Summary::new(&delays).unwrap();
+        assert_eq!(running.count, summary.count);
+        assert_eq!((running.min, running.max), (summary.min, summary.max));
+        assert!((running.mean - summary.mean).abs() < 1e-5);
+        assert!((running.stddev - summary.stddev).abs() < 1e-5);
+        assert_eq!(running.median, summary.median);
+        assert_eq!((running.p95, running.p99), (summary.p95, summary.p99));
+        assert!((running.jitter - summary.jitter).abs() < 1e-6);
+        assert!((running.ci95.unwrap() - summary.ci95.unwrap()).abs() < 1e-5);
+        // Six seconds of delays are too short to say anything about drift.
+        assert!(running.drift_ms_per_hour.is_none());
+    }
+
+    #[test]
+    fn delay_stats_only_keep_a_sample_of_long_runs() {
+        let mut stats = DelayStats::new();
+        for delay in 0..2 * RESERVOIR_SIZE {
+            stats.push(delay as u64 * 1_000_000_000, delay as f32);
+        }
+        assert_eq!(stats.reservoir.len(), RESERVOIR_SIZE);
+        let summary = stats.summary().unwrap();
+        assert_eq!(summary.count, 2 * RESERVOIR_SIZE);
+        assert_eq!(summary.max, (2 * RESERVOIR_SIZE - 1) as f32);
+    }
+
+    #[test]
+    fn drift_is_the_slope_over_the_last_hour() {
+        let mut stats = DelayStats::new();
+        let minute_ns = 60_000_000_000;
+        for minute in 0..=9 {
+            stats.push(minute * minute_ns, 10.0 + minute as f32 / 10.0);
+        }
+        assert!(stats.drift_ms_per_hour().is_none());
+        for minute in 10..=90 {
+            stats.push(minute * minute_ns, 10.0 + minute as f32 / 10.0);
+        }
+        // 0.1ms a minute, whichever hour of it is fitted.
+        assert!((stats.drift_ms_per_hour().unwrap() - 6.0).abs() < 1e-3);
+    }
+}
@@ -1,172 +1,3330 @@
 extern crate anyhow;
+extern crate audioping;
 extern crate clap;
 extern crate cpal;
 extern crate ctrlc;
+extern crate dirs;
+extern crate hound;
+extern crate libc;
+extern crate ratatui;
+extern crate serde;
+extern crate time;
+extern crate toml;
 
-use clap::arg;
+mod analyze;
+mod baseline;
+mod channel_sweep;
+mod http;
+#[cfg(feature = "fft")]
+mod impulse_capture;
+mod keys;
+mod pipeline;
+mod profile;
+mod publish;
+mod recording;
+mod report;
+mod run_info;
+mod self_test;
+mod signals;
+mod trigger;
+mod tui;
+mod writer;
+
+use audioping::{
+    start_input_stream, DelayStats, DelayTracker, DelayTrend, DelayWindow, Detector,
+    DetectorConfig, Direction, LatencyDetector, Measurement, Probe, Session, SessionConfig,
+    SilenceNoise, StreamSpec, Summary, SweepScale, SILENCE_CREST_FACTOR, TIMECODE_HZ,
+    WINDOW_PERIODS,
+};
+use baseline::Baseline;
+use channel_sweep::ChannelSweep;
+use clap::{arg, PossibleValue};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use std::f32::consts::PI;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::mpsc::channel;
-use std::sync::Arc;
-use std::time::Instant;
+use keys::{Command, Keys};
+use pipeline::{write_measurement_line, Filtered, LineFormat, MeasurementFilter, NEGATIVE_DELAY};
+use profile::Profile;
+use report::Report;
+use run_info::{RunInfo, StreamInfo};
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The command line options. The main ones can also be set with an
+/// `AUDIOPING_` environment variable, which the command line takes
+/// precedence over.
+fn cli() -> clap::Command<'static> {
+    clap::Command::new("audioping")
+        .after_help(EXIT_STATUS_HELP)
+        .arg(arg!(-l --list [FILTER] "List audio devices, only those whose names contain FILTER if given, as a JSON array with --json"))
+        .arg(arg!(--"list-formats" "List the stream configs each audio device supports"))
+        .arg(arg!(-v --volume [VOLUME] "Signal amplitude multiplier 0-100, default: 50").env("AUDIOPING_VOLUME"))
+        .arg(arg!(--dither "Add TPDF dither to the output when the device takes integer samples"))
+        .arg(arg!(--"in-gain" [FACTOR] "Multiply the input by FACTOR before detection, default: 1"))
+        .arg(arg!(--"out-gain" [FACTOR] "Multiply the probe by FACTOR after the volume, default: 1"))
+        .arg(
+            arg!(--"volume-dbfs" [DB] "Signal amplitude in dBFS, e.g. -6, instead of --volume")
+                .allow_hyphen_values(true)
+                .conflicts_with("volume"),
+        )
+        .arg(arg!(-f --frequency [FREQUENCY] "Probe tone frequency in Hz, default: 440").env("AUDIOPING_FREQUENCY"))
+        .arg(
+            arg!(--sweep [SWEEP] "Emit a chirp from START to END Hz over DURATION ms instead of a tone")
+                .number_of_values(3)
+                .max_values(3)
+                .value_names(&["START", "END", "DURATION"]),
+        )
+        .arg(
+            arg!(--waveform [WAVEFORM] "Shape of the probe signal, default: sine")
+                .env("AUDIOPING_WAVEFORM")
+                .possible_values(["sine", "square", "click", "noise"])
+                .conflicts_with_all(&["sweep", "burst-ms"]),
+        )
+        .arg(arg!(--seed [N] "Seed picking the sequence played by --waveform noise, default: 0"))
+        .arg(
+            arg!(--"probe-wav" [PATH] "Play the WAV file at PATH, mixed down to mono, as the probe, in full for every ping")
+                .conflicts_with_all(&["sweep", "burst-ms", "waveform", "timecode"]),
+        )
+        .arg(
+            arg!(--"probe-wav-loop" "With --probe-wav, play the file over and over until the ping is detected")
+                .requires("probe-wav"),
+        )
+        .arg(
+            arg!(--"burst-ms" [MS] "Emit MS of tone shaped by a raised-cosine envelope, default: 50")
+                .conflicts_with("sweep"),
+        )
+        .arg(
+            arg!(--"ramp-ms" [MS] "Fade a held tone in and out over MS so that its edges don't click, default: 0")
+                .conflicts_with_all(&["sweep", "burst-ms", "timecode", "precise"]),
+        )
+        .arg(
+            arg!(--"silence-level" [DB] "Play noise at DB dBFS RMS, e.g. -60, under the output so that it's never silent between pings, for an amplifier that gates or thumps on silence")
+                .allow_hyphen_values(true)
+                .conflicts_with_all(&["listen-only", "impulse"]),
+        )
+        .arg(
+            arg!(--"silence-noise" [NOISE] "Noise played for --silence-level, default: pink")
+                .possible_values(["pink", "white"])
+                .requires("silence-level"),
+        )
+        .arg(
+            arg!(--"probe-delay" [MS] "Hold each ping's output muted for MS before the probe starts, which should add exactly MS to every delay, default: 0")
+                .conflicts_with("listen-only"),
+        )
+        .arg(
+            arg!(--averages [N] "Play each ping as a train of N bursts and measure their averaged return, default: 1")
+                .requires("burst-ms"),
+        )
+        .arg(
+            arg!(--timecode "Start each ping with an FSK timecode of when it was played, so another machine can measure its one-way delay")
+                .conflicts_with_all(&["sweep", "burst-ms", "waveform", "stereo"]),
+        )
+        .arg(
+            arg!(--"listen-only" "Open no output and time probes played by something else: a timecode's one-way delay, or the time since the last probe")
+                .alias("listen")
+                .conflicts_with_all(&["averages", "stereo", "keys"]),
+        )
+        .arg(
+            arg!(--"sync-channel" [N] "With --listen-only, time each probe from a sync marker on input channel N")
+                .requires("listen-only")
+                .conflicts_with("timecode"),
+        )
+        .arg(
+            arg!(--"sweep-scale" [SCALE] "How the sweep's frequency changes, default: log")
+                .possible_values(["linear", "log"]),
+        )
+        .arg(arg!(-s --sensitivity [SENSITIVITY] "Signal amplitude required to trigger, default: 1.0").env("AUDIOPING_SENSITIVITY"))
+        .arg(arg!(--hysteresis [FACTOR] "Only count the tone as gone below FACTOR times the sensitivity, default: 1"))
+        .arg(arg!(--"trigger-periods" [PERIODS] "Length of the detection window in periods of the probe frequency, which the probe must last for before it triggers, default: 4"))
+        .arg(
+            arg!(--"auto-sensitivity" [MULTIPLE] "Measure the input's noise floor first and trigger at MULTIPLE times it, default: 4")
+                .conflicts_with("sensitivity"),
+        )
+        .arg(
+            arg!(-u --units [UNITS] "Units to print delays in, default: both")
+                .possible_values(["ms", "samples", "both"]),
+        )
+        .arg(arg!(-q --quiet "Only print the summary at the end of the run"))
+        .arg(
+            arg!(-V --verbose "Print the negotiated stream configs and callback buffer sizes")
+                .conflicts_with("quiet"),
+        )
+        .arg(arg!(--histogram "Show a live histogram of delays instead of each measurement"))
+        .arg(arg!(--tui "Show a full-screen dashboard of recent delays instead of each measurement").conflicts_with_all(&["histogram", "json", "csv", "quiet"]))
+        .arg(arg!(--keys "Control the run from the keyboard: p pauses, r resets the statistics and space pings once while paused").conflicts_with("tui"))
+        .arg(arg!(--csv [PATH] "Write measurements as CSV to PATH, appending if it exists, or stdout if omitted or -"))
+        .arg(arg!(--"metrics-file" [PATH] "Keep a Prometheus textfile at PATH updated with the latest measurement").env("AUDIOPING_METRICS_FILE"))
+        .arg(arg!(--"save-summary" [PATH] "Save the run's summary to PATH for use with --baseline"))
+        .arg(arg!(--baseline [PATH] "Compare the run's summary against one saved with --save-summary"))
+        .arg(arg!(--report [PATH] "Write a Markdown report of the devices, settings and summary to PATH when the run ends"))
+        .arg(arg!(--"http-addr" [ADDR] "Serve the latest measurement on /metrics and /status.json over HTTP on HOST:PORT").env("AUDIOPING_HTTP_ADDR"))
+        .arg(arg!(--publish [ADDR] "Send measurements as JSON lines to clients of tcp://HOST:PORT or a Unix socket path"))
+        .arg(arg!(--record [PATH] "Record the captured input to a WAV file at PATH, and when each ping started to PATH.pings"))
+        .arg(arg!(--json "Print measurements and the summary as JSON lines").conflicts_with("csv"))
+        .arg(arg!(--"sample-rate" [HZ] "Stream sample rate, default: the output device's default").env("AUDIOPING_SAMPLE_RATE"))
+        .arg(arg!(--"buffer-size" [FRAMES] "Stream buffer size in frames, default: the host's default").env("AUDIOPING_BUFFER_SIZE"))
+        .arg(
+            arg!(--"low-latency" "Use the config with the smallest buffers each device supports, at that minimum")
+                .conflicts_with("buffer-size"),
+        )
+        .arg(arg!(-t --timeout [MS] "Milliseconds to wait for a ping to return, default: 1000").env("AUDIOPING_TIMEOUT"))
+        .arg(arg!(--alpha [ALPHA] "Weight of each new delay in the smoothed delay, 0-1, default: 0.1"))
+        .arg(arg!(--interval [MS] "Minimum milliseconds between pings, default: 0").env("AUDIOPING_INTERVAL"))
+        .arg(arg!(--timestamps "Prefix each measurement with the local wall clock time, in RFC 3339 format"))
+        .arg(arg!(--average [N] "Report the mean of every N consecutive delays, default: 1"))
+        .arg(arg!(--window [SECONDS] "Also show the p50, p95 and p99 delay over the last SECONDS"))
+        .arg(arg!(--"max-delay" [MS] "Discard any delay above MS as a false trigger, default: off"))
+        .arg(arg!(--"drift-alarm" [MS_PER_HOUR] "Warn while the delay drifts faster than MS_PER_HOUR, fitted over the last hour"))
+        .arg(arg!(--"fail-over" [MS] "Stop and exit with an error as soon as a delay goes over MS, default: off"))
+        .arg(
+            arg!(--"fail-on" [VALUE] "What --fail-over checks: each delay, or the smoothed delay, default: delay")
+                .possible_values(["delay", "smoothed"])
+                .requires("fail-over"),
+        )
+        .arg(arg!(--"min-confidence" [CONFIDENCE] "Discard any delay measured with a confidence below CONFIDENCE, 0-1, default: 0"))
+        .arg(
+            arg!(--period [SECONDS] "Stay muted and ping once every SECONDS, on a fixed schedule")
+                .conflicts_with("listen-only"),
+        )
+        .arg(
+            arg!(--"trigger-fifo" [PATH] "Stay muted and ping once for every line written to the named pipe at PATH")
+                .conflicts_with_all(&["period", "listen-only"]),
+        )
+        .arg(arg!(-w --warmup [N] "Discard the first N measurements, default: 2"))
+        .arg(
+            arg!(--check "Confirm that a ping comes back before measuring, and abort if it times out")
+                .conflicts_with("listen-only"),
+        )
+        .arg(arg!(-c --count [COUNT] "Stop after COUNT measurements, default: run until Ctrl-C").env("AUDIOPING_COUNT"))
+        .arg(arg!(--duration [SECONDS] "Stop after SECONDS, default: run until Ctrl-C").env("AUDIOPING_DURATION"))
+        .arg(arg!(--"offset-ms" [MS] "Subtract a known fixed latency from every delay, default: 0").env("AUDIOPING_OFFSET_MS"))
+        .arg(arg!(--distance [METERS] "Subtract the time sound takes to travel METERS from every delay, default: 0"))
+        .arg(arg!(--"loopback-device" [NAME] "Use the device NAME as both the input and the output").conflicts_with_all(&["input", "output"]))
+        .arg(
+            arg!(--virtual "Preset for a virtual loopback device: the smallest buffers it offers, a sensitivity of 0.1, and a check that the input and output are the same device")
+                .conflicts_with("acoustic"),
+        )
+        .arg(arg!(--acoustic "Preset for a microphone picking up a speaker: goertzel detection where the probe allows it, and --auto-sensitivity"))
+        .arg(arg!(--calibrate "Measure the baseline delay, ignoring --offset-ms, and print the offset to use"))
+        .arg(arg!(-H --host [HOST] "The audio host/backend to use, e.g. ALSA, JACK, WASAPI, ASIO").env("AUDIOPING_HOST"))
+        .arg(
+            arg!(-d --detector [DETECTOR] "How to detect the returning tone, default: rms")
+                .env("AUDIOPING_DETECTOR")
+                .possible_values([
+                    PossibleValue::new("rms"),
+                    PossibleValue::new("peak"),
+                    PossibleValue::new("amplitude").hide(true),
+                    PossibleValue::new("goertzel"),
+                    PossibleValue::new("fft").hide(!cfg!(feature = "fft")),
+                ]),
+        )
+        .arg(arg!(--"fft-size" [FRAMES] "Frames in each FFT with --detector fft, default: 1024"))
+        .arg(arg!(--"fft-overlap" [FRACTION] "How much consecutive FFTs overlap with --detector fft, from 0 up to 1, default: 0.75"))
+        .arg(arg!(--"in-channel" [N] "Input channel to listen on, default: 0").env("AUDIOPING_IN_CHANNEL"))
+        .arg(
+            arg!(--stereo "Measure the left and right input channels and print their skew")
+                .conflicts_with_all(&["in-channel", "sweep", "burst-ms"]),
+        )
+        .arg(arg!(--"reference-channel" [N] "Input channel hearing only the ambient noise, subtracted before detection"))
+        .arg(arg!(--"out-channel" [N] "Output channel to play the tone on, default: all").env("AUDIOPING_OUT_CHANNEL"))
+        .arg(
+            arg!(--"sweep-channels" "Move the probe to the next output channel every ping, and report which channels it returns from")
+                .conflicts_with_all(&["out-channel", "listen-only", "average"]),
+        )
+        .arg(arg!(--precise "Refine each delay to sub-sample precision by cross-correlation"))
+        .arg(
+            arg!(--impulse "Play one exponential sweep, deconvolve the capture into the impulse response and report its first arrival, then exit")
+                .conflicts_with_all(&[
+                    "listen-only",
+                    "timecode",
+                    "burst-ms",
+                    "waveform",
+                    "stereo",
+                    "sweep-channels",
+                    "keys",
+                    "tui",
+                ]),
+        )
+        .arg(
+            arg!(--"impulse-tail" [MS] "With --impulse, keep capturing for MS after the sweep, the longest response measured, default: 1000")
+                .requires("impulse"),
+        )
+        .arg(
+            arg!(--"impulse-wav" [PATH] "With --impulse, write the impulse response to PATH as a WAV file")
+                .requires("impulse"),
+        )
+        .arg(
+            arg!(--"dc-block" [SWITCH] "Remove any DC offset from the input before detection, default: on")
+                .possible_values(["on", "off"]),
+        )
+        .arg(arg!(--"invert-detection" "Flip the input's polarity before detection, for a loopback that reverses it"))
+        .arg(arg!(--strict "Abort the run if the input clips"))
+        .arg(arg!(--reconnect [N] "Rebuild a failed stream up to N times, default: 3"))
+        .arg(arg!(--"dry-run" "Check the devices, stream configs and output files, then exit without playing anything"))
+        .arg(arg!(--"self-test" "Check the detector against a simulated loopback, without audio devices"))
+        .arg(
+            arg!(--analyze [PATH] "Run the detector over a WAV file saved with --record, without audio devices")
+                .conflicts_with_all(&["self-test", "record", "impulse", "auto-sensitivity"]),
+        )
+        .arg(arg!(--profile [NAME] "Load options not given on the command line from profile NAME").env("AUDIOPING_PROFILE"))
+        .arg(arg!(--"save-profile" [NAME] "Save the device and signal options to profile NAME"))
+        .arg(
+            arg!(-i --input <IN> "The input audio device to use, by name or --list index, repeat to compare several hearing the same output")
+                .env("AUDIOPING_INPUT")
+                .required(false)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            // `[OUT]` would allow only one value across every occurrence.
+            arg!(-o --output <OUT> "The output audio device to use, by name or --list index, repeat to take turns pinging several")
+                .env("AUDIOPING_OUTPUT")
+                .required(false)
+                .multiple_occurrences(true),
+        )
+}
 
 fn main() -> anyhow::Result<()> {
-    let app = clap::Command::new("audioping")
-        .arg(arg!(-l --list "List audio devices"))
-        .arg(arg!(-v --volume [VOLUME] "Signal amplitude multiplier 0-100, default: 50"))
-        .arg(arg!(-s --sensitivity [SENSITIVITY] "Signal amplitude required to trigger, default: 1.0"))
-        .arg(arg!(-i --input [IN] "The input audio device to use"))
-        .arg(arg!(-o --output [OUT] "The output audio device to use"));
-
-    let matches = app.get_matches();
-    let input_device = matches.value_of("input");
-    let output_device = matches.value_of("output");
-
-    let volume_str = matches.value_of("volume").unwrap_or("50");
-    let volume = volume_str.parse::<f32>()?.max(0f32).min(100f32) / 100f32;
-    let sensitivity_str = matches.value_of("sensitivity").unwrap_or("1");
-    let sensitivity = sensitivity_str.parse::<f32>()?.max(0f32).min(2f32);
+    let args: Vec<OsString> = std::env::args_os().collect();
+    drop_overridden_env(cli(), &args);
+    let app = cli();
+    let mut matches = app.clone().get_matches_from(&args);
+    if let Some(name) = matches.value_of("profile").map(str::to_owned) {
+        let profile = Profile::load(&name)?;
+        let profile_args = profile.to_args(&matches).into_iter().map(Into::into);
+        matches = app.get_matches_from(args.into_iter().chain(profile_args));
+    }
+    // Looking up the local offset is only sound before any other thread has
+    // started, so it's done once up front.
+    let local_offset = time::UtcOffset::current_local_offset().unwrap_or(time::UtcOffset::UTC);
+    let config = parse_config(&matches)?;
+    let status = Status {
+        // The dashboard owns the terminal while it's shown.
+        quiet: config.quiet || config.tui,
+        // Keep stdout parseable when measurements are written to it as data.
+        to_stderr: config.json || matches.is_present("csv"),
+    };
+    if config.propagation_ms > 0f32 {
+        status.print(&format!(
+            "Subtracting {:.2}ms for sound to travel {}m",
+            config.propagation_ms, config.distance
+        ));
+    }
+    if config.probe_delay_ms > 0f32 {
+        status.print(&format!(
+            "Holding each probe back by {}ms, which every delay should include",
+            config.probe_delay_ms
+        ));
+    }
+    if let Some(name) = matches.value_of("save-profile") {
+        let path = Profile::from_matches(&matches)?.save(name)?;
+        status.print(&format!("Saved profile \"{}\" to {}", name, path.display()));
+    }
 
-    let (tx, rx) = channel();
-    ctrlc::set_handler(move || tx.send(()).expect("Could not send signal on channel."))
-        .expect("Error setting Ctrl-C handler");
+    if matches.is_present("self-test") {
+        run_self_test(&config);
+        return Ok(());
+    }
+    if let Some(path) = matches.value_of("analyze") {
+        return run_analyze(&config, Path::new(path), &status);
+    }
 
-    let host = cpal::default_host();
+    let (tx, rx) = channel();
+    let count_tx = tx.clone();
+    let duration_tx = tx.clone();
+    let quit_tx = tx.clone();
+    let (tui_tx, tui_rx) = if config.tui {
+        let (tui_tx, tui_rx) = channel::<(f32, DelayTrend)>();
+        (Some(tui_tx), Some(tui_rx))
+    } else {
+        (None, None)
+    };
+    // Until measuring starts there's nothing to clean up, so Ctrl-C exits
+    // straight away rather than waiting for slow device setup to finish.
+    // SIGTERM, from a service manager stopping the run, is handled the same.
+    let measuring = Arc::new(AtomicBool::new(false));
+    let handler_measuring = Arc::clone(&measuring);
+    let interrupt = move || {
+        if !handler_measuring.load(Ordering::SeqCst) || tx.send(StopReason::Interrupted).is_err() {
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+    };
+    ctrlc::set_handler(interrupt.clone()).expect("Error setting Ctrl-C handler");
+    signals::handle_console_close(interrupt)?;
+    signals::handle_hangup()?;
 
+    let host = select_host(&matches)?;
     if matches.is_present("list") {
-        println!("Input devices:");
-        for device in host.input_devices()? {
-            println!("  {}", device.name()?);
+        return print_device_list(&host, &matches);
+    }
+    if matches.is_present("list-formats") {
+        return print_device_formats(&host);
+    }
+    let opened = open_session(
+        &matches,
+        &config,
+        &host,
+        status,
+        local_offset,
+        count_tx.clone(),
+    )?;
+    let Some(OpenSession {
+        mut session,
+        measurements,
+        input_names,
+        output_names,
+        sample_rate,
+        run_id,
+        csv,
+        baseline,
+        triggers,
+        publisher,
+        http_pages,
+        report,
+        channel_sweep,
+        clipped,
+        recording_thread,
+        ping_log_thread,
+    }) = opened
+    else {
+        return Ok(());
+    };
+
+    let (metrics, metrics_thread) = matches
+        .value_of("metrics-file")
+        .map(|path| spawn_metrics_writer(PathBuf::from(path)))
+        .unzip();
+    let mut context = MeasurementContext {
+        config: &config,
+        status,
+        local_offset,
+        run_id,
+        line_format: LineFormat {
+            decimals: if config.precise { 4 } else { 2 },
+            units: config.units.clone(),
+            sample_rate,
+            clock: config.probe == Probe::Timecode,
+            channel: config.sweep_channels,
+            average_count: config.average_count,
+            window: config.window_str.clone(),
+        },
+        // Measurements are only tagged with their output, or their input,
+        // when there's a choice.
+        devices: match (output_names.len() > 1, input_names.len() > 1) {
+            (true, _) => Some(("output", output_names.clone())),
+            (_, true) => Some(("input", input_names.clone())),
+            _ => None,
+        },
+        csv,
+        publisher,
+        http_pages,
+        metrics,
+        tui: tui_tx,
+        stop: count_tx.clone(),
+        filter: MeasurementFilter::new(
+            config.offset_ms,
+            config.max_delay_ms,
+            config.min_confidence,
+            config.average_count,
+        ),
+        trackers: session
+            .detectors()
+            .iter()
+            .map(|_| DelayTracker::new(config.alpha))
+            .collect(),
+        delay_window: config
+            .window
+            .map(|window| DelayWindow::new(window.as_nanos() as u64)),
+        json_line: String::new(),
+        warmup_remaining: config.warmup,
+        checking_loopback: matches.is_present("check"),
+        late_callback_noted: false,
+        drift_alarmed: false,
+        inverted_noted: false,
+        delays: DelayStats::new(),
+        input_delays: (input_names.len() > 1)
+            .then(|| input_names.iter().map(|_| DelayStats::new()).collect()),
+        channel_sweep,
+        histogram_buckets: Vec::new(),
+        measured: 0,
+        discarded: 0,
+        negative: 0,
+        failed_over: None,
+    };
+
+    if let Some(duration) = config.duration {
+        std::thread::spawn(move || {
+            std::thread::sleep(duration);
+            let _ = duration_tx.send(StopReason::DurationElapsed);
+        });
+    }
+
+    let keys = if config.keys {
+        Some(Keys::spawn(quit_tx.clone())?)
+    } else {
+        None
+    };
+    let tui_thread = match tui_rx {
+        Some(tui_rx) => Some(tui::spawn(tui_rx, quit_tx)?),
+        None => None,
+    };
+    measuring.store(true, Ordering::SeqCst);
+    if context.checking_loopback {
+        status.print("Checking the loopback...");
+    }
+    status.print("Measuring latency... Press Ctrl-C to stop");
+    if let Some(path) = matches.value_of("trigger-fifo") {
+        status.print(&format!("Pinging once for every line written to {}", path));
+    }
+    if keys.is_some() {
+        status.print(
+            "Press p to pause, r to reset the statistics, or space to ping once while paused",
+        );
+    }
+    let events = RunEvents {
+        measurements,
+        stops: rx,
+        commands: keys.as_ref().map(|(_, commands)| commands),
+        triggers,
+    };
+    let outcome = run_loop(
+        &mut session,
+        &mut context,
+        events,
+        &input_names,
+        &output_names,
+    );
+    drop(session);
+    // Puts the terminal back to normal before the summary is printed.
+    drop(keys);
+    // Closing the dashboard's channel makes it restore the terminal before
+    // the summary is printed.
+    drop(context.tui.take());
+    if let Some(tui_thread) = tui_thread {
+        let _ = tui_thread.join();
+    }
+    // Likewise, so that the last measurement's metrics are written.
+    drop(context.metrics.take());
+    if let Some(metrics_thread) = metrics_thread {
+        let _ = metrics_thread.join();
+    }
+    // The session held the recorder and the ping log, so dropping it has let
+    // their threads finish.
+    if let Some(thread) = recording_thread {
+        thread.join().expect("recording thread panicked")?;
+    }
+    if let Some(thread) = ping_log_thread {
+        thread.join().expect("ping log thread panicked")?;
+    }
+    if outcome.loopback_failed {
+        anyhow::bail!(
+            "the loopback check ping didn't come back within {}ms; check the cabling, the input gain and volume, and that the right devices are selected",
+            config.timeout.as_millis()
+        );
+    }
+    print_results(
+        &matches,
+        &context,
+        &outcome,
+        &input_names,
+        baseline.as_ref(),
+        report,
+    )?;
+
+    if let Some(direction) = outcome.failed_stream {
+        anyhow::bail!("the {} stream failed", direction.name());
+    }
+    if config.strict && clipped.load(Ordering::SeqCst) {
+        anyhow::bail!("aborted because the input clipped");
+    }
+    if let Some((delay_ms, fail_over_ms)) = context.failed_over {
+        anyhow::bail!(
+            "aborted because {} delay of {:.2}ms went over the --fail-over limit of {}ms",
+            if config.fail_on_smoothed {
+                "a smoothed"
+            } else {
+                "a"
+            },
+            delay_ms,
+            fail_over_ms
+        );
+    }
+
+    let succeeded = match config.count {
+        Some(count) => context.measured >= count && outcome.timeouts == 0,
+        None => context.measured > 0,
+    };
+    if !succeeded {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Prints the run's summaries and its comparison with the `--baseline`, and
+/// saves the `--save-summary` and the `--report`.
+fn print_results(
+    matches: &clap::ArgMatches,
+    context: &MeasurementContext,
+    outcome: &RunOutcome,
+    input_names: &[String],
+    baseline: Option<&Baseline>,
+    report: Option<(String, Report)>,
+) -> anyhow::Result<()> {
+    let config = context.config;
+    let status = context.status;
+    let summary = context.delays.summary();
+    print_summary(summary.as_ref(), config.json);
+    if let Some(sweep) = &context.channel_sweep {
+        sweep.print(config.json);
+    }
+    if let Some(input_delays) = &context.input_delays {
+        print_input_summaries(input_names, input_delays, config.json);
+    }
+    if let Some(summary) = &summary {
+        if let Some(baseline) = baseline {
+            baseline.print_comparison(summary, config.json);
         }
-        println!("Output devices:");
-        for device in host.output_devices()? {
-            println!("  {}", device.name()?);
+        if let Some(path) = matches.value_of("save-summary") {
+            Baseline::from_summary(summary).save(Path::new(path))?;
+            status.print(&format!("Saved the summary to {}", path));
         }
-        return Ok(());
     }
+    if let Some((path, mut report)) = report {
+        report.time = wall_clock_time(context.local_offset);
+        report.timeouts = outcome.timeouts;
+        report.discarded = context.discarded;
+        report.negative = context.negative;
+        report.save(summary.as_ref(), Path::new(&path))?;
+        status.print(&format!("Wrote the report to {}", path));
+    }
+    if config.calibrate {
+        if let Some(summary) = &summary {
+            println!(
+                "Baseline delay: {:.2}ms, run with --offset-ms {:.2} to remove it",
+                summary.median, summary.median
+            );
+        }
+    }
+    status.print("Done!");
+    Ok(())
+}
+
+/// Runs the `--self-test`, exiting with 1 if it fails.
+fn run_self_test(config: &Config) {
+    let passed = self_test::run(DetectorConfig {
+        sample_rate: config.requested_sample_rate.unwrap_or(48000) as f32,
+        frequency: config.frequency,
+        probe: config.probe.clone(),
+        volume: config.volume,
+        ramp_ms: config.ramp_ms,
+        probe_delay_ms: config.probe_delay_ms,
+        silence_level: config.silence_level,
+        silence_noise: config.silence_noise,
+        hysteresis: config.hysteresis,
+        window_periods: config.window_periods,
+        averages: config.averages,
+        detector: config.detector_kind,
+        precise: config.precise,
+        dc_block: config.dc_block,
+        invert_detection: config.invert_detection,
+        ..DetectorConfig::default()
+    });
+    println!("{}", if passed { "PASS" } else { "FAIL" });
+    if !passed {
+        std::process::exit(1);
+    }
+}
+
+/// Replays the recording at `path` for `--analyze` and prints its summary.
+fn run_analyze(config: &Config, path: &Path, status: &Status) -> anyhow::Result<()> {
+    if config.probe == Probe::Timecode {
+        anyhow::bail!("a recording doesn't keep the clock its timecodes were sent by, so they can't be analyzed");
+    }
+    let summary = analyze::run(
+        path,
+        analyze::AnalyzeSettings {
+            detector: DetectorConfig {
+                input_channel: config.input_channel,
+                compare_channel: if config.stereo { Some(1) } else { None },
+                reference_channel: config.reference_channel,
+                sync_channel: config.sync_channel,
+                output_channel: config.output_channel,
+                sensitivity: config.sensitivity,
+                hysteresis: config.hysteresis,
+                window_periods: config.window_periods,
+                averages: config.averages,
+                frequency: config.frequency,
+                probe: config.probe.clone(),
+                volume: config.volume,
+                ramp_ms: config.ramp_ms,
+                probe_delay_ms: config.probe_delay_ms,
+                input_gain: config.input_gain,
+                output_gain: config.output_gain,
+                detector: config.detector_kind,
+                precise: config.precise,
+                dc_block: config.dc_block,
+                invert_detection: config.invert_detection,
+                listen_only: config.listen_only && config.sync_channel.is_none(),
+                ..DetectorConfig::default()
+            },
+            unlogged: config.listen_only || config.sync_channel.is_some(),
+            timeout: config.timeout,
+            offset_ms: config.offset_ms,
+            max_delay_ms: config.max_delay_ms,
+            min_confidence: config.min_confidence,
+            average_count: config.average_count,
+            alpha: config.alpha,
+        },
+        config.json,
+        status,
+    )?;
+    print_summary(summary.as_ref(), config.json);
+    Ok(())
+}
+
+/// The `--host`, or the default host if that one isn't available.
+fn select_host(matches: &clap::ArgMatches) -> anyhow::Result<cpal::Host> {
+    let host_name = match matches.value_of("host") {
+        None => return Ok(cpal::default_host()),
+        Some(host_name) => host_name,
+    };
+    match cpal::available_hosts()
+        .into_iter()
+        .find(|id| id.name().eq_ignore_ascii_case(host_name))
+    {
+        Some(host_id) => Ok(cpal::host_from_id(host_id)?),
+        None => {
+            eprintln!(
+                "Warning: host \"{}\" is not available, using the default host",
+                host_name
+            );
+            Ok(cpal::default_host())
+        }
+    }
+}
+
+/// Prints the hosts and the devices of `host` for `--list`, leaving out the
+/// devices whose names don't contain its filter.
+fn print_device_list(host: &cpal::Host, matches: &clap::ArgMatches) -> anyhow::Result<()> {
+    let list_filter = matches.value_of("list").map(str::to_lowercase);
+    let listed = |name: &str| {
+        list_filter
+            .as_ref()
+            .is_none_or(|filter| name.to_lowercase().contains(filter))
+    };
+    if matches.is_present("json") {
+        return print_devices_json(host, listed);
+    }
+    println!("Hosts:");
+    for host_id in cpal::available_hosts() {
+        println!("  {}", host_id.name());
+    }
+    let default_input = host
+        .default_input_device()
+        .and_then(|device| device.name().ok());
+    let default_output = host
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+    // Numbered across every device, so the index selects the same one
+    // whatever the filter.
+    let directions = [
+        (
+            "Input devices:",
+            host.input_devices()?.collect::<Vec<_>>(),
+            default_input,
+        ),
+        (
+            "Output devices:",
+            host.output_devices()?.collect(),
+            default_output,
+        ),
+    ];
+    for (heading, devices, default_name) in directions {
+        println!("{}", heading);
+        for (index, device) in devices.iter().enumerate() {
+            let name = device.name()?;
+            if !listed(&name) {
+                continue;
+            }
+            let default = if default_name.as_deref() == Some(name.as_str()) {
+                " (default)"
+            } else {
+                ""
+            };
+            println!("  {}: {}{}", index, name, default);
+        }
+    }
+    Ok(())
+}
+
+/// Prints the stream configurations each device of `host` supports, for
+/// `--list-formats`.
+fn print_device_formats(host: &cpal::Host) -> anyhow::Result<()> {
+    println!("Input devices:");
+    for device in host.input_devices()? {
+        println!("  {}", device.name()?);
+        match device.supported_input_configs() {
+            Ok(configs) => configs.for_each(|config| println!("    {}", describe_config(&config))),
+            Err(err) => println!("    unavailable: {}", err),
+        }
+    }
+    println!("Output devices:");
+    for device in host.output_devices()? {
+        println!("  {}", device.name()?);
+        match device.supported_output_configs() {
+            Ok(configs) => configs.for_each(|config| println!("    {}", describe_config(&config))),
+            Err(err) => println!("    unavailable: {}", err),
+        }
+    }
+    Ok(())
+}
+
+/// The devices a run opens its streams on.
+struct Devices {
+    input: cpal::Device,
+    /// Inputs after the first time the same pings, each with a detector of
+    /// its own.
+    extra_inputs: Vec<cpal::Device>,
+    outputs: Vec<cpal::Device>,
+    input_names: Vec<String>,
+    output_names: Vec<String>,
+}
 
-    let input = if input_device.is_none() {
-        host.default_input_device()
+/// Finds the devices the options name, or the host's defaults, and checks
+/// that the options can measure them together.
+fn find_devices(host: &cpal::Host, config: &Config, status: Status) -> anyhow::Result<Devices> {
+    let input = match config.input_devices.first() {
+        None => host
+            .default_input_device()
+            .ok_or_else(|| anyhow::anyhow!("no default input device available"))?,
+        Some(name) => find_device(host.input_devices()?, name, "input")?,
+    };
+    let mut extra_inputs = Vec::new();
+    for name in config.input_devices.iter().skip(1) {
+        extra_inputs.push(find_device(host.input_devices()?, name, "input")?);
+    }
+
+    let mut outputs = Vec::new();
+    if config.listen_only {
+        // Any output left in a profile is simply not opened.
+        status.print("Only listening, no output stream is opened");
     } else {
-        host.input_devices()?.find(|x| {
-            x.name()
-                .map(|y| y == input_device.unwrap())
-                .unwrap_or(false)
+        for name in &config.output_devices {
+            outputs.push(find_device(host.output_devices()?, name, "output")?);
+        }
+        if outputs.is_empty() {
+            outputs.push(
+                host.default_output_device()
+                    .ok_or_else(|| anyhow::anyhow!("no default output device available"))?,
+            );
+        }
+    }
+    if config.sweep_channels && outputs.len() > 1 {
+        anyhow::bail!("--sweep-channels goes through the channels of one output device at a time");
+    }
+    if config.impulse && (outputs.len() > 1 || !extra_inputs.is_empty()) {
+        anyhow::bail!("--impulse measures one output and one input at a time");
+    }
+    if !extra_inputs.is_empty() {
+        if outputs.len() != 1 {
+            anyhow::bail!("several inputs can only be compared on the pings of one output");
+        }
+        for (option, given) in [
+            ("--sweep-channels", config.sweep_channels),
+            ("--stereo", config.stereo),
+            ("--average", config.average_count > 1),
+        ] {
+            if given {
+                anyhow::bail!("{} can't be used with more than one --input", option);
+            }
+        }
+    }
+    let output_names = outputs
+        .iter()
+        .map(|output| output.name())
+        .collect::<Result<Vec<_>, _>>()?;
+    let input_names = [&input]
+        .into_iter()
+        .chain(&extra_inputs)
+        .map(|input| input.name())
+        .collect::<Result<Vec<_>, _>>()?;
+    if config.virtual_loopback {
+        let input_name = &input_names[0];
+        if let Some(name) = output_names.iter().find(|name| *name != input_name) {
+            anyhow::bail!(
+                "--virtual measures one device looped back on itself, but the input \"{}\" and output \"{}\" differ; pick one with --loopback-device NAME",
+                input_name,
+                name
+            );
+        }
+    }
+    Ok(Devices {
+        input,
+        extra_inputs,
+        outputs,
+        input_names,
+        output_names,
+    })
+}
+
+/// Where the `--csv` rows are written.
+type CsvWriter = BufWriter<Box<dyn Write>>;
+
+/// Opens the `--csv` file, or stdout, for the run's rows to be written to,
+/// and says whether they're appended under a header already there.
+fn open_csv(
+    matches: &clap::ArgMatches,
+    dry_run: bool,
+    header: &str,
+) -> anyhow::Result<(Option<CsvWriter>, bool)> {
+    if !matches.is_present("csv") {
+        return Ok((None, false));
+    }
+    let (writer, append): (Box<dyn Write>, bool) = match matches.value_of("csv") {
+        None | Some("-") => (Box::new(std::io::stdout()), false),
+        Some(path) => {
+            let existing = match File::open(path) {
+                Ok(file) => BufReader::new(file).lines().next().transpose()?,
+                Err(_) => None,
+            };
+            let append = match existing {
+                Some(existing) if existing == header => true,
+                Some(_) => anyhow::bail!(
+                    "can't append to {}, its columns don't match the ones this run writes",
+                    path
+                ),
+                None => false,
+            };
+            if dry_run {
+                check_writable(Path::new(path))?;
+                (Box::new(std::io::sink()), append)
+            } else {
+                let file = File::options().create(true).append(true).open(path)?;
+                (Box::new(file), append)
+            }
+        }
+    };
+    Ok((Some(BufWriter::new(writer)), append))
+}
+
+/// Picks a stream config for the first input and for each output, with the
+/// buffer size the options ask for. Every one runs at the first output's
+/// sample rate, so that a delay counted in frames means the same on each
+/// side.
+fn configure_streams(
+    input: cpal::Device,
+    outputs: Vec<cpal::Device>,
+    output_names: &[String],
+    config: &Config,
+    status: Status,
+) -> anyhow::Result<(StreamSpec, Vec<StreamSpec>)> {
+    let mut output_supported: Vec<cpal::SupportedStreamConfig> = Vec::new();
+    for output in &outputs {
+        let sample_rate = match output_supported.first() {
+            Some(first) => Some(first.sample_rate().0),
+            None => config.requested_sample_rate,
+        };
+        output_supported.push(select_config(
+            output,
+            Direction::Output,
+            sample_rate,
+            config.low_latency,
+        )?);
+    }
+    let input_supported = select_config(
+        &input,
+        Direction::Input,
+        output_supported
+            .first()
+            .map(|supported| supported.sample_rate().0)
+            .or(config.requested_sample_rate),
+        config.low_latency,
+    )?;
+    if let Some(frames) = config.requested_buffer_size {
+        let streams = outputs.iter().zip(&output_supported);
+        for (device, supported) in streams.chain([(&input, &input_supported)]) {
+            if let cpal::SupportedBufferSize::Range { min, max } = supported.buffer_size() {
+                if frames < *min || frames > *max {
+                    anyhow::bail!(
+                        "buffer size {} is not supported by \"{}\", supported: {}-{} frames",
+                        frames,
+                        device.name()?,
+                        min,
+                        max
+                    );
+                }
+            }
+        }
+    }
+    let output_formats: Vec<_> = output_supported
+        .iter()
+        .map(|supported| supported.sample_format())
+        .collect();
+    let input_format = input_supported.sample_format();
+    if config.verbose {
+        // Each stream converts to and from f32 in its own format, so they
+        // needn't match.
+        for (name, supported) in output_names.iter().zip(&output_supported) {
+            status.print(&format!(
+                "Output \"{}\" takes {} samples and supports {:?} buffers",
+                name,
+                sample_format_name(supported.sample_format()),
+                supported.buffer_size()
+            ));
+        }
+        status.print(&format!(
+            "Input delivers {} samples and supports {:?} buffers",
+            sample_format_name(input_format),
+            input_supported.buffer_size()
+        ));
+    }
+    let output_buffer_sizes: Vec<_> = output_supported
+        .iter()
+        .map(|supported| supported.buffer_size().clone())
+        .collect();
+    let input_buffer_size = input_supported.buffer_size().clone();
+    let mut output_configs: Vec<cpal::StreamConfig> =
+        output_supported.into_iter().map(Into::into).collect();
+    let mut input_config: cpal::StreamConfig = input_supported.into();
+    if let Some(frames) = config.requested_buffer_size {
+        for output_config in &mut output_configs {
+            output_config.buffer_size = cpal::BufferSize::Fixed(frames);
+        }
+        input_config.buffer_size = cpal::BufferSize::Fixed(frames);
+    }
+    if config.low_latency {
+        // cpal doesn't expose exclusive mode, so the smallest buffer the host
+        // offers in its default mode is as low as this can go.
+        let output_streams = output_configs
+            .iter_mut()
+            .zip(&output_formats)
+            .zip(&output_buffer_sizes)
+            .map(|((stream, format), buffer_size)| {
+                (Direction::Output, stream, *format, buffer_size.clone())
+            });
+        let input_stream = (
+            Direction::Input,
+            &mut input_config,
+            input_format,
+            input_buffer_size,
+        );
+        for (direction, stream, format, buffer_size) in output_streams.chain([input_stream]) {
+            match buffer_size {
+                cpal::SupportedBufferSize::Range { min, .. } => {
+                    stream.buffer_size = cpal::BufferSize::Fixed(min);
+                    status.print(&format!(
+                        "Low latency: the {} stream uses {} frame buffers of {} {} channel(s) at {}Hz",
+                        direction.name(),
+                        min,
+                        sample_format_name(format),
+                        stream.channels,
+                        stream.sample_rate.0
+                    ));
+                }
+                cpal::SupportedBufferSize::Unknown => status.print(&format!(
+                    "Low latency: the host doesn't report the {} stream's buffer sizes, using its default",
+                    direction.name()
+                )),
+            }
+        }
+    }
+    let input_spec = StreamSpec {
+        device: input,
+        config: input_config,
+        format: input_format,
+    };
+    let output_specs = outputs
+        .into_iter()
+        .zip(output_configs)
+        .zip(output_formats)
+        .map(|((device, stream), format)| StreamSpec {
+            device,
+            config: stream,
+            format,
         })
+        .collect();
+    Ok((input_spec, output_specs))
+}
+
+/// Checks that the probe can be played at the streams' sample rate, and that
+/// the channels the options name are there on the first input and on the
+/// outputs.
+fn check_channels(
+    config: &Config,
+    input: &StreamSpec,
+    input_name: &str,
+    outputs: &[StreamSpec],
+    output_names: &[String],
+) -> anyhow::Result<()> {
+    let sample_rate = input.config.sample_rate.0 as f32;
+    let input_channels = input.config.channels as usize;
+    let output_channels = outputs.iter().map(|spec| spec.config.channels as usize);
+    let streams = output_names.iter().map(String::as_str).zip(output_channels);
+    for (name, channels) in [(input_name, input_channels)].into_iter().chain(streams) {
+        if channels == 0 {
+            anyhow::bail!("\"{}\" reported a config with no channels", name);
+        }
+    }
+    let probe_frequencies = match config.probe {
+        Probe::Tone | Probe::Square | Probe::Burst { .. } => vec![config.frequency],
+        Probe::Click | Probe::Noise { .. } | Probe::Wav { .. } => vec![],
+        Probe::Timecode => TIMECODE_HZ.to_vec(),
+        Probe::Sweep {
+            start_hz, end_hz, ..
+        } => vec![start_hz, end_hz],
+    };
+    for frequency in probe_frequencies {
+        if frequency <= 0f32 || frequency > sample_rate / 2f32 {
+            anyhow::bail!(
+                "frequency {}Hz must be between 0 and {}Hz (half the sample rate)",
+                frequency,
+                sample_rate / 2f32
+            );
+        }
+    }
+    if let Probe::Wav {
+        sample_rate: wav_rate,
+        ..
+    } = config.probe
+    {
+        if wav_rate as f32 != sample_rate {
+            anyhow::bail!(
+                "the probe WAV file is at {}Hz but the streams run at {}Hz; convert it, or pick a device that takes {}Hz",
+                wav_rate,
+                sample_rate,
+                wav_rate
+            );
+        }
+    }
+    if config.input_channel >= input_channels {
+        anyhow::bail!(
+            "input channel {} does not exist, the stream has {} channels",
+            config.input_channel,
+            input_channels
+        );
+    }
+    if config.stereo && input_channels < 2 {
+        anyhow::bail!(
+            "--stereo needs at least 2 input channels, the stream has {}",
+            input_channels
+        );
+    }
+    if let Some(reference_channel) = config.reference_channel {
+        if reference_channel >= input_channels {
+            anyhow::bail!(
+                "reference channel {} does not exist, the stream has {} channels",
+                reference_channel,
+                input_channels
+            );
+        }
+        if reference_channel == config.input_channel
+            || (config.stereo && reference_channel == 1)
+            || config.sync_channel == Some(reference_channel)
+        {
+            anyhow::bail!(
+                "--reference-channel must differ from the channels the probe is listened for on"
+            );
+        }
+    }
+    if let Some(sync_channel) = config.sync_channel {
+        if sync_channel >= input_channels {
+            anyhow::bail!(
+                "sync channel {} does not exist, the stream has {} channels",
+                sync_channel,
+                input_channels
+            );
+        }
+        if sync_channel == config.input_channel {
+            anyhow::bail!(
+                "--sync-channel must differ from the channel the probe is listened for on"
+            );
+        }
+    }
+    for (name, spec) in output_names.iter().zip(outputs) {
+        let channels = spec.config.channels as usize;
+        if let Some(output_channel) = config.output_channel.filter(|c| *c >= channels) {
+            anyhow::bail!(
+                "output channel {} does not exist, the stream to \"{}\" has {} channels",
+                output_channel,
+                name,
+                channels
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Picks a stream config for each input after the first. They keep their own
+/// sample rate unless one was asked for, as each detector counts frames at
+/// its own input's rate.
+fn configure_extra_inputs(
+    devices: Vec<cpal::Device>,
+    names: &[String],
+    config: &Config,
+    sample_rate: f32,
+    status: Status,
+) -> anyhow::Result<Vec<StreamSpec>> {
+    let mut specs = Vec::new();
+    for (device, name) in devices.into_iter().zip(names) {
+        let supported = select_config(
+            &device,
+            Direction::Input,
+            config.requested_sample_rate,
+            config.low_latency,
+        )?;
+        let format = supported.sample_format();
+        let mut stream: cpal::StreamConfig = match supported.buffer_size() {
+            cpal::SupportedBufferSize::Range { min, .. } if config.low_latency => {
+                let min = *min;
+                let mut stream: cpal::StreamConfig = supported.into();
+                stream.buffer_size = cpal::BufferSize::Fixed(min);
+                stream
+            }
+            _ => supported.into(),
+        };
+        if let Some(frames) = config.requested_buffer_size {
+            stream.buffer_size = cpal::BufferSize::Fixed(frames);
+        }
+        let channels = stream.channels as usize;
+        if channels == 0 {
+            anyhow::bail!("\"{}\" reported a config with no channels", name);
+        }
+        for (option, channel) in [
+            ("input", Some(config.input_channel)),
+            ("reference", config.reference_channel),
+        ] {
+            if let Some(channel) = channel.filter(|c| *c >= channels) {
+                anyhow::bail!(
+                    "{} channel {} does not exist, the stream from \"{}\" has {} channels",
+                    option,
+                    channel,
+                    name,
+                    channels
+                );
+            }
+        }
+        if stream.sample_rate.0 as f32 != sample_rate {
+            status.print(&format!(
+                "Input \"{}\" runs at {}Hz",
+                name, stream.sample_rate.0
+            ));
+        }
+        specs.push(StreamSpec {
+            device,
+            config: stream,
+            format,
+        });
     }
-    .expect("failed to find input device");
+    Ok(specs)
+}
 
-    let output = if output_device.is_none() {
-        host.default_output_device()
+/// The sensitivity to detect the probe with: the one given, or one set from
+/// the noise floor of the `input` with `--auto-sensitivity`, checked against
+/// the noise played for `--silence-level`.
+fn settle_sensitivity(
+    matches: &clap::ArgMatches,
+    config: &Config,
+    input: &StreamSpec,
+    detector_config: &DetectorConfig,
+    status: Status,
+) -> anyhow::Result<f32> {
+    let auto_sensitivity = matches.is_present("auto-sensitivity")
+        || (config.acoustic && !matches.is_present("sensitivity"));
+    let sensitivity = if auto_sensitivity && config.dry_run {
+        status.print("Would measure the input's noise floor to set the sensitivity");
+        config.sensitivity
+    } else if auto_sensitivity {
+        let multiple_str = matches.value_of("auto-sensitivity").unwrap_or("4");
+        let multiple = multiple_str.parse::<f32>()?;
+        status.print("Measuring the input's noise floor...");
+        // Scaled the same as the input the detector sees.
+        let noise_floor =
+            measure_noise_floor(&input.device, &input.config, input.format, detector_config)?;
+        let sensitivity = (noise_floor * multiple).clamp(MIN_AUTO_SENSITIVITY, 2f32);
+        status.print(&format!(
+            "Noise floor: {:.4}, using sensitivity {:.4}",
+            noise_floor, sensitivity
+        ));
+        sensitivity
+    } else {
+        config.sensitivity
+    };
+    // The noise played between pings comes back up to its peaks, which the
+    // detector has to take for silence.
+    let hysteresis = config.hysteresis;
+    let silence_peak_to_peak =
+        2f32 * SILENCE_CREST_FACTOR * config.silence_level * config.input_gain;
+    if config.silence_level == 0f32 || silence_peak_to_peak < sensitivity * hysteresis {
+        Ok(sensitivity)
+    } else if auto_sensitivity && hysteresis > 0f32 {
+        let sensitivity = (2f32 * silence_peak_to_peak / hysteresis).min(2f32);
+        status.print(&format!(
+            "Raising the sensitivity to {:.4}, above the noise played for --silence-level",
+            sensitivity
+        ));
+        Ok(sensitivity)
     } else {
-        host.output_devices()?.find(|x| {
-            x.name()
-                .map(|y| y == output_device.unwrap())
-                .unwrap_or(false)
+        anyhow::bail!(
+            "the noise played for --silence-level comes back at up to {:.4} peak-to-peak, which a sensitivity of {} with a hysteresis of {} would not take for silence; raise --sensitivity or lower --silence-level",
+            silence_peak_to_peak,
+            sensitivity,
+            hysteresis
+        );
+    }
+}
+
+/// Measures the impulse response from `output` to `input` for `--impulse`.
+#[cfg(feature = "fft")]
+fn run_impulse(
+    matches: &clap::ArgMatches,
+    config: &Config,
+    output: &StreamSpec,
+    input: &StreamSpec,
+    status: Status,
+) -> anyhow::Result<()> {
+    if output.config.sample_rate != input.config.sample_rate {
+        anyhow::bail!(
+            "--impulse needs the output and input at the same sample rate; pick one with --sample-rate"
+        );
+    }
+    let sample_rate = input.config.sample_rate.0 as f32;
+    let tail_str = matches.value_of("impulse-tail").unwrap_or("1000");
+    let tail_ms = tail_str.parse::<f32>()?;
+    if tail_ms <= 0f32 {
+        anyhow::bail!("--impulse-tail must be greater than 0ms");
+    }
+    // A sweep given with --sweep is played as it is.
+    let sweep = match config.probe {
+        Probe::Sweep { .. } => config.probe.clone(),
+        _ => Probe::Sweep {
+            start_hz: 20f32,
+            end_hz: (0.45 * sample_rate).min(20000f32),
+            duration_ms: 1000f32,
+            scale: SweepScale::Logarithmic,
+        },
+    };
+    let frames = sweep.duration_frames(sample_rate).unwrap_or_default();
+    let excitation = (1..=frames)
+        .map(|clock| {
+            let value = sweep.value(config.frequency, sample_rate, clock);
+            (value * config.volume * config.output_gain).clamp(-1.0, 1.0)
         })
+        .collect();
+    let settings = impulse_capture::ImpulseSettings {
+        excitation,
+        output_channel: config.output_channel,
+        input_channel: config.input_channel,
+        input_gain: config.input_gain,
+        tail: Duration::from_secs_f32(tail_ms / 1000f32),
+        offset_ms: config.offset_ms,
+    };
+    status.print(&format!(
+        "Measuring the impulse response with {}",
+        report::describe_probe(&sweep, config.frequency)
+    ));
+    let wav_path = matches.value_of("impulse-wav").map(Path::new);
+    impulse_capture::run(output, input, settings, config.json, wav_path)
+}
+
+/// The session `open_session` started, and what else it set up for the run.
+struct OpenSession {
+    session: Session,
+    measurements: Receiver<Measurement>,
+    input_names: Vec<String>,
+    output_names: Vec<String>,
+    sample_rate: f32,
+    /// Distinguishes this run's rows from those of earlier runs appended to
+    /// the same CSV file.
+    run_id: u128,
+    csv: Option<CsvWriter>,
+    baseline: Option<Baseline>,
+    triggers: Option<Receiver<()>>,
+    publisher: Option<Sender<String>>,
+    http_pages: Option<Arc<Mutex<http::Pages>>>,
+    /// Where the `--report` goes, and the report, with the rest filled in
+    /// when the run ends.
+    report: Option<(String, Report)>,
+    channel_sweep: Option<ChannelSweep>,
+    /// Set once the input has clipped.
+    clipped: Arc<AtomicBool>,
+    recording_thread: Option<JoinHandle<hound::Result<()>>>,
+    ping_log_thread: Option<JoinHandle<std::io::Result<()>>>,
+}
+
+/// Opens the devices the options ask for and starts a session on them, along
+/// with whatever the run reports to. Gives `None` for a dry run or an
+/// `--impulse` measurement, which are done once this returns. The input
+/// clipping with `--strict` is sent to `stop`.
+fn open_session(
+    matches: &clap::ArgMatches,
+    config: &Config,
+    host: &cpal::Host,
+    status: Status,
+    local_offset: time::UtcOffset,
+    stop: Sender<StopReason>,
+) -> anyhow::Result<Option<OpenSession>> {
+    let Devices {
+        input,
+        extra_inputs,
+        outputs,
+        input_names,
+        output_names,
+    } = find_devices(host, config, status)?;
+
+    if config.dry_run {
+        let paths = ["metrics-file", "save-summary", "report"];
+        for path in paths.into_iter().filter_map(|name| matches.value_of(name)) {
+            check_writable(Path::new(path))?;
+        }
+    }
+    // Loaded up front so that a bad path fails before the run, not after it.
+    let baseline = match matches.value_of("baseline") {
+        Some(path) => Some(Baseline::load(Path::new(path))?),
+        None => None,
+    };
+    // A dry run only checks the listeners could be started.
+    let triggers = match matches.value_of("trigger-fifo") {
+        Some(path) if config.dry_run => {
+            trigger::check(path)?;
+            None
+        }
+        Some(path) => Some(trigger::spawn(path)?),
+        None => None,
+    };
+    let publisher = match matches.value_of("publish") {
+        Some(addr) if config.dry_run => {
+            publish::check(addr)?;
+            None
+        }
+        Some(addr) => Some(publish::spawn(addr)?),
+        None => None,
+    };
+    let http_pages = match matches.value_of("http-addr") {
+        Some(addr) if config.dry_run => {
+            http::check(addr)?;
+            None
+        }
+        Some(addr) => Some(http::spawn(addr)?),
+        None => None,
+    };
+    let run_id = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let csv_header = if config.timestamps {
+        format!("{},time", CSV_HEADER)
+    } else {
+        CSV_HEADER.to_owned()
+    };
+    // The header is written along with the run's details once the streams'
+    // configs are known.
+    let (mut csv, csv_append) = open_csv(matches, config.dry_run, &csv_header)?;
+
+    status.print(&format!("Using host: \"{}\"", host.id().name()));
+    for name in &input_names {
+        status.print(&format!("Using input device: \"{}\"", name));
+    }
+    for name in &output_names {
+        status.print(&format!("Using output device: \"{}\"", name));
+    }
+
+    let (input_spec, output_specs) =
+        configure_streams(input, outputs, &output_names, config, status)?;
+    let sample_rate = input_spec.config.sample_rate.0 as f32;
+    let recording = match matches.value_of("record") {
+        Some(path) if config.dry_run => {
+            check_writable(Path::new(path))?;
+            None
+        }
+        Some(path) => {
+            let spec = hound::WavSpec {
+                channels: input_spec.config.channels,
+                sample_rate: input_spec.config.sample_rate.0,
+                bits_per_sample: 32,
+                sample_format: hound::SampleFormat::Float,
+            };
+            Some(recording::spawn(Path::new(path), spec)?)
+        }
+        None => None,
+    };
+    let (mut recorder, recording_thread) = recording.unzip();
+    let ping_log = match matches.value_of("record") {
+        Some(path) if !config.dry_run => Some(analyze::PingLog::create(
+            Path::new(path),
+            input_spec.config.sample_rate.0,
+        )?),
+        _ => None,
+    };
+    let (mut ping_log, ping_log_thread) = ping_log.unzip();
+    check_channels(
+        config,
+        &input_spec,
+        &input_names[0],
+        &output_specs,
+        &output_names,
+    )?;
+    let extra_input_specs =
+        configure_extra_inputs(extra_inputs, &input_names[1..], config, sample_rate, status)?;
+    let detector_config = DetectorConfig {
+        input_channel: config.input_channel,
+        compare_channel: if config.stereo { Some(1) } else { None },
+        reference_channel: config.reference_channel,
+        sync_channel: config.sync_channel,
+        output_channel: config.output_channel,
+        sensitivity: config.sensitivity,
+        hysteresis: config.hysteresis,
+        window_periods: config.window_periods,
+        averages: config.averages,
+        frequency: config.frequency,
+        probe: config.probe.clone(),
+        volume: config.volume,
+        ramp_ms: config.ramp_ms,
+        probe_delay_ms: config.probe_delay_ms,
+        input_gain: config.input_gain,
+        output_gain: config.output_gain,
+        silence_level: config.silence_level,
+        silence_noise: config.silence_noise,
+        detector: config.detector_kind,
+        precise: config.precise,
+        dc_block: config.dc_block,
+        invert_detection: config.invert_detection,
+        interval_ns: config.interval.as_nanos() as u64,
+        on_request: config.period.is_some()
+            || config.keys
+            || config.sweep_channels
+            || matches.is_present("trigger-fifo"),
+        // A sync marker stands in for the ping the detector would play.
+        listen_only: config.listen_only && config.sync_channel.is_none(),
+        ..DetectorConfig::default()
+    };
+    let sensitivity = settle_sensitivity(matches, config, &input_spec, &detector_config, status)?;
+    // The sensitivity is filled in once it's settled.
+    let detector_config = DetectorConfig {
+        sensitivity,
+        ..detector_config
+    };
+    let clipped = Arc::new(AtomicBool::new(false));
+    let input_clipped = Arc::clone(&clipped);
+    // Filled in once the session has made them, for the ping log.
+    let logged_detectors = Arc::new(OnceLock::<Vec<Arc<LatencyDetector>>>::new());
+    let tap_logged_detectors = Arc::clone(&logged_detectors);
+    let input_channels = input_spec.config.channels as usize;
+    let input_channel = config.input_channel;
+    let strict = config.strict;
+    let input_tap = move |data: &[f32], frame_end_ns: u64| {
+        if let Some(recorder) = &mut recorder {
+            recorder.write(data);
+        }
+        if let Some(ping_log) = &mut ping_log {
+            let detectors = tap_logged_detectors.get().map_or(&[][..], Vec::as_slice);
+            ping_log.record(data.len() / input_channels, frame_end_ns, detectors);
+        }
+        // Integer samples convert to exactly +/-1.0 at their extremes.
+        let clipping = data
+            .chunks_exact(input_channels)
+            .any(|frame| frame[input_channel].abs() >= 1.0);
+        if clipping && !input_clipped.swap(true, Ordering::SeqCst) {
+            eprintln!("Warning: input clipping detected, reduce gain");
+            if strict {
+                let _ = stop.send(StopReason::Clipped);
+            }
+        }
+    };
+
+    for spec in [&input_spec].into_iter().chain(&extra_input_specs) {
+        status.print(&format!(
+            "Attempting to build the input stream with {:?} samples and `{:?}`.",
+            spec.format, spec.config
+        ));
+    }
+    for spec in &output_specs {
+        status.print(&format!(
+            "Attempting to build the output stream with {:?} samples and `{:?}`.",
+            spec.format, spec.config
+        ));
+    }
+    let input_infos = [&input_spec]
+        .into_iter()
+        .chain(&extra_input_specs)
+        .zip(&input_names)
+        .map(|(spec, name)| StreamInfo::new(name, spec.format, &spec.config))
+        .collect();
+    let output_infos = output_specs
+        .iter()
+        .zip(&output_names)
+        .map(|(spec, name)| StreamInfo::new(name, spec.format, &spec.config))
+        .collect();
+    let run_info = RunInfo::new(
+        run_id,
+        wall_clock_time(local_offset),
+        host,
+        input_infos,
+        output_infos,
+    );
+    // The rest is filled in when the run ends.
+    let report = matches.value_of("report").map(|path| {
+        let report = Report {
+            run: run_info.clone(),
+            time: String::new(),
+            probe: config.probe.clone(),
+            frequency: config.frequency,
+            sensitivity,
+            offset_ms: config.offset_ms,
+            timeouts: 0,
+            discarded: 0,
+            negative: 0,
+        };
+        (path.to_owned(), report)
+    });
+    if config.dry_run {
+        status.print(&format!(
+            "Dry run OK: probe {}, {} output(s), input channel {}; the streams were not started",
+            report::describe_probe(&config.probe, config.frequency),
+            output_names.len(),
+            config.input_channel
+        ));
+        return Ok(None);
+    }
+    if config.json {
+        println!("{}", run_info.json());
+    }
+    #[cfg(feature = "fft")]
+    if config.impulse {
+        run_impulse(matches, config, &output_specs[0], &input_spec, status)?;
+        return Ok(None);
+    }
+    if let Some(writer) = &mut csv {
+        if !csv_append {
+            writeln!(writer, "{}", csv_header)?;
+        }
+        write!(writer, "{}", run_info.csv_comments())?;
+        writer.flush()?;
+    }
+    let channel_sweep = config.sweep_channels.then(|| {
+        let channels = output_specs[0].config.channels as usize;
+        ChannelSweep::new(channels)
+    });
+    let (session, measurements) = Session::start(SessionConfig {
+        inputs: [input_spec].into_iter().chain(extra_input_specs).collect(),
+        outputs: output_specs,
+        detector: detector_config,
+        dither: config.dither,
+        input_tap: Some(Box::new(input_tap)),
+    })?;
+    status.print("Successfully built and started the streams.");
+    // Followers on further inputs time the same pings, so only those played
+    // are logged.
+    let _ = logged_detectors.set(session.output_detectors().to_vec());
+    Ok(Some(OpenSession {
+        session,
+        measurements,
+        input_names,
+        output_names,
+        sample_rate,
+        run_id,
+        csv,
+        baseline,
+        triggers,
+        publisher,
+        http_pages,
+        report,
+        channel_sweep,
+        clipped,
+        recording_thread,
+        ping_log_thread,
+    }))
+}
+
+/// Everything `handle_measurement` goes by and reports to, and what it keeps
+/// from one measurement to the next.
+struct MeasurementContext<'a> {
+    config: &'a Config,
+    status: Status,
+    local_offset: time::UtcOffset,
+    /// Distinguishes this run's rows from those of earlier runs appended to
+    /// the same CSV file.
+    run_id: u128,
+    line_format: LineFormat,
+    /// The kind of device each measurement is tagged with, and their names.
+    devices: Option<(&'static str, Vec<String>)>,
+    csv: Option<CsvWriter>,
+    publisher: Option<Sender<String>>,
+    http_pages: Option<Arc<Mutex<http::Pages>>>,
+    metrics: Option<writer::Writer<String>>,
+    /// The dashboard's end of its channel.
+    tui: Option<Sender<(f32, DelayTrend)>>,
+    /// Where the run is told to stop.
+    stop: Sender<StopReason>,
+    filter: MeasurementFilter,
+    trackers: Vec<DelayTracker>,
+    delay_window: Option<DelayWindow>,
+    json_line: String,
+    warmup_remaining: u64,
+    /// Whether the next measurement is the --check ping.
+    checking_loopback: bool,
+    late_callback_noted: bool,
+    drift_alarmed: bool,
+    inverted_noted: bool,
+    delays: DelayStats,
+    /// Delays heard on each input, compared at the end.
+    input_delays: Option<Vec<DelayStats>>,
+    channel_sweep: Option<ChannelSweep>,
+    histogram_buckets: Vec<u32>,
+    measured: u64,
+    discarded: u64,
+    negative: u64,
+    /// The delay that went over --fail-over, if one did, and the limit.
+    failed_over: Option<(f32, f32)>,
+}
+
+impl MeasurementContext<'_> {
+    /// Starts the statistics over, along with the --count and the tallies
+    /// the exit status and report go by.
+    fn reset(&mut self) {
+        self.trackers.iter_mut().for_each(DelayTracker::reset);
+        self.filter.reset();
+        if let Some(delay_window) = &mut self.delay_window {
+            delay_window.reset();
+        }
+        self.drift_alarmed = false;
+        self.delays = DelayStats::new();
+        if let Some(input_delays) = &mut self.input_delays {
+            for delays in input_delays.iter_mut() {
+                *delays = DelayStats::new();
+            }
+        }
+        self.histogram_buckets.clear();
+        self.measured = 0;
+        self.discarded = 0;
+        self.negative = 0;
+    }
+}
+
+/// Reports `measurement` wherever the run reports to, once it's through the
+/// warmup and the filter, and tells the run to stop if it should.
+fn handle_measurement(context: &mut MeasurementContext, measurement: Measurement) {
+    let config = context.config;
+    let status = context.status;
+    // Taken here rather than in the audio callback, to keep formatting out
+    // of it.
+    let time = config
+        .timestamps
+        .then(|| wall_clock_time(context.local_offset));
+    let line_prefix = time
+        .as_ref()
+        .map(|time| format!("{} ", time))
+        .unwrap_or_default();
+    // Either the outputs or the inputs have a detector each, and the other
+    // index is always 0.
+    let detector_index = measurement.output + measurement.input;
+    let device = context
+        .devices
+        .as_ref()
+        .map(|(kind, names)| (*kind, names[detector_index].as_str()));
+    let swept_channel = context
+        .channel_sweep
+        .as_ref()
+        .and(measurement.output_channel);
+    if let (Some(sweep), Some(channel)) = (&mut context.channel_sweep, swept_channel) {
+        sweep.returned(channel);
+    }
+    if context.checking_loopback {
+        context.checking_loopback = false;
+        status.print("Loopback OK");
+        return;
+    }
+    if context.warmup_remaining > 0 {
+        context.warmup_remaining -= 1;
+        if context.warmup_remaining == 0 {
+            status.print("Warmup complete");
+        }
+        return;
+    }
+    let (measurement, average_stddev_ms) = match context.filter.push(measurement) {
+        Filtered::Kept(measurement, average_stddev_ms) => (measurement, average_stddev_ms),
+        Filtered::Averaging => return,
+        Filtered::Negative => {
+            status.print(NEGATIVE_DELAY);
+            context.negative += 1;
+            return;
+        }
+        Filtered::Discarded(reason) => {
+            status.print(&reason);
+            context.discarded += 1;
+            return;
+        }
+    };
+    let trend = context.trackers[detector_index].push(measurement.delay_ms);
+    let window_summary = context
+        .delay_window
+        .as_mut()
+        .map(|window| window.push(measurement.timestamp_ns, measurement.delay_ms));
+    let measurement_json =
+        (context.publisher.is_some() || context.http_pages.is_some()).then(|| {
+            let mut line = String::new();
+            write_measurement_json(
+                &mut line,
+                &measurement,
+                &trend,
+                window_summary.as_ref(),
+                average_stddev_ms,
+                device,
+                time.as_deref(),
+            );
+            line
+        });
+    if let (Some(publisher), Some(line)) = (&context.publisher, &measurement_json) {
+        let _ = publisher.send(line.clone());
+    }
+
+    if let Some(writer) = &mut context.csv {
+        let stream_delay = measurement
+            .stream_delay_ms
+            .map(|delay_ms| delay_ms.to_string())
+            .unwrap_or_default();
+        let _ = writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{},{},{}{}",
+            context.run_id,
+            measurement.timestamp_ns,
+            measurement.delay_ms,
+            measurement.amplitude,
+            stream_delay,
+            trend.smoothed_ms,
+            trend.jitter_ms,
+            measurement.confidence,
+            measurement.output,
+            measurement.input,
+            time.as_ref()
+                .map(|time| format!(",{}", time))
+                .unwrap_or_default()
+        )
+        .and_then(|_| writer.flush());
+    } else if config.json && !config.quiet {
+        context.json_line.clear();
+        write_measurement_json(
+            &mut context.json_line,
+            &measurement,
+            &trend,
+            window_summary.as_ref(),
+            average_stddev_ms,
+            device,
+            time.as_deref(),
+        );
+        println!("{}", context.json_line);
+    } else if let Some(tui) = &context.tui {
+        let _ = tui.send((measurement.delay_ms, trend));
+    } else if config.histogram {
+        let bucket = measurement.delay_ms.max(0.0) as usize;
+        let buckets = &mut context.histogram_buckets;
+        if buckets.len() <= bucket {
+            buckets.resize(bucket + 1, 0);
+        }
+        buckets[bucket] += 1;
+    } else if config.stereo && !config.quiet {
+        let decimals = context.line_format.decimals;
+        match measurement.compare_delay_ms {
+            Some(right_ms) => println!(
+                "{}L: {:.*}ms  R: {:.*}ms  skew: {:.*}ms",
+                line_prefix,
+                decimals,
+                measurement.delay_ms,
+                decimals,
+                right_ms,
+                decimals,
+                right_ms - measurement.delay_ms
+            ),
+            None => println!(
+                "{}L: {:.*}ms  R: not detected in the same block",
+                line_prefix, decimals, measurement.delay_ms
+            ),
+        }
+    } else if !config.quiet {
+        if measurement.inverted && !context.inverted_noted {
+            context.inverted_noted = true;
+            status.print(
+                "The probe came back with its polarity reversed; check the cabling, or pass --invert-detection if the loopback is meant to invert it",
+            );
+        }
+        if measurement.scheduling_jitter_ms.is_some() && !context.late_callback_noted {
+            context.late_callback_noted = true;
+            status.print(
+                "Delays marked * were measured while an input callback ran late, and may include OS scheduling noise",
+            );
+        }
+        let mut line = line_prefix;
+        write_measurement_line(
+            &mut line,
+            &measurement,
+            &trend,
+            window_summary.as_ref(),
+            average_stddev_ms,
+            device,
+            &context.line_format,
+        );
+        println!("{}", line);
     }
-    .expect("failed to find output device");
 
-    println!("Using input device: \"{}\"", input.name()?);
-    println!("Using output device: \"{}\"", output.name()?);
+    // With several inputs, the run's summary is the first input's, and the
+    // others are summarized beside it.
+    if measurement.input == 0 {
+        context
+            .delays
+            .push(measurement.timestamp_ns, measurement.delay_ms);
+    }
+    if let Some(input_delays) = &mut context.input_delays {
+        input_delays[measurement.input].push(measurement.timestamp_ns, measurement.delay_ms);
+    }
+    if let (Some(sweep), Some(channel)) = (&mut context.channel_sweep, swept_channel) {
+        sweep.record(channel, measurement.timestamp_ns, measurement.delay_ms);
+    }
+    if let Some(drift_alarm) = config.drift_alarm {
+        // Only warned about as it crosses the threshold, either way.
+        let drift = context.delays.drift_ms_per_hour();
+        let drifting = drift.is_some_and(|drift| drift.abs() > drift_alarm);
+        if drifting && !context.drift_alarmed {
+            status.print(&format!(
+                "Warning: the delay is drifting by {:+.2}ms/hour, over the --drift-alarm of {}ms/hour",
+                drift.unwrap_or_default(),
+                drift_alarm
+            ));
+        } else if !drifting && context.drift_alarmed {
+            status.print("The delay's drift is back under the --drift-alarm");
+        }
+        context.drift_alarmed = drifting;
+    }
 
-    let config: cpal::StreamConfig = output.default_output_config()?.into();
-    let sample_rate = config.sample_rate.0 as f32;
-    let channels = config.channels as usize;
-    let signal_active = Arc::new(AtomicBool::new(false));
-    let signal_active2 = Arc::clone(&signal_active);
-    let signal_start = Arc::new(AtomicU64::new(0));
-    let signal_start2 = Arc::clone(&signal_start);
+    context.measured += 1;
+    let measured = context.measured;
+    if let Some(metrics) = &mut context.metrics {
+        metrics.send(|metrics| {
+            metrics.clear();
+            let _ = write_metrics_text(metrics, measurement.delay_ms, trend, measured);
+        });
+    }
+    if let (Some(pages), Some(line)) = (&context.http_pages, &measurement_json) {
+        // Rendered here, so that a request only has to copy them.
+        let summary = context
+            .delays
+            .summary()
+            .map(|summary| summary_json(&summary));
+        let status_json = format!(
+            "{{\"count\":{},\"latest\":{},\"summary\":{}}}",
+            measured,
+            line,
+            summary.as_deref().unwrap_or("null")
+        );
+        let metrics = metrics_text(measurement.delay_ms, trend, measured);
+        let mut pages = pages.lock().unwrap();
+        pages.metrics = metrics;
+        pages.status_json = status_json;
+    }
+    let checked_ms = if config.fail_on_smoothed {
+        trend.smoothed_ms
+    } else {
+        measurement.delay_ms
+    };
+    if let Some(fail_over_ms) = config.fail_over_ms.filter(|limit| checked_ms > *limit) {
+        if context.failed_over.is_none() {
+            // Reported once the run has stopped.
+            context.failed_over = Some((checked_ms, fail_over_ms));
+            let _ = context.stop.send(StopReason::FailedOver);
+        }
+    }
+    if config.count == Some(measured) {
+        let _ = context.stop.send(StopReason::CountReached);
+    }
+}
 
-    let start_time = Instant::now();
+/// What the run's loop listens to, besides the session.
+struct RunEvents<'a> {
+    measurements: Receiver<Measurement>,
+    /// Why the run should stop, from whatever noticed.
+    stops: Receiver<StopReason>,
+    /// What the keys pressed ask for, with --keys.
+    commands: Option<&'a Receiver<Command>>,
+    /// A message for each line written to the --trigger-fifo.
+    triggers: Option<Receiver<()>>,
+}
 
-    // Input loop
-    let input_data_fn = move |data: &[f32], _: &cpal::InputCallbackInfo| {
-        let frame_start_us = (start_time.elapsed().as_nanos() / 1000) as u64;
-        let signal_start_us = signal_start.load(Ordering::SeqCst) / 1000;
+/// How the run's loop ended.
+struct RunOutcome {
+    timeouts: u64,
+    /// The stream that failed and couldn't be rebuilt, if one did.
+    failed_stream: Option<Direction>,
+    /// Whether the --check ping never came back.
+    loopback_failed: bool,
+}
 
-        let mut signal_count = 0u32;
-        let mut signal_found = false;
-        let (mut min, mut max) = (Option::<f32>::None, Option::<f32>::None);
-        for frame in data.chunks(channels) {
-            let sample = &frame[0];
-            min = min.and_then(|x| Some(x.min(*sample))).or(Some(*sample));
-            max = max.and_then(|x| Some(x.max(*sample))).or(Some(*sample));
-            if max.unwrap() - min.unwrap() > sensitivity {
-                signal_found = true;
+/// Sends the pings and hands each measurement to `handle_measurement`, until
+/// something stops the run.
+fn run_loop(
+    session: &mut Session,
+    context: &mut MeasurementContext,
+    events: RunEvents,
+    input_names: &[String],
+    output_names: &[String],
+) -> RunOutcome {
+    let config = context.config;
+    let status = context.status;
+    let mut last_redraw = Instant::now();
+    let mut histogram_lines = 0;
+    let mut outcome = RunOutcome {
+        timeouts: 0,
+        failed_stream: None,
+        loopback_failed: false,
+    };
+    let mut reconnects_left = config.reconnects;
+    let mut next_ping = Instant::now();
+    let mut input_buffers_logged = vec![!config.verbose; input_names.len()];
+    let mut output_buffers_logged = vec![!config.verbose; output_names.len()];
+    // Streams that have reported an error since they were last built.
+    let mut failing_streams = Vec::new();
+    let mut paused = false;
+    // Triggers received and not yet pinged for.
+    let mut pending_triggers = 0;
+    loop {
+        while let Ok(measurement) = events.measurements.try_recv() {
+            handle_measurement(context, measurement);
+        }
+        // A SIGHUP resets the statistics as the r key does.
+        let hangup = signals::take_hangup().then_some(Command::Reset);
+        let key_commands = std::iter::from_fn(|| {
+            events
+                .commands
+                .and_then(|commands| commands.try_recv().ok())
+        });
+        for command in hangup.into_iter().chain(key_commands) {
+            match command {
+                Command::TogglePause => {
+                    paused = !paused;
+                    status.print(if paused {
+                        "Paused, press space to ping once or p to resume"
+                    } else {
+                        "Resumed"
+                    });
+                }
+                Command::Reset => {
+                    context.reset();
+                    outcome.timeouts = 0;
+                    status.print("Statistics reset");
+                }
+                Command::Ping if paused => request_ping(session, context.channel_sweep.as_mut()),
+                Command::Ping => {}
             }
-            if signal_found {
-                signal_count += 1;
+        }
+        while let Ok((direction, err)) = session.errors().try_recv() {
+            eprintln!(
+                "an error occurred on the {} stream: {}",
+                direction.name(),
+                err
+            );
+            if !failing_streams.contains(&direction) {
+                failing_streams.push(direction);
+                let _ = context.stop.send(StopReason::StreamFailed(direction));
             }
         }
-        let amplitude = max.unwrap_or(0f32) - min.unwrap_or(0f32);
-        if signal_found {
-            let was_active = signal_active.swap(false, Ordering::SeqCst);
-            if was_active && signal_start_us < frame_start_us {
-                let mut delay_ms = (frame_start_us - signal_start_us) as f32 / 1000.0;
-                delay_ms -= signal_count as f32 * 1000.0 / sample_rate;
-                println!("Delay: {:3.2}ms, Signal: {}", delay_ms, amplitude);
+        for (index, logged) in input_buffers_logged.iter_mut().enumerate() {
+            if !*logged && session.input_buffer_frames(index) > 0 {
+                *logged = true;
+                status.print(&format!(
+                    "First input callback: {} frames",
+                    session.input_buffer_frames(index)
+                ));
             }
-        } else {
-            let was_active = signal_active.swap(true, Ordering::SeqCst);
-            if !was_active {
-                signal_start.store(0, Ordering::SeqCst);
+        }
+        for (index, logged) in output_buffers_logged.iter_mut().enumerate() {
+            if !*logged && session.output_buffer_frames(index) > 0 {
+                *logged = true;
+                status.print(&format!(
+                    "First output callback: {} frames",
+                    session.output_buffer_frames(index)
+                ));
+            }
+        }
+
+        let now = Instant::now();
+        if let Some(triggers) = &events.triggers {
+            // Each trigger gets a ping of its own, however quickly they come.
+            pending_triggers += triggers.try_iter().count();
+            if pending_triggers > 0 && !paused && !session.ping_pending() {
+                pending_triggers -= 1;
+                request_ping(session, context.channel_sweep.as_mut());
             }
         }
+        let next_turn = match config.period {
+            Some(_) => now >= next_ping,
+            None if events.triggers.is_some() => false,
+            None => {
+                (events.commands.is_some()
+                    || output_names.len() > 1
+                    || input_names.len() > 1
+                    || config.sweep_channels)
+                    && !session.ping_pending()
+            }
+        };
+        if next_turn && !paused {
+            request_ping(session, context.channel_sweep.as_mut());
+        }
+        if let Some(period) = config.period.filter(|_| next_turn) {
+            // Keep to the schedule, skipping any pings missed entirely.
+            while next_ping <= now {
+                next_ping += period;
+            }
+        }
+        match events.stops.recv_timeout(Duration::from_millis(10)) {
+            Ok(StopReason::StreamFailed(direction)) if reconnects_left > 0 => {
+                reconnects_left -= 1;
+                status.print(&format!(
+                    "The {} stream failed, rebuilding it",
+                    direction.name()
+                ));
+                if let Err(err) = session.rebuild(direction) {
+                    eprintln!("Could not rebuild the {} stream: {}", direction.name(), err);
+                    outcome.failed_stream = Some(direction);
+                    break;
+                }
+                failing_streams.retain(|failing| *failing != direction);
+            }
+            Ok(reason) => {
+                if let StopReason::StreamFailed(direction) = reason {
+                    outcome.failed_stream = Some(direction);
+                }
+                status.print(reason.message());
+                break;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(index) = session.check_timeout(config.timeout) {
+                    if context.checking_loopback {
+                        outcome.loopback_failed = true;
+                        break;
+                    }
+                    outcome.timeouts += 1;
+                    if let Some(sweep) = &mut context.channel_sweep {
+                        sweep.timed_out();
+                        status.print(&format!(
+                            "Timeout: no signal returned from channel {}",
+                            sweep.current()
+                        ));
+                    } else if output_names.len() > 1 {
+                        status.print(&format!(
+                            "Timeout: no signal returned from \"{}\"",
+                            output_names[index]
+                        ));
+                    } else if input_names.len() > 1 {
+                        status.print(&format!(
+                            "Timeout: no signal returned to \"{}\"",
+                            input_names[index]
+                        ));
+                    } else {
+                        status.print("Timeout: no signal returned");
+                    }
+                }
+            }
+        }
+        if config.histogram && last_redraw.elapsed() >= HISTOGRAM_REDRAW_INTERVAL {
+            histogram_lines = draw_histogram(&context.histogram_buckets, histogram_lines);
+            last_redraw = Instant::now();
+        }
+    }
+    outcome
+}
+
+/// The options given on the command line, parsed and checked.
+struct Config {
+    virtual_loopback: bool,
+    /// The `--input` devices, or the `--loopback-device`.
+    input_devices: Vec<String>,
+    /// The `--output` devices, or the `--loopback-device`.
+    output_devices: Vec<String>,
+    timestamps: bool,
+    volume: f32,
+    input_gain: f32,
+    output_gain: f32,
+    /// Before `--auto-sensitivity` has had its say.
+    sensitivity: f32,
+    hysteresis: f32,
+    window_periods: f32,
+    silence_level: f32,
+    silence_noise: SilenceNoise,
+    ramp_ms: f32,
+    probe_delay_ms: f32,
+    averages: usize,
+    frequency: f32,
+    timeout: Duration,
+    requested_sample_rate: Option<u32>,
+    requested_buffer_size: Option<u32>,
+    low_latency: bool,
+    units: String,
+    alpha: f32,
+    interval: Duration,
+    average_count: usize,
+    /// The `--window`, as given, to print beside its percentiles.
+    window_str: String,
+    window: Option<Duration>,
+    max_delay_ms: Option<f32>,
+    fail_over_ms: Option<f32>,
+    fail_on_smoothed: bool,
+    drift_alarm: Option<f32>,
+    min_confidence: f32,
+    period: Option<Duration>,
+    warmup: u64,
+    detector_kind: Detector,
+    input_channel: usize,
+    stereo: bool,
+    sweep_channels: bool,
+    reference_channel: Option<usize>,
+    output_channel: Option<usize>,
+    quiet: bool,
+    verbose: bool,
+    histogram: bool,
+    tui: bool,
+    keys: bool,
+    listen_only: bool,
+    impulse: bool,
+    sync_channel: Option<usize>,
+    dry_run: bool,
+    precise: bool,
+    dc_block: bool,
+    invert_detection: bool,
+    dither: bool,
+    strict: bool,
+    reconnects: u32,
+    probe: Probe,
+    acoustic: bool,
+    json: bool,
+    calibrate: bool,
+    count: Option<u64>,
+    duration: Option<Duration>,
+    distance: f32,
+    /// How long sound takes to travel the `--distance`.
+    propagation_ms: f32,
+    /// Taken off every delay, the sound's travel time included.
+    offset_ms: f32,
+}
+
+/// Parses and checks the options given on the command line.
+fn parse_config(matches: &clap::ArgMatches) -> anyhow::Result<Config> {
+    let virtual_loopback = matches.is_present("virtual");
+    let loopback_device = matches.value_of("loopback-device");
+    let input_devices: Vec<String> = match matches.values_of("input") {
+        Some(names) => names.map(str::to_owned).collect(),
+        None => loopback_device.into_iter().map(str::to_owned).collect(),
+    };
+    let timestamps = matches.is_present("timestamps");
+    let output_devices: Vec<String> = match matches.values_of("output") {
+        Some(names) => names.map(str::to_owned).collect(),
+        None => loopback_device.into_iter().map(str::to_owned).collect(),
+    };
+
+    let volume = match matches.value_of("volume-dbfs") {
+        Some(db_str) => 10f32.powf(db_str.parse::<f32>()? / 20f32).min(1f32),
+        None => {
+            let volume_str = matches.value_of("volume").unwrap_or("50");
+            volume_str.parse::<f32>()?.clamp(0f32, 100f32) / 100f32
+        }
+    };
+    let input_gain_str = matches.value_of("in-gain").unwrap_or("1");
+    let input_gain = input_gain_str.parse::<f32>()?;
+    let output_gain_str = matches.value_of("out-gain").unwrap_or("1");
+    let output_gain = output_gain_str.parse::<f32>()?;
+    // A virtual device returns the probe untouched and without noise, so
+    // even a quiet path can be picked up.
+    let default_sensitivity = if virtual_loopback { "0.1" } else { "1" };
+    let sensitivity_str = matches
+        .value_of("sensitivity")
+        .unwrap_or(default_sensitivity);
+    let sensitivity = sensitivity_str.parse::<f32>()?.clamp(0f32, 2f32);
+    let hysteresis_str = matches.value_of("hysteresis").unwrap_or("1");
+    let hysteresis = hysteresis_str.parse::<f32>()?.clamp(0f32, 1f32);
+    let window_periods = match matches.value_of("trigger-periods") {
+        Some(periods_str) => periods_str.parse::<f32>()?,
+        None => WINDOW_PERIODS,
+    };
+    if window_periods <= 0f32 {
+        anyhow::bail!("--trigger-periods must be greater than 0");
+    }
+    let silence_level = match matches.value_of("silence-level") {
+        Some(db_str) => 10f32.powf(db_str.parse::<f32>()? / 20f32).min(1f32),
+        None => 0f32,
+    };
+    let silence_noise = match matches.value_of("silence-noise") {
+        Some("white") => SilenceNoise::White,
+        _ => SilenceNoise::Pink,
+    };
+    let ramp_str = matches.value_of("ramp-ms").unwrap_or("0");
+    let ramp_ms = ramp_str.parse::<f32>()?;
+    if ramp_ms < 0f32 {
+        anyhow::bail!("--ramp-ms can't be negative");
+    }
+    let probe_delay_str = matches.value_of("probe-delay").unwrap_or("0");
+    let probe_delay_ms = probe_delay_str.parse::<f32>()?;
+    if probe_delay_ms < 0f32 {
+        anyhow::bail!("--probe-delay can't be negative");
+    }
+    let averages_str = matches.value_of("averages").unwrap_or("1");
+    let averages = averages_str.parse::<usize>()?;
+    if averages == 0 {
+        anyhow::bail!("--averages must be at least 1");
+    }
+    let frequency_str = matches.value_of("frequency").unwrap_or("440");
+    let frequency = frequency_str.parse::<f32>()?;
+    let timeout_str = matches.value_of("timeout").unwrap_or("1000");
+    let timeout = Duration::from_millis(timeout_str.parse::<u64>()?);
+    let requested_sample_rate = match matches.value_of("sample-rate") {
+        Some(rate_str) => Some(rate_str.parse::<u32>()?),
+        None => None,
+    };
+    let requested_buffer_size = match matches.value_of("buffer-size") {
+        Some(frames_str) => Some(frames_str.parse::<u32>()?),
+        None => None,
     };
+    // Only software buffering stands between a virtual device's output and
+    // input, so measure it at its smallest.
+    let low_latency = matches.is_present("low-latency")
+        || (virtual_loopback && !matches.is_present("buffer-size"));
+    let units = matches.value_of("units").unwrap_or("both").to_owned();
+    let alpha_str = matches.value_of("alpha").unwrap_or("0.1");
+    let alpha = alpha_str.parse::<f32>()?.clamp(0f32, 1f32);
+    let interval_str = matches.value_of("interval").unwrap_or("0");
+    let interval = Duration::from_millis(interval_str.parse::<u64>()?);
+    let average_str = matches.value_of("average").unwrap_or("1");
+    let average_count = average_str.parse::<usize>()?;
+    let window_str = matches.value_of("window").unwrap_or_default().to_owned();
+    let window = match matches.value_of("window") {
+        Some(window_str) => Some(Duration::try_from_secs_f64(window_str.parse::<f64>()?)?),
+        None => None,
+    };
+    let max_delay_ms = match matches.value_of("max-delay") {
+        Some(max_delay_str) => Some(max_delay_str.parse::<f32>()?),
+        None => None,
+    };
+    let fail_over_ms = match matches.value_of("fail-over") {
+        Some(fail_over_str) => Some(fail_over_str.parse::<f32>()?),
+        None => None,
+    };
+    let fail_on_smoothed = matches.value_of("fail-on") == Some("smoothed");
+    let drift_alarm = match matches.value_of("drift-alarm") {
+        Some(drift_str) => Some(drift_str.parse::<f32>()?.abs()),
+        None => None,
+    };
+    let min_confidence_str = matches.value_of("min-confidence").unwrap_or("0");
+    let min_confidence = min_confidence_str.parse::<f32>()?.clamp(0f32, 1f32);
 
-    // Output loop
-    let output_data_fn = move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-        if signal_active2.load(Ordering::SeqCst) {
-            // Produce a sinusoid at the specified amplitude.
-            let mut sample_clock = 0f32;
-            let mut next_value = move || {
-                sample_clock = (sample_clock + 1.0) % sample_rate;
-                (sample_clock * 440.0 * 2.0 * PI / sample_rate).sin()
+    let period = match matches.value_of("period") {
+        Some(period_str) => Some(Duration::try_from_secs_f64(period_str.parse::<f64>()?)?),
+        None => None,
+    };
+    if period.is_some_and(|period| period.is_zero()) {
+        anyhow::bail!("period must be greater than 0 seconds");
+    }
+    let warmup_str = matches.value_of("warmup").unwrap_or("2");
+    let warmup = warmup_str.parse::<u64>()?;
+    let detector_kind = match matches.value_of("detector") {
+        Some("peak") | Some("amplitude") => Detector::Peak,
+        Some("goertzel") => Detector::Goertzel,
+        Some("fft") => fft_detector(matches)?,
+        _ => Detector::Rms,
+    };
+    if (matches.is_present("fft-size") || matches.is_present("fft-overlap"))
+        && matches.value_of("detector") != Some("fft")
+    {
+        anyhow::bail!("--fft-size and --fft-overlap only apply to --detector fft");
+    }
+    let input_channel_str = matches.value_of("in-channel").unwrap_or("0");
+    let input_channel = input_channel_str.parse::<usize>()?;
+    let stereo = matches.is_present("stereo");
+    let sweep_channels = matches.is_present("sweep-channels");
+    let reference_channel = match matches.value_of("reference-channel") {
+        Some(channel_str) => Some(channel_str.parse::<usize>()?),
+        None => None,
+    };
+    let output_channel = match matches.value_of("out-channel") {
+        Some(channel_str) => Some(channel_str.parse::<usize>()?),
+        None => None,
+    };
+    let quiet = matches.is_present("quiet");
+    let verbose = matches.is_present("verbose");
+    let histogram = matches.is_present("histogram") && !quiet;
+    let tui = matches.is_present("tui");
+    let keys = matches.is_present("keys");
+    if keys && !std::io::stdin().is_terminal() {
+        anyhow::bail!("--keys needs a terminal to read key presses from");
+    }
+    let listen_only = matches.is_present("listen-only");
+    let impulse = matches.is_present("impulse");
+    if impulse && !cfg!(feature = "fft") {
+        anyhow::bail!(
+            "this build has no impulse response measurement; rebuild audioping with --features fft"
+        );
+    }
+    let sync_channel = match matches.value_of("sync-channel") {
+        Some(channel_str) => Some(channel_str.parse::<usize>()?),
+        None => None,
+    };
+    let dry_run = matches.is_present("dry-run");
+    let precise = matches.is_present("precise");
+    let dc_block = matches.value_of("dc-block") != Some("off");
+    let invert_detection = matches.is_present("invert-detection");
+    let dither = matches.is_present("dither");
+    let strict = matches.is_present("strict");
+    let reconnects = if matches.is_present("reconnect") {
+        let reconnects_str = matches.value_of("reconnect").unwrap_or("3");
+        reconnects_str.parse::<u32>()?
+    } else {
+        0
+    };
+    let probe = match matches.values_of("sweep") {
+        Some(mut values) => {
+            let start_hz = values.next().unwrap_or_default().parse::<f32>()?;
+            let end_hz = values.next().unwrap_or_default().parse::<f32>()?;
+            let duration_ms = values.next().unwrap_or_default().parse::<f32>()?;
+            if duration_ms <= 0f32 {
+                anyhow::bail!("sweep duration must be greater than 0ms");
+            }
+            if detector_kind.is_tuned() {
+                anyhow::bail!(
+                    "the {} detector only works with a fixed tone, not a sweep",
+                    matches.value_of("detector").unwrap_or_default()
+                );
+            }
+            let scale = match matches.value_of("sweep-scale") {
+                Some("linear") => SweepScale::Linear,
+                _ => SweepScale::Logarithmic,
             };
-            for frame in data.chunks_mut(channels) {
-                let value = next_value() * volume;
-                for sample in frame.iter_mut() {
-                    *sample = value;
+            Probe::Sweep {
+                start_hz,
+                end_hz,
+                duration_ms,
+                scale,
+            }
+        }
+        None if matches.is_present("probe-wav") => {
+            let path = matches.value_of("probe-wav").unwrap_or_default();
+            let (samples, sample_rate) = load_probe_wav(Path::new(path))?;
+            Probe::Wav {
+                samples: samples.into(),
+                sample_rate,
+                looped: matches.is_present("probe-wav-loop"),
+            }
+        }
+        None if matches.is_present("timecode") => Probe::Timecode,
+        None if matches.is_present("burst-ms") => {
+            let duration_str = matches.value_of("burst-ms").unwrap_or("50");
+            let duration_ms = duration_str.parse::<f32>()?;
+            if duration_ms <= 0f32 {
+                anyhow::bail!("burst duration must be greater than 0ms");
+            }
+            Probe::Burst { duration_ms }
+        }
+        None => match matches.value_of("waveform") {
+            Some("square") => Probe::Square,
+            Some("click") => Probe::Click,
+            Some("noise") => {
+                let seed_str = matches.value_of("seed").unwrap_or("0");
+                Probe::Noise {
+                    duration_ms: NOISE_MS,
+                    seed: seed_str.parse::<u64>()?,
                 }
             }
-            let _ = signal_start2.compare_exchange(
-                0,
-                start_time.elapsed().as_nanos() as u64,
-                Ordering::SeqCst,
-                Ordering::Relaxed,
+            _ => Probe::Tone,
+        },
+    };
+    // A WAV file plays at the rate it was recorded at.
+    let requested_sample_rate = match probe {
+        Probe::Wav { sample_rate, .. } => requested_sample_rate.or(Some(sample_rate)),
+        _ => requested_sample_rate,
+    };
+    if ramp_ms > 0f32 && !matches!(probe, Probe::Tone | Probe::Square) {
+        anyhow::bail!("--ramp-ms only fades a held tone, not a click or noise");
+    }
+    let acoustic = matches.is_present("acoustic");
+    // A microphone hears noise right across the spectrum, so only listen at
+    // the probe's frequency unless told otherwise.
+    let detector_kind = if acoustic
+        && !matches.is_present("detector")
+        && matches!(probe, Probe::Tone | Probe::Square | Probe::Burst { .. })
+    {
+        Detector::Goertzel
+    } else {
+        detector_kind
+    };
+    if matches!(
+        probe,
+        Probe::Click | Probe::Noise { .. } | Probe::Timecode | Probe::Wav { .. }
+    ) {
+        // Only a detector asked for by name can be tuned here, as --acoustic
+        // leaves these probes alone.
+        if detector_kind.is_tuned() {
+            anyhow::bail!(
+                "the {} detector only works with a fixed tone, not a click, noise, timecode or WAV file",
+                matches.value_of("detector").unwrap_or_default()
             );
+        }
+        if stereo {
+            anyhow::bail!("--stereo only works with a held sine or square wave");
+        }
+    }
+    let json = matches.is_present("json");
+    let calibrate = matches.is_present("calibrate");
+    let count = match matches.value_of("count") {
+        Some(count_str) => Some(count_str.parse::<u64>()?),
+        None if calibrate => Some(CALIBRATION_COUNT),
+        None => None,
+    };
+    let duration = match matches.value_of("duration") {
+        Some(duration_str) => Some(Duration::try_from_secs_f64(duration_str.parse::<f64>()?)?),
+        None => None,
+    };
+    let offset_str = matches.value_of("offset-ms").unwrap_or("0");
+    let offset_ms = if calibrate {
+        0f32
+    } else {
+        offset_str.parse::<f32>()?
+    };
+    let distance_str = matches.value_of("distance").unwrap_or("0");
+    let distance = distance_str.parse::<f32>()?;
+    if distance < 0f32 {
+        anyhow::bail!("distance must not be negative");
+    }
+    // Still subtracted when calibrating, so the baseline is the same with or
+    // without the speaker moved.
+    let propagation_ms = distance / SPEED_OF_SOUND * 1000f32;
+    let offset_ms = offset_ms + propagation_ms;
+    Ok(Config {
+        virtual_loopback,
+        input_devices,
+        output_devices,
+        timestamps,
+        volume,
+        input_gain,
+        output_gain,
+        sensitivity,
+        hysteresis,
+        window_periods,
+        silence_level,
+        silence_noise,
+        ramp_ms,
+        probe_delay_ms,
+        averages,
+        frequency,
+        timeout,
+        requested_sample_rate,
+        requested_buffer_size,
+        low_latency,
+        units,
+        alpha,
+        interval,
+        average_count,
+        window_str,
+        window,
+        max_delay_ms,
+        fail_over_ms,
+        fail_on_smoothed,
+        drift_alarm,
+        min_confidence,
+        period,
+        warmup,
+        detector_kind,
+        input_channel,
+        stereo,
+        sweep_channels,
+        reference_channel,
+        output_channel,
+        quiet,
+        verbose,
+        histogram,
+        tui,
+        keys,
+        listen_only,
+        impulse,
+        sync_channel,
+        dry_run,
+        precise,
+        dc_block,
+        invert_detection,
+        dither,
+        strict,
+        reconnects,
+        probe,
+        acoustic,
+        json,
+        calibrate,
+        count,
+        duration,
+        distance,
+        propagation_ms,
+        offset_ms,
+    })
+}
+
+const EXIT_STATUS_HELP: &str = "EXIT STATUS:
+    0  At least one measurement was recorded, or with --count, all COUNT
+       measurements were recorded without any timeouts, or --dry-run found
+       no problems
+    1  No measurements were recorded, a ping timed out with --count, a
+       delay went over --fail-over, or an error occurred
+    2  Invalid command line arguments
+  130  Interrupted by Ctrl-C or SIGTERM before measuring started";
+
+/// Unsets the variable of any option that conflicts with an option given in
+/// `args`, such as `AUDIOPING_VOLUME` alongside `--volume-dbfs`, which clap
+/// would refuse rather than let the command line take precedence. Changing
+/// the environment is only sound before any other thread has started.
+fn drop_overridden_env(mut app: clap::Command, args: &[OsString]) {
+    // Built first, so that it has the same arguments the matches are for.
+    app.build();
+    let matches = app.clone().ignore_errors(true).get_matches_from(args);
+    let source = |arg: &clap::Arg| matches.value_source(arg.get_id());
+    let conflict = |a: &clap::Arg, b: &clap::Arg| {
+        app.get_arg_conflicts_with(a)
+            .iter()
+            .any(|arg| arg.get_id() == b.get_id())
+    };
+    for arg in app.get_arguments() {
+        let overridden = source(arg) == Some(clap::ValueSource::EnvVariable)
+            && app.get_arguments().any(|given| {
+                source(given) == Some(clap::ValueSource::CommandLine)
+                    && (conflict(arg, given) || conflict(given, arg))
+            });
+        if let Some(var) = arg.get_env().filter(|_| overridden) {
+            std::env::remove_var(var);
+        }
+    }
+}
+
+/// The FFT detector as configured by `--fft-size` and `--fft-overlap`.
+#[cfg(feature = "fft")]
+fn fft_detector(matches: &clap::ArgMatches) -> anyhow::Result<Detector> {
+    let size = matches
+        .value_of("fft-size")
+        .unwrap_or("1024")
+        .parse::<usize>()?;
+    if size < 16 {
+        anyhow::bail!("--fft-size must be at least 16 frames");
+    }
+    let overlap = matches
+        .value_of("fft-overlap")
+        .unwrap_or("0.75")
+        .parse::<f32>()?;
+    if !(0f32..1f32).contains(&overlap) {
+        anyhow::bail!("--fft-overlap must be from 0 up to, but not including, 1");
+    }
+    let hop = ((size as f32 * (1f32 - overlap)).round() as usize).max(1);
+    Ok(Detector::Fft { size, hop })
+}
+
+/// The WAV file at `path` as mono samples from -1.0 to 1.0, averaging its
+/// channels, and its sample rate.
+fn load_probe_wav(path: &Path) -> anyhow::Result<(Vec<f32>, u32)> {
+    let (samples, spec) = read_wav(path, "probe")?;
+    let channels = spec.channels.max(1) as usize;
+    let samples: Vec<f32> = samples
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+    if samples.is_empty() {
+        anyhow::bail!("probe {} has no samples", path.display());
+    }
+    Ok((samples, spec.sample_rate))
+}
+
+/// The WAV file at `path` as interleaved samples from -1.0 to 1.0, and its
+/// spec. `what` names the file in errors.
+fn read_wav(path: &Path, what: &str) -> anyhow::Result<(Vec<f32>, hound::WavSpec)> {
+    let mut reader = match hound::WavReader::open(path) {
+        Ok(reader) => reader,
+        Err(err) => anyhow::bail!("can't read {} {}: {}", what, path.display(), err),
+    };
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 / scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    Ok((samples, spec))
+}
+
+/// Without the `fft` feature there's no FFT detector to configure.
+#[cfg(not(feature = "fft"))]
+fn fft_detector(_matches: &clap::ArgMatches) -> anyhow::Result<Detector> {
+    anyhow::bail!("this build has no fft detector; rebuild audioping with --features fft")
+}
+
+/// Exit code for a Ctrl-C before measuring started, following the shell
+/// convention of 128 plus SIGINT.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// How often the live histogram is redrawn.
+const HISTOGRAM_REDRAW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Width in characters of the longest histogram bar.
+const HISTOGRAM_WIDTH: u64 = 50;
+
+/// Redraws the histogram of 1ms-wide delay buckets over the previous drawing,
+/// which was `previous_lines` tall. Returns the number of lines drawn.
+fn draw_histogram(buckets: &[u32], previous_lines: usize) -> usize {
+    let mut output = String::new();
+    if previous_lines > 0 {
+        // Move back up to the top of the previous drawing and clear it.
+        let _ = write!(output, "\r\x1b[{}A\x1b[J", previous_lines);
+    }
+
+    let first = buckets.iter().position(|count| *count > 0);
+    let last = buckets.iter().rposition(|count| *count > 0);
+    let mut lines = 0;
+    if let (Some(first), Some(last)) = (first, last) {
+        let max_count = buckets.iter().copied().max().unwrap_or(1) as u64;
+        for (ms, count) in buckets.iter().enumerate().take(last + 1).skip(first) {
+            let bar_length = (*count as u64 * HISTOGRAM_WIDTH).div_ceil(max_count) as usize;
+            let _ = writeln!(
+                output,
+                "{:5}-{:<5}ms {:6} {}",
+                ms,
+                ms + 1,
+                count,
+                "#".repeat(bar_length)
+            );
+            lines += 1;
+        }
+    }
+
+    print!("{}", output);
+    let _ = std::io::stdout().flush();
+    lines
+}
+
+/// Why a run stopped.
+#[derive(Clone, Copy)]
+enum StopReason {
+    Interrupted,
+    CountReached,
+    DurationElapsed,
+    Clipped,
+    FailedOver,
+    StreamFailed(Direction),
+}
+
+impl StopReason {
+    fn message(&self) -> &'static str {
+        match self {
+            StopReason::Interrupted => "Stopping: interrupted",
+            StopReason::CountReached => "Stopping: measurement count reached",
+            StopReason::DurationElapsed => "Stopping: duration elapsed",
+            StopReason::Clipped => "Stopping: input clipped",
+            StopReason::FailedOver => "Stopping: a delay went over the --fail-over limit",
+            StopReason::StreamFailed(Direction::Input) => "Stopping: the input stream failed",
+            StopReason::StreamFailed(Direction::Output) => "Stopping: the output stream failed",
+        }
+    }
+}
+
+/// Where progress and status messages are printed.
+#[derive(Clone, Copy)]
+struct Status {
+    /// Drop status messages entirely.
+    quiet: bool,
+    /// Print to stderr because stdout is carrying measurement data.
+    to_stderr: bool,
+}
+
+impl Status {
+    fn print(&self, message: &str) {
+        if self.quiet {
+            return;
+        }
+        if self.to_stderr {
+            eprintln!("{}", message);
         } else {
-            // Mute
-            for frame in data.chunks_mut(channels) {
-                for sample in frame.iter_mut() {
-                    *sample = 0f32;
-                }
+            println!("{}", message);
+        }
+    }
+}
+
+/// Prints every input and output device of `host` as a JSON array, along with
+/// the stream configurations each one supports.
+fn print_devices_json(host: &cpal::Host, listed: impl Fn(&str) -> bool) -> anyhow::Result<()> {
+    let default_input = host
+        .default_input_device()
+        .and_then(|device| device.name().ok());
+    let default_output = host
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+    let mut entries = Vec::new();
+    for (index, device) in host.input_devices()?.enumerate() {
+        let name = device.name()?;
+        if !listed(&name) {
+            continue;
+        }
+        let configs = match device.supported_input_configs() {
+            Ok(configs) => configs.collect(),
+            Err(_) => Vec::new(),
+        };
+        entries.push(device_json(&name, index, "input", &default_input, &configs));
+    }
+    for (index, device) in host.output_devices()?.enumerate() {
+        let name = device.name()?;
+        if !listed(&name) {
+            continue;
+        }
+        let configs = match device.supported_output_configs() {
+            Ok(configs) => configs.collect(),
+            Err(_) => Vec::new(),
+        };
+        entries.push(device_json(
+            &name,
+            index,
+            "output",
+            &default_output,
+            &configs,
+        ));
+    }
+    println!("[{}]", entries.join(","));
+    Ok(())
+}
+
+/// Starts a thread that writes each metrics text sent to it to `path`, as
+/// `write_metrics` does. Texts sent while it's still writing are skipped for
+/// the latest. The thread finishes once the `Writer` is dropped.
+fn spawn_metrics_writer(
+    path: PathBuf,
+) -> (writer::Writer<String>, JoinHandle<std::io::Result<()>>) {
+    writer::spawn_latest(
+        path,
+        |path, metrics: &String| {
+            // A failed write is left for the next measurement to retry.
+            let _ = write_metrics(path, metrics);
+            Ok(())
+        },
+        |_| Ok(()),
+    )
+}
+
+/// Replaces the Prometheus textfile at `path` with `metrics`. The file is
+/// written under a temporary name and renamed into place, so a collector
+/// never reads it half written.
+fn write_metrics(path: &Path, metrics: &str) -> std::io::Result<()> {
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = PathBuf::from(temp_name);
+    std::fs::write(&temp_path, metrics)?;
+    std::fs::rename(&temp_path, path)
+}
+
+/// The latest measurement in the Prometheus text exposition format.
+fn metrics_text(delay_ms: f32, trend: DelayTrend, measured: u64) -> String {
+    let mut writer = String::new();
+    let _ = write_metrics_text(&mut writer, delay_ms, trend, measured);
+    writer
+}
+
+fn write_metrics_text(
+    writer: &mut String,
+    delay_ms: f32,
+    trend: DelayTrend,
+    measured: u64,
+) -> std::fmt::Result {
+    writeln!(writer, "# HELP audioping_delay_ms Latest round-trip delay.")?;
+    writeln!(writer, "# TYPE audioping_delay_ms gauge")?;
+    writeln!(writer, "audioping_delay_ms {}", delay_ms)?;
+    writeln!(
+        writer,
+        "# HELP audioping_smoothed_delay_ms Moving average of the round-trip delay."
+    )?;
+    writeln!(writer, "# TYPE audioping_smoothed_delay_ms gauge")?;
+    writeln!(writer, "audioping_smoothed_delay_ms {}", trend.smoothed_ms)?;
+    writeln!(
+        writer,
+        "# HELP audioping_jitter_ms RFC 3550 jitter of the round-trip delay."
+    )?;
+    writeln!(writer, "# TYPE audioping_jitter_ms gauge")?;
+    writeln!(writer, "audioping_jitter_ms {}", trend.jitter_ms)?;
+    writeln!(
+        writer,
+        "# HELP audioping_measurements_total Measurements recorded."
+    )?;
+    writeln!(writer, "# TYPE audioping_measurements_total counter")?;
+    writeln!(writer, "audioping_measurements_total {}", measured)
+}
+
+/// Length of the probe played by `--waveform noise`.
+const NOISE_MS: f32 = 50.0;
+
+/// First line of `--csv` output, followed by `,time` with `--timestamps`,
+/// which must match to append to a file.
+const CSV_HEADER: &str =
+    "run_id,timestamp_ns,delay_ms,amplitude,cpal_delay_ms,smoothed_delay_ms,jitter_ms,confidence,output,input";
+
+/// Speed of sound in air at 20°C, in meters per second.
+const SPEED_OF_SOUND: f32 = 343.0;
+
+/// Number of measurements `--calibrate` takes unless `--count` is given.
+const CALIBRATION_COUNT: u64 = 20;
+
+/// How long `--auto-sensitivity` listens to the input for.
+const NOISE_FLOOR_DURATION: Duration = Duration::from_secs(1);
+
+/// Lowest sensitivity `--auto-sensitivity` picks, so that a digitally silent
+/// input doesn't trigger on the smallest rounding error.
+const MIN_AUTO_SENSITIVITY: f32 = 0.001;
+
+/// Listens to the input for `NOISE_FLOOR_DURATION`, with nothing playing,
+/// and returns the loudest the detector described by `detector` hears it to
+/// be, after the input gain and any DC blocking or reference channel, in the
+/// same units as the sensitivity.
+fn measure_noise_floor(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    format: cpal::SampleFormat,
+    detector: &DetectorConfig,
+) -> anyhow::Result<f32> {
+    let detector = LatencyDetector::new(DetectorConfig {
+        sample_rate: config.sample_rate.0 as f32,
+        input_channels: config.channels as usize,
+        ..detector.clone()
+    });
+    let mut input_state = detector.input_state();
+    let noise_floor = Arc::new(Mutex::new(0f32));
+    let input_noise_floor = Arc::clone(&noise_floor);
+    let data_fn = Arc::new(Mutex::new(
+        move |data: &[f32], _: &cpal::InputCallbackInfo| {
+            let amplitude = detector.noise_amplitude(&mut input_state, data);
+            let mut noise_floor = input_noise_floor.lock().unwrap();
+            *noise_floor = noise_floor.max(amplitude);
+        },
+    ));
+    // A failing device will fail again once the real streams are built, and
+    // report its errors then.
+    let (errors, _) = channel();
+    let stream = start_input_stream(device, config, format, &data_fn, &errors)?;
+    stream.play()?;
+    std::thread::sleep(NOISE_FLOOR_DURATION);
+    drop(stream);
+    let noise_floor = *noise_floor.lock().unwrap();
+    Ok(noise_floor)
+}
+
+/// Fails if `path` can't be written, without changing anything already
+/// there: an existing file is only opened to append to, and a missing one is
+/// removed again once created.
+fn check_writable(path: &Path) -> anyhow::Result<()> {
+    let existed = path.exists();
+    if let Err(err) = File::options().create(true).append(true).open(path) {
+        anyhow::bail!("can't write {}: {}", path.display(), err);
+    }
+    if !existed {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Formats one device for `print_devices_json`.
+fn device_json(
+    name: &str,
+    index: usize,
+    direction: &str,
+    default_name: &Option<String>,
+    configs: &[cpal::SupportedStreamConfigRange],
+) -> String {
+    let configs: Vec<String> = configs
+        .iter()
+        .map(|config| {
+            format!(
+                "{{\"channels\":{},\"sample_format\":\"{}\",\"min_sample_rate\":{},\"max_sample_rate\":{}}}",
+                config.channels(),
+                sample_format_name(config.sample_format()),
+                config.min_sample_rate().0,
+                config.max_sample_rate().0
+            )
+        })
+        .collect();
+    format!(
+        "{{\"name\":{},\"index\":{},\"direction\":\"{}\",\"default\":{},\"configs\":[{}]}}",
+        json_string(name),
+        index,
+        direction,
+        default_name.as_deref() == Some(name),
+        configs.join(",")
+    )
+}
+
+/// Formats one supported stream config for `--list-formats`.
+fn describe_config(config: &cpal::SupportedStreamConfigRange) -> String {
+    let mut description = format!(
+        "{}, {} channels, {}-{}Hz",
+        sample_format_name(config.sample_format()),
+        config.channels(),
+        config.min_sample_rate().0,
+        config.max_sample_rate().0
+    );
+    if let cpal::SupportedBufferSize::Range { min, max } = config.buffer_size() {
+        let _ = write!(description, ", {}-{} frame buffers", min, max);
+    }
+    description
+}
+
+fn sample_format_name(format: cpal::SampleFormat) -> &'static str {
+    match format {
+        cpal::SampleFormat::I16 => "i16",
+        cpal::SampleFormat::U16 => "u16",
+        cpal::SampleFormat::F32 => "f32",
+    }
+}
+
+/// Appends `measurement` to `line` as a JSON object, with the percentiles of
+/// the `--window` it ends and the spread of the delays it's the `--average`
+/// of, if any.
+fn write_measurement_json(
+    line: &mut String,
+    measurement: &Measurement,
+    trend: &DelayTrend,
+    window: Option<&Summary>,
+    average_stddev_ms: Option<f32>,
+    device: Option<(&str, &str)>,
+    time: Option<&str>,
+) {
+    let _ = write!(
+        line,
+        "{{\"t_ns\":{},\"delay_ms\":{},\"smoothed_ms\":{},\"jitter_ms\":{},\"amplitude\":{},\"confidence\":{}",
+        measurement.timestamp_ns,
+        measurement.delay_ms,
+        trend.smoothed_ms,
+        trend.jitter_ms,
+        measurement.amplitude,
+        measurement.confidence
+    );
+    if let Some(stream_delay_ms) = measurement.stream_delay_ms {
+        let _ = write!(line, ",\"cpal_delay_ms\":{}", stream_delay_ms);
+    }
+    if let Some(compare_delay_ms) = measurement.compare_delay_ms {
+        let _ = write!(line, ",\"right_delay_ms\":{}", compare_delay_ms);
+    }
+    if let Some(timecode_us) = measurement.timecode_us {
+        let _ = write!(line, ",\"timecode_us\":{}", timecode_us);
+    }
+    if let Some(scheduling_jitter_ms) = measurement.scheduling_jitter_ms {
+        let _ = write!(line, ",\"scheduling_jitter_ms\":{}", scheduling_jitter_ms);
+    }
+    if measurement.inverted {
+        line.push_str(",\"inverted\":true");
+    }
+    if let Some(window) = window {
+        let _ = write!(
+            line,
+            ",\"window_p50_ms\":{},\"window_p95_ms\":{},\"window_p99_ms\":{}",
+            window.median, window.p95, window.p99
+        );
+    }
+    if let Some(average_stddev_ms) = average_stddev_ms {
+        let _ = write!(line, ",\"average_stddev_ms\":{}", average_stddev_ms);
+    }
+    if let Some((kind, name)) = device {
+        let _ = write!(line, ",\"{}\":{}", kind, json_string(name));
+    }
+    if let Some(channel) = measurement.output_channel {
+        let _ = write!(line, ",\"channel\":{}", channel);
+    }
+    if let Some(time) = time {
+        let _ = write!(line, ",\"time\":\"{}\"", time);
+    }
+    line.push('}');
+}
+
+/// The current time at `offset` from UTC, in RFC 3339 format.
+fn wall_clock_time(offset: time::UtcOffset) -> String {
+    time::OffsetDateTime::now_utc()
+        .to_offset(offset)
+        .format(&time::format_description::well_known::Rfc3339)
+        .unwrap_or_default()
+}
+
+/// Quotes and escapes `value` as a JSON string.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
             }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Finds the device called `name`, or numbered `name` in `--list`, or fails
+/// with a hint naming the closest match. A device whose name is a number is
+/// found by its name first.
+fn find_device(
+    devices: impl Iterator<Item = cpal::Device>,
+    name: &str,
+    direction: &str,
+) -> anyhow::Result<cpal::Device> {
+    let devices: Vec<cpal::Device> = devices.collect();
+    let mut closest: Option<(usize, String)> = None;
+    let mut found = None;
+    for (index, device) in devices.iter().enumerate() {
+        let device_name = match device.name() {
+            Ok(device_name) => device_name,
+            Err(_) => continue,
+        };
+        if device_name == name {
+            found = Some(index);
+            break;
+        }
+        let distance = edit_distance(&device_name.to_lowercase(), &name.to_lowercase());
+        if closest.as_ref().is_none_or(|(best, _)| distance < *best) {
+            closest = Some((distance, device_name));
         }
+    }
+
+    if let Some(index) = found {
+        return Ok(devices.into_iter().nth(index).unwrap());
+    }
+    if let Ok(index) = name.parse::<usize>() {
+        let count = devices.len();
+        return match devices.into_iter().nth(index) {
+            Some(device) => Ok(device),
+            None => anyhow::bail!(
+                "no {} device {}, there are {}. Run with --list to see all devices",
+                direction,
+                index,
+                count
+            ),
+        };
+    }
+    match closest {
+        Some((_, suggestion)) => anyhow::bail!(
+            "no {} device named \"{}\", did you mean \"{}\"? Run with --list to see all devices",
+            direction,
+            name,
+            suggestion
+        ),
+        None => anyhow::bail!(
+            "no {} device named \"{}\". Run with --list to see all devices",
+            direction,
+            name
+        ),
+    }
+}
+
+/// Levenshtein distance between two strings, counted in chars.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = diagonal + (a_char != *b_char) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Picks the device's default config for `direction`, or if `sample_rate` is
+/// given, a supported config running at that rate, preferring the default
+/// sample format and channel count. With `low_latency`, the config supporting
+/// the smallest buffers is preferred over either.
+fn select_config(
+    device: &cpal::Device,
+    direction: Direction,
+    sample_rate: Option<u32>,
+    low_latency: bool,
+) -> anyhow::Result<cpal::SupportedStreamConfig> {
+    let default_config = match direction {
+        Direction::Input => device.default_input_config(),
+        Direction::Output => device.default_output_config(),
+    };
+    let default_config = match default_config {
+        Ok(config) => config,
+        // Typical of machines without audio hardware, such as CI runners,
+        // where only --self-test can run.
+        Err(err) => anyhow::bail!(
+            "no usable {} config on \"{}\"; check that it's connected and not in use, pick another with --{} (see --list), or run --self-test, which needs no audio devices ({})",
+            direction.name(),
+            device.name().unwrap_or_default(),
+            direction.name(),
+            err
+        ),
+    };
+    let sample_rate = match sample_rate {
+        Some(sample_rate) if sample_rate != default_config.sample_rate().0 => sample_rate,
+        _ if low_latency => default_config.sample_rate().0,
+        _ => return Ok(default_config),
     };
 
+    let mut ranges: Vec<_> = match direction {
+        Direction::Input => device.supported_input_configs()?.collect(),
+        Direction::Output => device.supported_output_configs()?.collect(),
+    };
+    ranges.sort_by_key(|range| {
+        (
+            low_latency.then(|| match range.buffer_size() {
+                cpal::SupportedBufferSize::Range { min, .. } => *min,
+                cpal::SupportedBufferSize::Unknown => u32::MAX,
+            }),
+            range.sample_format() != default_config.sample_format(),
+            range.channels() != default_config.channels(),
+        )
+    });
+    match ranges.iter().find(|range| {
+        range.min_sample_rate().0 <= sample_rate && sample_rate <= range.max_sample_rate().0
+    }) {
+        Some(range) => Ok(range
+            .clone()
+            .with_sample_rate(cpal::SampleRate(sample_rate))),
+        None => {
+            let supported: Vec<String> = ranges
+                .iter()
+                .map(|range| {
+                    format!(
+                        "{}-{}Hz",
+                        range.min_sample_rate().0,
+                        range.max_sample_rate().0
+                    )
+                })
+                .collect();
+            anyhow::bail!(
+                "sample rate {}Hz is not supported by \"{}\", supported: {}",
+                sample_rate,
+                device.name()?,
+                supported.join(", ")
+            )
+        }
+    }
+}
+
+/// Asks for the next ping, moving it on to the next output channel first when
+/// sweeping through them.
+fn request_ping(session: &mut Session, channel_sweep: Option<&mut ChannelSweep>) {
+    if let Some(sweep) = channel_sweep {
+        sweep.advance(&session.detectors()[0]);
+    }
+    session.request_ping();
+}
+
+/// Formats `summary` as a JSON object.
+fn summary_json(summary: &Summary) -> String {
+    format!(
+        "{{\"count\":{},\"min_ms\":{},\"max_ms\":{},\"mean_ms\":{},\"median_ms\":{},\"stddev_ms\":{},\"p95_ms\":{},\"p99_ms\":{},\"ci95_ms\":{},\"jitter_ms\":{},\"drift_ms_per_hour\":{}}}",
+        summary.count,
+        summary.min,
+        summary.max,
+        summary.mean,
+        summary.median,
+        summary.stddev,
+        summary.p95,
+        summary.p99,
+        summary
+            .ci95
+            .map_or("null".to_string(), |ci95| ci95.to_string()),
+        summary.jitter,
+        summary
+            .drift_ms_per_hour
+            .map_or("null".to_string(), |drift| drift.to_string())
+    )
+}
+
+/// Prints aggregate statistics over all measured delays, similar to `ping`.
+fn print_summary(summary: Option<&Summary>, json: bool) {
+    let summary = match summary {
+        Some(summary) => summary,
+        None if json => {
+            println!("{{\"count\":0}}");
+            return;
+        }
+        None => {
+            println!("No measurements recorded");
+            return;
+        }
+    };
+
+    if json {
+        println!("{}", summary_json(summary));
+        return;
+    }
+
+    println!("--- {} measurements ---", summary.count);
     println!(
-        "Attempting to build both streams with f32 samples and `{:?}`.",
-        config
+        "min/max/mean/median/stddev = {:.2}/{:.2}/{:.2}/{:.2}/{:.2}ms",
+        summary.min, summary.max, summary.mean, summary.median, summary.stddev
     );
-    let input_stream = input.build_input_stream(&config, input_data_fn, err_fn)?;
-    let output_stream = output.build_output_stream(&config, output_data_fn, err_fn)?;
-    println!("Successfully built streams.");
-
-    println!("Starting the input and output streams");
-    output_stream.play()?;
-    input_stream.play()?;
-
-    println!("Measuring latency... Press Ctrl-C to stop");
-    rx.recv().expect("Could not receive from channel.");
-    drop(input_stream);
-    drop(output_stream);
-    println!("Done!");
-    Ok(())
+    println!("p95/p99 = {:.2}/{:.2}ms", summary.p95, summary.p99);
+    if let Some(ci95) = summary.ci95 {
+        println!(
+            "mean 95% CI = {:.2} +/- {:.2}ms ({:.2} to {:.2}ms)",
+            summary.mean,
+            ci95,
+            summary.mean - ci95,
+            summary.mean + ci95
+        );
+    }
+    println!("jitter = {:.2}ms", summary.jitter);
+    if let Some(drift) = summary.drift_ms_per_hour {
+        println!("drift = {:+.2}ms/hour", drift);
+    }
 }
 
-fn err_fn(err: cpal::StreamError) {
-    eprintln!("an error occurred on stream: {}", err);
+/// Prints each input's delays side by side, with how far its median is from
+/// the first input's.
+fn print_input_summaries(names: &[String], delays: &[DelayStats], json: bool) {
+    let summaries: Vec<Option<Summary>> = delays.iter().map(DelayStats::summary).collect();
+    let first_median = summaries[0].as_ref().map(|summary| summary.median);
+    if json {
+        let inputs: Vec<String> = names
+            .iter()
+            .zip(&summaries)
+            .map(|(name, summary)| match summary {
+                Some(summary) => format!(
+                    "{{\"input\":{},\"count\":{},\"median_ms\":{},\"mean_ms\":{},\"stddev_ms\":{}}}",
+                    json_string(name),
+                    summary.count,
+                    summary.median,
+                    summary.mean,
+                    summary.stddev
+                ),
+                None => format!("{{\"input\":{},\"count\":0}}", json_string(name)),
+            })
+            .collect();
+        println!("{{\"inputs\":[{}]}}", inputs.join(","));
+        return;
+    }
+
+    println!("--- {} inputs ---", names.len());
+    for (index, (name, summary)) in names.iter().zip(&summaries).enumerate() {
+        let summary = match summary {
+            Some(summary) => summary,
+            None => {
+                println!("\"{}\": no measurements", name);
+                continue;
+            }
+        };
+        let mut line = format!(
+            "\"{}\": {} measurements, median/mean/stddev = {:.2}/{:.2}/{:.2}ms",
+            name, summary.count, summary.median, summary.mean, summary.stddev
+        );
+        if let Some(first_median) = first_median.filter(|_| index > 0) {
+            let _ = write!(
+                line,
+                ", {:+.2}ms from the first",
+                summary.median - first_median
+            );
+        }
+        println!("{}", line);
+    }
 }
@@ -0,0 +1,213 @@
+//! What each measurement goes through between the detector and being
+//! printed, the same for a live run as for `--analyze`.
+
+use audioping::{DelayTrend, Measurement, MeasurementAverager, Summary};
+use std::fmt::Write as _;
+
+/// Printed in place of a negative delay.
+pub const NEGATIVE_DELAY: &str = "Negative delay detected (check wiring / clock)";
+
+/// Leaves out negative delays, takes the `--offset-ms` off each measurement,
+/// discards those over `--max-delay` or under `--min-confidence`, and
+/// averages the rest over `--average`.
+pub struct MeasurementFilter {
+    offset_ms: f32,
+    max_delay_ms: Option<f32>,
+    min_confidence: f32,
+    averager: Option<MeasurementAverager>,
+}
+
+/// What became of a measurement passed through a `MeasurementFilter`.
+pub enum Filtered {
+    /// To be reported, with the spread of the delays it's the `--average`
+    /// of, if any.
+    Kept(Measurement, Option<f32>),
+    /// Held until the rest of its `--average` has been measured.
+    Averaging,
+    /// Left out, as the delay came out negative before the `--offset-ms` was
+    /// taken off it.
+    Negative,
+    /// Left out by `--max-delay` or `--min-confidence`, for the reason given.
+    Discarded(String),
+}
+
+impl MeasurementFilter {
+    pub fn new(
+        offset_ms: f32,
+        max_delay_ms: Option<f32>,
+        min_confidence: f32,
+        average_count: usize,
+    ) -> MeasurementFilter {
+        MeasurementFilter {
+            offset_ms,
+            max_delay_ms,
+            min_confidence,
+            averager: (average_count > 1).then(|| MeasurementAverager::new(average_count)),
+        }
+    }
+
+    /// Forgets any part of an average measured so far.
+    pub fn reset(&mut self) {
+        if let Some(averager) = &mut self.averager {
+            averager.reset();
+        }
+    }
+
+    pub fn push(&mut self, mut measurement: Measurement) -> Filtered {
+        // Checked before the offset: a probe heard before it was played means
+        // a wiring or clock problem, while a delay only the offset takes under
+        // zero is an offset larger than the loop's latency, and is kept.
+        if measurement.delay_ms < 0.0 {
+            return Filtered::Negative;
+        }
+        let offset_ms = self.offset_ms;
+        measurement.delay_ms -= offset_ms;
+        measurement.stream_delay_ms = measurement
+            .stream_delay_ms
+            .map(|delay_ms| delay_ms - offset_ms);
+        measurement.compare_delay_ms = measurement
+            .compare_delay_ms
+            .map(|delay_ms| delay_ms - offset_ms);
+        if let Some(max_delay_ms) = self.max_delay_ms.filter(|max| measurement.delay_ms > *max) {
+            return Filtered::Discarded(format!(
+                "Discarded a delay of {:.2}ms, over the {}ms limit",
+                measurement.delay_ms, max_delay_ms
+            ));
+        }
+        if measurement.confidence < self.min_confidence {
+            return Filtered::Discarded(format!(
+                "Discarded a delay of {:.2}ms, its confidence of {:.2} is under {}",
+                measurement.delay_ms, measurement.confidence, self.min_confidence
+            ));
+        }
+        match &mut self.averager {
+            Some(averager) => match averager.push(measurement) {
+                Some((average, stddev_ms)) => Filtered::Kept(average, Some(stddev_ms)),
+                None => Filtered::Averaging,
+            },
+            None => Filtered::Kept(measurement, None),
+        }
+    }
+}
+
+/// How measurements are printed as text.
+pub struct LineFormat {
+    /// Decimals each delay is printed with.
+    pub decimals: usize,
+    /// "ms", "samples" or "both".
+    pub units: String,
+    pub sample_rate: f32,
+    /// Whether to say which clock each ping was timed by, for a timecode.
+    pub clock: bool,
+    /// Whether to say which output channel each ping was played on.
+    pub channel: bool,
+    /// How many delays each one printed is the average of.
+    pub average_count: usize,
+    /// The `--window`, as given.
+    pub window: String,
+}
+
+/// Appends `measurement` to `line` as text, with the percentiles of the
+/// `--window` it ends, the spread of the delays it's the `--average` of and
+/// the device that measured it, if any.
+pub fn write_measurement_line(
+    line: &mut String,
+    measurement: &Measurement,
+    trend: &DelayTrend,
+    window: Option<&Summary>,
+    average_stddev_ms: Option<f32>,
+    device: Option<(&str, &str)>,
+    format: &LineFormat,
+) {
+    let decimals = format.decimals;
+    let delay_samples = (measurement.delay_ms * format.sample_rate / 1000.0).round() as i64;
+    let _ = match format.units.as_str() {
+        "ms" => write!(line, "wall_delay: {:8.*}ms", decimals, measurement.delay_ms),
+        "samples" => write!(line, "wall_delay: {:7} samples", delay_samples),
+        _ => write!(
+            line,
+            "wall_delay: {:8.*}ms {:7} samples",
+            decimals, measurement.delay_ms, delay_samples
+        ),
+    };
+    if let Some(stream_delay_ms) = measurement.stream_delay_ms {
+        let _ = write!(line, ", cpal_delay: {:8.*}ms", decimals, stream_delay_ms);
+    }
+    let _ = write!(
+        line,
+        ", smoothed: {:8.*}ms, jitter: {:.*}ms",
+        decimals, trend.smoothed_ms, decimals, trend.jitter_ms
+    );
+    if format.clock {
+        let clock = match measurement.timecode_us {
+            Some(_) => "timecode",
+            None => "callback",
+        };
+        let _ = write!(line, ", clock: {}", clock);
+    }
+    if let Some(stddev_ms) = average_stddev_ms {
+        let _ = write!(
+            line,
+            ", stddev of {}: {:.*}ms",
+            format.average_count, decimals, stddev_ms
+        );
+    }
+    if let Some(window) = window {
+        let _ = write!(
+            line,
+            ", last {}s p50/p95/p99: {:.*}/{:.*}/{:.*}ms",
+            format.window, decimals, window.median, decimals, window.p95, decimals, window.p99
+        );
+    }
+    if let Some((kind, name)) = device {
+        let _ = write!(line, ", {}: \"{}\"", kind, name);
+    }
+    if let Some(channel) = measurement.output_channel.filter(|_| format.channel) {
+        let _ = write!(line, ", channel: {}", channel);
+    }
+    let _ = write!(line, ", Signal: {:.3}", measurement.amplitude);
+    let _ = write!(line, ", confidence: {:.2}", measurement.confidence);
+    if measurement.inverted {
+        line.push_str(", inverted");
+    }
+    if measurement.scheduling_jitter_ms.is_some() {
+        line.push_str(" *");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(delay_ms: f32) -> Measurement {
+        Measurement {
+            timestamp_ns: 0,
+            delay_ms,
+            stream_delay_ms: None,
+            amplitude: 1.0,
+            compare_delay_ms: None,
+            timecode_us: None,
+            scheduling_jitter_ms: None,
+            confidence: 1.0,
+            inverted: false,
+            output: 0,
+            input: 0,
+            output_channel: None,
+        }
+    }
+
+    #[test]
+    fn negative_delays_are_caught_before_the_offset() {
+        let mut filter = MeasurementFilter::new(5.0, Some(20.0), 0.0, 1);
+        assert!(matches!(filter.push(measurement(-1.0)), Filtered::Negative));
+        // Only the offset takes this one under zero, so it's reported.
+        match filter.push(measurement(3.0)) {
+            Filtered::Kept(measurement, None) => assert_eq!(measurement.delay_ms, -2.0),
+            _ => panic!("a delay under the offset was left out"),
+        }
+        assert!(matches!(
+            filter.push(measurement(30.0)),
+            Filtered::Discarded(_)
+        ));
+    }
+}
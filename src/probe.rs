@@ -0,0 +1,154 @@
+//! Probe signals emitted by the output for each ping.
+
+use crate::timecode;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+/// How a sweep's frequency moves from its start to its end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SweepScale {
+    Linear,
+    Logarithmic,
+}
+
+/// Shape of the signal emitted for each ping.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Probe {
+    /// A sine at `DetectorConfig::frequency`, held until the ping is detected.
+    Tone,
+    /// A square wave at `DetectorConfig::frequency`, held until the ping is
+    /// detected.
+    Square,
+    /// A single full-scale impulse.
+    Click,
+    /// White noise from the pseudo-random sequence picked by `seed`, played
+    /// in full for every ping.
+    Noise { duration_ms: f32, seed: u64 },
+    /// A chirp from `start_hz` to `end_hz`, played in full for every ping.
+    Sweep {
+        start_hz: f32,
+        end_hz: f32,
+        duration_ms: f32,
+        scale: SweepScale,
+    },
+    /// A sine at `DetectorConfig::frequency` shaped by a raised-cosine
+    /// envelope, played in full for every ping.
+    Burst { duration_ms: f32 },
+    /// An FSK timecode of when the ping was emitted, played in full for every
+    /// ping. `Probe::value` gives the timecode for a time of 0.
+    Timecode,
+    /// Samples loaded from a WAV file recorded at `sample_rate`, played in
+    /// full for every ping, or over and over until the ping is detected if
+    /// `looped`.
+    Wav {
+        samples: Arc<[f32]>,
+        sample_rate: u32,
+        looped: bool,
+    },
+}
+
+impl Probe {
+    /// Length of the probe in frames, or `None` if it is held until the ping
+    /// is detected.
+    pub fn duration_frames(&self, sample_rate: f32) -> Option<u64> {
+        match *self {
+            Probe::Tone | Probe::Square | Probe::Wav { looped: true, .. } => None,
+            Probe::Click => Some(1),
+            Probe::Wav { ref samples, .. } => Some(samples.len() as u64),
+            Probe::Timecode => Some(timecode::frame_frames(sample_rate)),
+            Probe::Sweep { duration_ms, .. }
+            | Probe::Burst { duration_ms }
+            | Probe::Noise { duration_ms, .. } => {
+                Some((duration_ms as f64 * sample_rate as f64 / 1000.0).round() as u64)
+            }
+        }
+    }
+
+    /// Value of the probe, from -1.0 to 1.0, at frame `clock` of a ping,
+    /// counting the ping's first frame as 1.
+    pub fn value(&self, frequency: f32, sample_rate: f32, clock: u64) -> f32 {
+        let sample_rate = sample_rate as f64;
+        match *self {
+            Probe::Tone => (clock as f64 * 2.0 * PI * frequency as f64 / sample_rate).sin() as f32,
+            Probe::Square => {
+                if (clock as f64 * 2.0 * PI * frequency as f64 / sample_rate).sin() >= 0.0 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Probe::Click => {
+                if clock == 1 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Probe::Noise { seed, .. } => {
+                if clock == 0 || Some(clock) > self.duration_frames(sample_rate as f32) {
+                    return 0.0;
+                }
+                // The top 24 bits of the clock'th SplitMix64 output from
+                // `seed`, scaled to -1.0 to 1.0.
+                let mut x = seed.wrapping_add(clock.wrapping_mul(0x9e3779b97f4a7c15));
+                x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+                x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+                x ^= x >> 31;
+                (x >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+            }
+            Probe::Sweep {
+                start_hz,
+                end_hz,
+                duration_ms,
+                scale,
+            } => {
+                if clock == 0 || Some(clock) > self.duration_frames(sample_rate as f32) {
+                    return 0.0;
+                }
+                let (start_hz, end_hz) = (start_hz as f64, end_hz as f64);
+                let duration = duration_ms as f64 / 1000.0;
+                let t = (clock - 1) as f64 / sample_rate;
+                let cycles = match scale {
+                    SweepScale::Linear => {
+                        start_hz * t + (end_hz - start_hz) * t * t / (2.0 * duration)
+                    }
+                    SweepScale::Logarithmic => {
+                        let rate = (end_hz / start_hz).ln() / duration;
+                        if rate == 0.0 {
+                            start_hz * t
+                        } else {
+                            start_hz * ((rate * t).exp() - 1.0) / rate
+                        }
+                    }
+                };
+                (2.0 * PI * cycles).sin() as f32
+            }
+            Probe::Burst { .. } => {
+                let duration_frames = match self.duration_frames(sample_rate as f32) {
+                    Some(frames) if clock != 0 && clock <= frames => frames,
+                    _ => return 0.0,
+                };
+                let phase = (clock - 1) as f64 / duration_frames as f64;
+                let envelope = 0.5 * (1.0 - (2.0 * PI * phase).cos());
+                let tone = (clock as f64 * 2.0 * PI * frequency as f64 / sample_rate).sin();
+                (envelope * tone) as f32
+            }
+            Probe::Timecode => timecode::value(timecode::encode(0), sample_rate as f32, clock),
+            Probe::Wav {
+                ref samples,
+                looped,
+                ..
+            } => {
+                if clock == 0 || samples.is_empty() {
+                    return 0.0;
+                }
+                let index = (clock - 1) as usize;
+                if looped {
+                    samples[index % samples.len()]
+                } else {
+                    samples.get(index).copied().unwrap_or_default()
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Named sets of command line options, saved as TOML under the user's config
+//! directory.
+
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+/// The options a profile can hold. Anything left unset falls back to the
+/// command line or its default.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Profile {
+    pub host: Option<String>,
+    pub input: Option<String>,
+    /// Older profiles hold a single output device rather than a list.
+    #[serde(
+        default,
+        deserialize_with = "one_or_more",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub output: Vec<String>,
+    pub frequency: Option<f32>,
+    pub volume: Option<f32>,
+    pub sensitivity: Option<f32>,
+    pub sample_rate: Option<u32>,
+    pub buffer_size: Option<u32>,
+    pub in_channel: Option<usize>,
+    pub out_channel: Option<usize>,
+    pub offset_ms: Option<f32>,
+}
+
+impl Profile {
+    /// Collects the profile options given in `matches`.
+    pub fn from_matches(matches: &clap::ArgMatches) -> anyhow::Result<Profile> {
+        Ok(Profile {
+            host: matches.value_of("host").map(str::to_owned),
+            // A loopback device is saved as both the input and the output.
+            input: matches
+                .value_of("input")
+                .or(matches.value_of("loopback-device"))
+                .map(str::to_owned),
+            output: matches
+                .values_of("output")
+                .or(matches.values_of("loopback-device"))
+                .map(|names| names.map(str::to_owned).collect())
+                .unwrap_or_default(),
+            frequency: matches.value_of("frequency").map(str::parse).transpose()?,
+            volume: match matches.value_of("volume-dbfs") {
+                // Saved on the --volume scale so profiles only need the one form.
+                Some(db_str) => Some(10f32.powf(db_str.parse::<f32>()? / 20.0) * 100.0),
+                None => matches.value_of("volume").map(str::parse).transpose()?,
+            },
+            sensitivity: matches
+                .value_of("sensitivity")
+                .map(str::parse)
+                .transpose()?,
+            sample_rate: matches
+                .value_of("sample-rate")
+                .map(str::parse)
+                .transpose()?,
+            buffer_size: matches
+                .value_of("buffer-size")
+                .map(str::parse)
+                .transpose()?,
+            in_channel: matches.value_of("in-channel").map(str::parse).transpose()?,
+            out_channel: matches
+                .value_of("out-channel")
+                .map(str::parse)
+                .transpose()?,
+            offset_ms: matches.value_of("offset-ms").map(str::parse).transpose()?,
+        })
+    }
+
+    /// Reads the profile called `name`.
+    pub fn load(name: &str) -> anyhow::Result<Profile> {
+        let path = profile_path(name)?;
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) => anyhow::bail!(
+                "can't read profile \"{}\" from {}: {}",
+                name,
+                path.display(),
+                err
+            ),
+        };
+        Ok(toml::from_str(&text)?)
+    }
+
+    /// Writes the profile as `name`, replacing any existing profile with that
+    /// name, and returns where it was written.
+    pub fn save(&self, name: &str) -> anyhow::Result<PathBuf> {
+        let path = profile_path(name)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, toml::to_string(self)?)?;
+        Ok(path)
+    }
+
+    /// The profile as command line arguments, leaving out any already given
+    /// in `matches` so that those take precedence.
+    pub fn to_args(&self, matches: &clap::ArgMatches) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut push = |name: &str, value: Option<String>| {
+            if let Some(value) = value.filter(|_| !matches.is_present(name)) {
+                args.push(format!("--{}={}", name, value));
+            }
+        };
+        push("host", self.host.clone());
+        // A loopback device on the command line replaces both.
+        if !matches.is_present("loopback-device") {
+            push("input", self.input.clone());
+            for name in &self.output {
+                push("output", Some(name.clone()));
+            }
+        }
+        push("frequency", self.frequency.map(|x| x.to_string()));
+        // Either form of the volume on the command line replaces the profile's.
+        push(
+            "volume",
+            self.volume
+                .filter(|_| !matches.is_present("volume-dbfs"))
+                .map(|x| x.to_string()),
+        );
+        push("sensitivity", self.sensitivity.map(|x| x.to_string()));
+        push("sample-rate", self.sample_rate.map(|x| x.to_string()));
+        push("buffer-size", self.buffer_size.map(|x| x.to_string()));
+        push("in-channel", self.in_channel.map(|x| x.to_string()));
+        push("out-channel", self.out_channel.map(|x| x.to_string()));
+        push("offset-ms", self.offset_ms.map(|x| x.to_string()));
+        args
+    }
+}
+
+/// Reads either a single string or a list of them.
+fn one_or_more<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMore {
+        One(String),
+        More(Vec<String>),
+    }
+    Ok(match OneOrMore::deserialize(deserializer)? {
+        OneOrMore::One(name) => vec![name],
+        OneOrMore::More(names) => names,
+    })
+}
+
+/// Where the profile called `name` is stored.
+fn profile_path(name: &str) -> anyhow::Result<PathBuf> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        anyhow::bail!("invalid profile name \"{}\"", name);
+    }
+    let dir = match dirs::config_dir() {
+        Some(dir) => dir,
+        None => anyhow::bail!("can't find a config directory to keep profiles in"),
+    };
+    Ok(dir.join("audioping").join(format!("{}.toml", name)))
+}
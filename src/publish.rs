@@ -0,0 +1,121 @@
+//! Pushes each measurement, as a JSON line, to every client connected to a
+//! TCP or Unix socket listener, for `--publish`.
+
+use std::io::Write;
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long a write to a client may block before the client is dropped, so
+/// that one stalled client can't hold up the others.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+type Clients = Arc<Mutex<Vec<Box<dyn Write + Send>>>>;
+
+/// Starts listening on `addr`, either `tcp://HOST:PORT`, or `unix://PATH` or
+/// a bare path for a Unix socket. Each line sent on the returned channel is
+/// written to every connected client; clients whose writes fail are dropped.
+pub fn spawn(addr: &str) -> anyhow::Result<Sender<String>> {
+    let clients: Clients = Arc::default();
+    match addr.strip_prefix("tcp://") {
+        Some(tcp_addr) => {
+            let listener = TcpListener::bind(tcp_addr)?;
+            let accept_clients = Arc::clone(&clients);
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    if stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_ok() {
+                        accept_clients.lock().unwrap().push(Box::new(stream));
+                    }
+                }
+            });
+        }
+        None => listen_unix(addr.strip_prefix("unix://").unwrap_or(addr), &clients)?,
+    }
+
+    let (tx, rx) = channel::<String>();
+    std::thread::spawn(move || {
+        for mut line in rx {
+            line.push('\n');
+            clients
+                .lock()
+                .unwrap()
+                .retain_mut(|client| client.write_all(line.as_bytes()).is_ok());
+        }
+    });
+    Ok(tx)
+}
+
+/// Fails if `addr` couldn't be listened on, without binding it, for
+/// `--dry-run`: a TCP address has to resolve, and a Unix socket needs a
+/// directory to go in and nothing but an old socket in its place.
+pub fn check(addr: &str) -> anyhow::Result<()> {
+    match addr.strip_prefix("tcp://") {
+        Some(tcp_addr) => {
+            if let Err(err) = tcp_addr.to_socket_addrs() {
+                anyhow::bail!("can't publish on {}: {}", addr, err);
+            }
+            Ok(())
+        }
+        None => check_unix(addr.strip_prefix("unix://").unwrap_or(addr)),
+    }
+}
+
+#[cfg(unix)]
+fn check_unix(path: &str) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let path = std::path::Path::new(path);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    if !dir.is_dir() {
+        anyhow::bail!(
+            "can't publish on {}: {} is not a directory",
+            path.display(),
+            dir.display()
+        );
+    }
+    if std::fs::metadata(path).is_ok_and(|metadata| !metadata.file_type().is_socket()) {
+        anyhow::bail!(
+            "can't publish on {}: it already exists and is not a socket",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_unix(path: &str) -> anyhow::Result<()> {
+    listen_unix(path, &Clients::default())
+}
+
+#[cfg(unix)]
+fn listen_unix(path: &str, clients: &Clients) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixListener;
+
+    // A socket left behind by an earlier run would stop this one binding.
+    if std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let accept_clients = Arc::clone(clients);
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_ok() {
+                accept_clients.lock().unwrap().push(Box::new(stream));
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn listen_unix(path: &str, _clients: &Clients) -> anyhow::Result<()> {
+    anyhow::bail!(
+        "Unix sockets aren't supported on this platform, use tcp://HOST:PORT instead of {}",
+        path
+    )
+}
@@ -0,0 +1,44 @@
+//! Writes the captured input to a WAV file for `--record` on a thread of its
+//! own, so that the input callback only hands over a copy of each buffer.
+
+use crate::writer::{self, Writer};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::thread::JoinHandle;
+
+/// The input callback's end of a recording.
+pub struct Recorder {
+    blocks: Writer<Vec<f32>>,
+}
+
+impl Recorder {
+    /// Queues `data` to be written.
+    pub fn write(&mut self, data: &[f32]) {
+        self.blocks.send(|block| {
+            block.clear();
+            block.extend_from_slice(data);
+        });
+    }
+}
+
+/// Creates the WAV file at `path` and starts the thread that writes it. Once
+/// the `Recorder` is dropped, the thread writes what's left and finalizes the
+/// file, which joining the returned handle waits for.
+pub fn spawn(
+    path: &Path,
+    spec: hound::WavSpec,
+) -> anyhow::Result<(Recorder, JoinHandle<hound::Result<()>>)> {
+    let file = hound::WavWriter::<BufWriter<File>>::create(path, spec)?;
+    let (blocks, thread) = writer::spawn(
+        file,
+        |file, block: &Vec<f32>| {
+            for sample in block {
+                file.write_sample(*sample)?;
+            }
+            Ok(())
+        },
+        hound::WavWriter::finalize,
+    );
+    Ok((Recorder { blocks }, thread))
+}
@@ -0,0 +1,138 @@
+//! A human-readable summary of a run, written as Markdown with `--report`.
+
+use crate::run_info::RunInfo;
+use audioping::{Probe, Summary};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+/// What a run measured with, alongside the counts its summary leaves out.
+pub struct Report {
+    pub run: RunInfo,
+    /// Wall clock time the run ended at.
+    pub time: String,
+    pub probe: Probe,
+    pub frequency: f32,
+    pub sensitivity: f32,
+    pub offset_ms: f32,
+    pub timeouts: u64,
+    /// Delays thrown away by `--max-delay` or `--min-confidence`.
+    pub discarded: u64,
+    /// Delays left out for coming out negative, before the offset.
+    pub negative: u64,
+}
+
+impl Report {
+    /// Writes the report, with `summary` if anything was measured, to
+    /// `path`, replacing anything already there.
+    pub fn save(&self, summary: Option<&Summary>, path: &Path) -> anyhow::Result<()> {
+        let mut text = String::new();
+        writeln!(text, "# audioping report")?;
+        writeln!(text)?;
+        writeln!(text, "- Run: {}", self.run.run_id)?;
+        writeln!(text, "- Started: {}", self.run.started)?;
+        writeln!(text, "- Ended: {}", self.time)?;
+        writeln!(text, "- audioping: {}", self.run.version)?;
+        writeln!(text, "- Machine: {}, {}", self.run.hostname, self.run.os)?;
+        writeln!(text, "- Host: {}", self.run.host)?;
+        for input in &self.run.inputs {
+            writeln!(
+                text,
+                "- Input: \"{}\", {}",
+                input.name,
+                input.describe_config()
+            )?;
+        }
+        for output in &self.run.outputs {
+            writeln!(
+                text,
+                "- Output: \"{}\", {}",
+                output.name,
+                output.describe_config()
+            )?;
+        }
+        writeln!(
+            text,
+            "- Probe: {}",
+            describe_probe(&self.probe, self.frequency)
+        )?;
+        writeln!(text, "- Sensitivity: {:.4}", self.sensitivity)?;
+        writeln!(text, "- Offset: {:.2}ms", self.offset_ms)?;
+        writeln!(text)?;
+        writeln!(text, "## Delays")?;
+        writeln!(text)?;
+        writeln!(text, "- Measurements: {}", summary.map_or(0, |s| s.count))?;
+        writeln!(text, "- Timeouts: {}", self.timeouts)?;
+        writeln!(
+            text,
+            "- Discarded by --max-delay or --min-confidence: {}",
+            self.discarded
+        )?;
+        writeln!(
+            text,
+            "- Negative, so left out (check wiring / clock): {}",
+            self.negative
+        )?;
+        if let Some(summary) = summary {
+            writeln!(text)?;
+            writeln!(text, "| Statistic | Delay |")?;
+            writeln!(text, "| --------- | ----- |")?;
+            for (name, delay_ms) in [
+                ("min", summary.min),
+                ("max", summary.max),
+                ("mean", summary.mean),
+                ("median", summary.median),
+                ("stddev", summary.stddev),
+                ("p95", summary.p95),
+                ("p99", summary.p99),
+                ("jitter", summary.jitter),
+            ] {
+                writeln!(text, "| {} | {:.2}ms |", name, delay_ms)?;
+            }
+            if let Some(ci95) = summary.ci95 {
+                writeln!(
+                    text,
+                    "| mean 95% CI | {:.2} +/- {:.2}ms |",
+                    summary.mean, ci95
+                )?;
+            }
+            if let Some(drift) = summary.drift_ms_per_hour {
+                writeln!(text, "| drift | {:+.2}ms/hour |", drift)?;
+            }
+        }
+        if let Err(err) = fs::write(path, text) {
+            anyhow::bail!("can't write report {}: {}", path.display(), err);
+        }
+        Ok(())
+    }
+}
+
+/// The probe in a few words, such as "50ms burst at 440Hz".
+pub fn describe_probe(probe: &Probe, frequency: f32) -> String {
+    match *probe {
+        Probe::Tone => format!("{}Hz sine", frequency),
+        Probe::Square => format!("{}Hz square", frequency),
+        Probe::Click => "click".to_string(),
+        Probe::Noise { duration_ms, seed } => format!("{}ms of noise, seed {}", duration_ms, seed),
+        Probe::Sweep {
+            start_hz,
+            end_hz,
+            duration_ms,
+            ..
+        } => format!(
+            "{}ms sweep from {}Hz to {}Hz",
+            duration_ms, start_hz, end_hz
+        ),
+        Probe::Burst { duration_ms } => format!("{}ms burst at {}Hz", duration_ms, frequency),
+        Probe::Timecode => "timecode".to_string(),
+        Probe::Wav {
+            ref samples,
+            sample_rate,
+            looped,
+        } => format!(
+            "{:.0}ms WAV file{}",
+            samples.len() as f32 * 1000.0 / sample_rate as f32,
+            if looped { ", looped" } else { "" }
+        ),
+    }
+}
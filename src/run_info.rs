@@ -0,0 +1,190 @@
+//! Where a run measured and with what, recorded at the start of its CSV, JSON
+//! and report output so that saved results can be told apart later.
+
+use crate::json_string;
+use std::fmt::Write as _;
+
+/// One stream as it was opened.
+#[derive(Clone)]
+pub struct StreamInfo {
+    pub name: String,
+    pub format: String,
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// Buffer size in frames, or `None` for the host's default.
+    pub buffer_size: Option<u32>,
+}
+
+impl StreamInfo {
+    pub fn new(name: &str, format: cpal::SampleFormat, config: &cpal::StreamConfig) -> Self {
+        Self {
+            name: name.to_owned(),
+            format: format!("{:?}", format),
+            channels: config.channels,
+            sample_rate: config.sample_rate.0,
+            buffer_size: match config.buffer_size {
+                cpal::BufferSize::Fixed(frames) => Some(frames),
+                cpal::BufferSize::Default => None,
+            },
+        }
+    }
+
+    /// The stream's config, e.g. `F32, 2 channels at 48000Hz, 256 frame
+    /// buffer`.
+    pub fn describe_config(&self) -> String {
+        let buffer = match self.buffer_size {
+            Some(frames) => format!("{} frame buffer", frames),
+            None => "host default buffer".to_owned(),
+        };
+        format!(
+            "{}, {} channels at {}Hz, {}",
+            self.format, self.channels, self.sample_rate, buffer
+        )
+    }
+
+    fn json(&self) -> String {
+        let buffer_size = match self.buffer_size {
+            Some(frames) => frames.to_string(),
+            None => "null".to_owned(),
+        };
+        format!(
+            "{{\"name\":{},\"format\":\"{}\",\"channels\":{},\"sample_rate\":{},\"buffer_size\":{}}}",
+            json_string(&self.name),
+            self.format,
+            self.channels,
+            self.sample_rate,
+            buffer_size
+        )
+    }
+}
+
+/// The machine, tool and streams a run measured with.
+#[derive(Clone)]
+pub struct RunInfo {
+    /// Matches the `run_id` column of the run's CSV rows.
+    pub run_id: u128,
+    /// Wall clock time the run started at.
+    pub started: String,
+    pub version: &'static str,
+    pub hostname: String,
+    pub os: String,
+    /// The cpal host, e.g. ALSA or WASAPI.
+    pub host: String,
+    pub inputs: Vec<StreamInfo>,
+    pub outputs: Vec<StreamInfo>,
+}
+
+impl RunInfo {
+    pub fn new(
+        run_id: u128,
+        started: String,
+        host: &cpal::Host,
+        inputs: Vec<StreamInfo>,
+        outputs: Vec<StreamInfo>,
+    ) -> Self {
+        Self {
+            run_id,
+            started,
+            version: env!("CARGO_PKG_VERSION"),
+            hostname: hostname(),
+            os: os(),
+            host: host.id().name().to_owned(),
+            inputs,
+            outputs,
+        }
+    }
+
+    /// The run as `# key: value` comment lines, written to the CSV ahead of
+    /// its rows.
+    pub fn csv_comments(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "# run_id: {}", self.run_id);
+        let _ = writeln!(text, "# started: {}", self.started);
+        let _ = writeln!(text, "# audioping: {}", self.version);
+        let _ = writeln!(text, "# hostname: {}", self.hostname);
+        let _ = writeln!(text, "# os: {}", self.os);
+        let _ = writeln!(text, "# host: {}", self.host);
+        for (kind, streams) in [("input", &self.inputs), ("output", &self.outputs)] {
+            for stream in streams {
+                let _ = writeln!(
+                    text,
+                    "# {}: \"{}\", {}",
+                    kind,
+                    stream.name,
+                    stream.describe_config()
+                );
+            }
+        }
+        text
+    }
+
+    /// The run as one JSON line, printed ahead of the measurements.
+    pub fn json(&self) -> String {
+        let streams = |streams: &[StreamInfo]| {
+            let streams: Vec<String> = streams.iter().map(StreamInfo::json).collect();
+            streams.join(",")
+        };
+        format!(
+            "{{\"run\":{{\"run_id\":{},\"started\":\"{}\",\"version\":\"{}\",\"hostname\":{},\"os\":{},\"host\":{},\"inputs\":[{}],\"outputs\":[{}]}}}}",
+            self.run_id,
+            self.started,
+            self.version,
+            json_string(&self.hostname),
+            json_string(&self.os),
+            json_string(&self.host),
+            streams(&self.inputs),
+            streams(&self.outputs)
+        )
+    }
+}
+
+/// This machine's name, or an empty string if it can't be found.
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut name = [0u8; 256];
+    // SAFETY: the buffer is as long as the length passed, and
+    // `gethostname` writes no more than that.
+    if unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) } != 0 {
+        return String::new();
+    }
+    let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+/// Windows keeps the machine's name in the environment.
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+/// The kernel's name, release and architecture, e.g. `Linux 6.8.0 x86_64`.
+#[cfg(unix)]
+fn os() -> String {
+    // SAFETY: `uname` fills in every field of the zeroed struct with a
+    // NUL-terminated string.
+    let name = unsafe {
+        let mut name: libc::utsname = std::mem::zeroed();
+        if libc::uname(&mut name) != 0 {
+            return format!("{} {}", std::env::consts::OS, std::env::consts::ARCH);
+        }
+        name
+    };
+    let field = |field: &[libc::c_char]| {
+        // SAFETY: `uname` NUL-terminated the field.
+        unsafe { std::ffi::CStr::from_ptr(field.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    format!(
+        "{} {} {}",
+        field(&name.sysname),
+        field(&name.release),
+        field(&name.machine)
+    )
+}
+
+/// Elsewhere there's only what the build targeted.
+#[cfg(not(unix))]
+fn os() -> String {
+    format!("{} {}", std::env::consts::OS, std::env::consts::ARCH)
+}
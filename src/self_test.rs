@@ -0,0 +1,294 @@
+//! Checks the detector against a simulated loopback, without any audio
+//! hardware.
+
+use audioping::{Detector, DetectorConfig, LatencyDetector, Measurement, Probe};
+
+/// Delays, in frames, that the probe is looped back with.
+const DELAYS: [usize; 3] = [100, 733, 1234];
+
+/// Offset between the callback and stream clocks of the simulated streams, so
+/// that stream timestamps are never 0.
+const STREAM_EPOCH_NS: f64 = 1e9;
+
+/// Input buffer lengths, in frames, cycled through to check that how the
+/// input is split up doesn't change the delay. 0 stands for the output's
+/// buffer length.
+const UNEVEN_BLOCKS: [usize; 5] = [13, 0, 160, 1, 701];
+
+/// How far apart the delays measured from even and uneven input buffers may
+/// be, in ms.
+const UNEVEN_TOLERANCE_MS: f32 = 0.001;
+
+/// RMS amplitude of the noise floor played under the probe unless
+/// `--silence-level` gives another, -60dBFS.
+const SILENCE_LEVEL: f32 = 0.001;
+
+/// Pings played in a row over the noise floor, so that anything it
+/// triggered between them would hold up or replace the next ping.
+const NOISE_PINGS: usize = 3;
+
+/// How far a delay measured over the noise floor may be from the one
+/// measured without it, in frames. The noise can take the first sample over
+/// a detector's onset threshold a frame sooner or later, and no more.
+const NOISE_TOLERANCE_FRAMES: f32 = 1.01;
+
+/// The same for a burst, in periods of its tone.
+const BURST_NOISE_TOLERANCE_PERIODS: f32 = 0.25;
+
+/// Plays the probe through a simulated loopback at each of `DELAYS` and
+/// checks that the detector measures each delay, on both clocks, to within a
+/// tolerance, the same again with input buffers of uneven lengths, the same
+/// for several pings in a row over the noise floor, and that a loopback
+/// inverting the probe is flagged where it can be. Prints each result
+/// and returns whether all of them passed.
+pub fn run(config: DetectorConfig) -> bool {
+    let noise_config = DetectorConfig {
+        input_channels: 1,
+        output_channels: 1,
+        input_channel: 0,
+        output_channel: None,
+        // The loopback has unity gain, so trigger at a quarter of a held
+        // tone's peak-to-peak amplitude, which every probe reaches.
+        sensitivity: config.volume / 2.0,
+        silence_level: if config.silence_level > 0.0 {
+            config.silence_level
+        } else {
+            SILENCE_LEVEL
+        },
+        ..config
+    };
+    let config = DetectorConfig {
+        silence_level: 0.0,
+        ..noise_config.clone()
+    };
+    // Coarse onsets of a held tone are only accurate to about a period.
+    let tolerance_ms = if config.precise || config.probe != Probe::Tone {
+        0.05
+    } else {
+        1000.0 / config.frequency
+    };
+    // A fade-in holds the onset back until it's loud enough to be found.
+    let ramp_shift_ms = ramp_shift_ms(&config);
+    // With --invert-detection the loopback inverts the probe, for the
+    // detector to put right.
+    let polarity = if config.invert_detection { -1.0 } else { 1.0 };
+    // A held probe inverted is the same wave half a period on, so it can't be
+    // flagged, and in precise mode it may be taken for the wave half a period
+    // either way. Any other probe should be flagged, at the same delay,
+    // unless a burst, whose envelope is all it's timed by.
+    let held_offset_ms = if config.precise {
+        500.0 / config.frequency
+    } else {
+        0.0
+    };
+    let (inverted_offset_ms, flagged) = match config.probe {
+        Probe::Tone | Probe::Square => (held_offset_ms, false),
+        Probe::Burst { .. } => (0.0, false),
+        _ => (0.0, true),
+    };
+    // A burst is timed by where its envelope rises through half its peak,
+    // which rises slowly enough for the noise to move that by a few frames.
+    let noise_tolerance_ms = match config.probe {
+        Probe::Burst { .. } => BURST_NOISE_TOLERANCE_PERIODS * 1000.0 / config.frequency,
+        _ => NOISE_TOLERANCE_FRAMES * 1000.0 / config.sample_rate,
+    };
+    let block_frames = ((config.sample_rate / 100.0).round() as usize).max(1);
+    let uneven_blocks = UNEVEN_BLOCKS.map(|frames| if frames == 0 { block_frames } else { frames });
+
+    let mut passed = true;
+    for delay_frames in DELAYS {
+        // Any --probe-delay is added on in whole frames.
+        let probe_delay_frames = (config.probe_delay_ms * config.sample_rate / 1000.0).round();
+        let expected_ms = (delay_frames as f32 + probe_delay_frames) * 1000.0 / config.sample_rate
+            + ramp_shift_ms;
+        let loopback = |config: &DetectorConfig, input_blocks: &[usize], pings: usize| {
+            simulate(
+                config,
+                block_frames,
+                input_blocks,
+                delay_frames,
+                polarity,
+                pings,
+            )
+        };
+        let (measurement, uneven) = match (
+            loopback(&config, &[block_frames], 1).pop(),
+            loopback(&config, &uneven_blocks, 1).pop(),
+        ) {
+            (Some(measurement), Some(uneven)) => (measurement, uneven),
+            (measurement, _) => {
+                let blocks = if measurement.is_some() {
+                    " from uneven blocks"
+                } else {
+                    ""
+                };
+                println!(
+                    "delay {} frames: no signal detected{} FAIL",
+                    delay_frames, blocks
+                );
+                passed = false;
+                continue;
+            }
+        };
+        // The ping furthest from the one measured without the noise floor.
+        let noisy = loopback(&noise_config, &[block_frames], NOISE_PINGS);
+        let noisy_ms = noisy
+            .iter()
+            .map(|noisy| noisy.delay_ms)
+            .max_by(|a, b| {
+                let from = |delay_ms: f32| (delay_ms - measurement.delay_ms).abs();
+                from(*a).total_cmp(&from(*b))
+            })
+            .unwrap_or(f32::NAN);
+        let noise_floor = if noisy.len() < NOISE_PINGS {
+            format!("{} of {} pings", noisy.len(), NOISE_PINGS)
+        } else {
+            format!("{:.4}ms", noisy_ms)
+        };
+        // The loopback inverts the probe without the detector being told.
+        let inverted_config = DetectorConfig {
+            invert_detection: false,
+            ..config.clone()
+        };
+        let inverted = simulate(
+            &inverted_config,
+            block_frames,
+            &[block_frames],
+            delay_frames,
+            -1.0,
+            1,
+        )
+        .pop();
+        let inverted_loopback = match &inverted {
+            Some(inverted) => format!(
+                "{:.4}ms{}",
+                inverted.delay_ms,
+                if inverted.inverted { " flagged" } else { "" }
+            ),
+            None => "no signal".to_string(),
+        };
+        let stream_delay_ms = measurement.stream_delay_ms.unwrap_or(f32::NAN);
+        let ok = !measurement.inverted
+            && (measurement.delay_ms - expected_ms).abs() <= tolerance_ms
+            && (stream_delay_ms - expected_ms).abs() <= tolerance_ms
+            && (uneven.delay_ms - measurement.delay_ms).abs() <= UNEVEN_TOLERANCE_MS
+            && noisy.len() == NOISE_PINGS
+            && (noisy_ms - measurement.delay_ms).abs() <= noise_tolerance_ms
+            && inverted.is_some_and(|inverted| {
+                inverted.inverted == flagged
+                    && ((inverted.delay_ms - expected_ms).abs() - inverted_offset_ms).abs()
+                        <= tolerance_ms
+            });
+        println!(
+            "delay {} frames: expected {:.4}ms, measured {:.4}ms, stream {:.4}ms, uneven blocks {:.4}ms, noise floor {}, inverted loopback {} {}",
+            delay_frames,
+            expected_ms,
+            measurement.delay_ms,
+            stream_delay_ms,
+            uneven.delay_ms,
+            noise_floor,
+            inverted_loopback,
+            if ok { "PASS" } else { "FAIL" }
+        );
+        passed &= ok;
+    }
+    passed
+}
+
+/// How much later a held probe's coarse onset is found with its fade-in than
+/// without, in ms: the onset is the first sample reaching a quarter of the
+/// sensitivity for a windowed detector, and the first frame whose window's
+/// peak-to-peak amplitude goes over the sensitivity for the peak detector.
+fn ramp_shift_ms(config: &DetectorConfig) -> f32 {
+    if !matches!(config.probe, Probe::Tone | Probe::Square) {
+        return 0.0;
+    }
+    let window_frames =
+        ((config.window_periods * config.sample_rate / config.frequency).round() as usize).max(1);
+    let onset = |ramp_frames: u64| {
+        // As the detector plays it, from its first frame at clock 1.
+        let sample = |clock: u64| {
+            let gain = if clock >= ramp_frames {
+                1.0
+            } else {
+                0.5 - 0.5 * (std::f32::consts::PI * clock as f32 / ramp_frames as f32).cos()
+            };
+            let value = config
+                .probe
+                .value(config.frequency, config.sample_rate, clock);
+            (value * config.volume * config.output_gain * gain).clamp(-1.0, 1.0)
+        };
+        let frames = ramp_frames as usize + 2 * window_frames;
+        let samples: Vec<f32> = (1..=frames as u64).map(sample).collect();
+        let found = if config.detector == Detector::Peak {
+            // The silence before the probe counts towards the first windows.
+            (0..frames).find(|&frame| {
+                let window = &samples[(frame + 1).saturating_sub(window_frames)..=frame];
+                let max = window.iter().fold(0f32, |max, sample| max.max(*sample));
+                let min = window.iter().fold(0f32, |min, sample| min.min(*sample));
+                max - min > config.sensitivity
+            })
+        } else {
+            samples
+                .iter()
+                .position(|sample| sample.abs() >= config.sensitivity / 4.0)
+        };
+        found.unwrap_or(frames) as f32
+    };
+    let ramp_frames = (config.ramp_ms.max(0.0) * config.sample_rate / 1000.0) as u64;
+    (onset(ramp_frames) - onset(0)) * 1000.0 / config.sample_rate
+}
+
+/// Loops the probe back `delay_frames` late, played in blocks of
+/// `block_frames` and heard in blocks cycling through the lengths in
+/// `input_blocks`, and returns the first `pings` measurements, or as many as
+/// were made before giving up.
+fn simulate(
+    config: &DetectorConfig,
+    block_frames: usize,
+    input_blocks: &[usize],
+    delay_frames: usize,
+    polarity: f32,
+    pings: usize,
+) -> Vec<Measurement> {
+    let probe_delay_frames = (config.probe_delay_ms * config.sample_rate / 1000.0).round();
+    let frame_ns = 1e9 / config.sample_rate as f64;
+    let detector = LatencyDetector::new(config.clone());
+    let mut input_state = detector.input_state();
+    let mut played = vec![0f32; delay_frames];
+    let mut output = vec![0f32; block_frames];
+    let mut output_blocks = 0;
+    let mut input_frames = 0;
+    let frames = (pings * (200 * block_frames + probe_delay_frames as usize)) as f64;
+    let mut measurements = Vec::new();
+    for input_block in input_blocks.iter().cycle() {
+        let input_end = input_frames + input_block;
+        if input_end as f64 > frames {
+            break;
+        }
+        // Each output block is filled as it starts playing, and each input
+        // block is processed just after it was captured.
+        while output_blocks * block_frames < input_end {
+            let block_start_ns = (output_blocks * block_frames) as f64 * frame_ns;
+            detector.fill_output(
+                &mut output,
+                block_start_ns as u64,
+                Some((STREAM_EPOCH_NS + block_start_ns) as u64),
+            );
+            played.extend(output.iter().map(|sample| sample * polarity));
+            output_blocks += 1;
+        }
+        let measurement = detector.process_input(
+            &mut input_state,
+            &played[input_frames..input_end],
+            (input_end as f64 * frame_ns) as u64,
+            Some((STREAM_EPOCH_NS + input_frames as f64 * frame_ns) as u64),
+        );
+        measurements.extend(measurement);
+        if measurements.len() == pings {
+            break;
+        }
+        input_frames = input_end;
+    }
+    measurements
+}
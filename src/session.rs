@@ -0,0 +1,513 @@
+//! Runs [`LatencyDetector`]s on cpal streams, for programs that want the
+//! measurements without managing the audio themselves.
+//!
+//! A [`Session`] opens one input and one or more outputs, or several inputs
+//! and one output, and sends every measurement on a channel. The caller owns
+//! the loop: it decides when pings time out, when to ask for the next one if
+//! pings are made on request, and when to stop, which happens when the
+//! session is dropped.
+
+use super::{DetectorConfig, LatencyDetector, Measurement};
+use cpal::traits::{DeviceTrait, StreamTrait};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Which side of the loopback a device is used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+impl Direction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Direction::Input => "input",
+            Direction::Output => "output",
+        }
+    }
+}
+
+/// An error reported by one of a session's streams.
+pub type StreamFailure = (Direction, cpal::StreamError);
+
+/// A device and how to open it.
+pub struct StreamSpec {
+    pub device: cpal::Device,
+    pub config: cpal::StreamConfig,
+    pub format: cpal::SampleFormat,
+}
+
+/// What a [`Session`] plays and listens on.
+pub struct SessionConfig {
+    /// Devices to listen on. With more than one, there can only be one
+    /// output, and each input has a detector of its own timing its pings,
+    /// counting frames at the input's own sample rate.
+    pub inputs: Vec<StreamSpec>,
+    /// Devices to ping from. With more than one, they take turns, one ping
+    /// each, and only ping when asked to with [`Session::request_ping`]. May
+    /// be empty if the detector only listens, or starts pings at a sync
+    /// marker, in which case a single detector follows the input.
+    pub outputs: Vec<StreamSpec>,
+    /// Settings shared by every output's detector. Its sample rate, channel
+    /// counts and epoch are filled in from the streams and the clock.
+    pub detector: DetectorConfig,
+    /// Add TPDF dither to outputs that take integer samples.
+    pub dither: bool,
+    /// Called with each buffer of the first input, and the detectors' clock
+    /// at its callback, before it's scanned, to record it for example.
+    pub input_tap: Option<InputTap>,
+}
+
+/// See [`SessionConfig::input_tap`].
+pub type InputTap = Box<dyn FnMut(&[f32], u64) + Send>;
+
+type InputDataFn = Box<dyn FnMut(&[f32], &cpal::InputCallbackInfo) + Send>;
+type OutputDataFn = Box<dyn FnMut(&mut [f32], &cpal::OutputCallbackInfo) + Send>;
+
+/// Running streams with a detector per output, or per input if there are
+/// several.
+pub struct Session {
+    // Streams are dropped first, inputs before outputs, so that nothing is
+    // left waiting on a callback that will never run.
+    input_streams: Vec<cpal::Stream>,
+    output_streams: Vec<cpal::Stream>,
+    inputs: Vec<StreamSpec>,
+    outputs: Vec<StreamSpec>,
+    dither: bool,
+    start_time: Instant,
+    detectors: Vec<Arc<LatencyDetector>>,
+    /// The output whose turn it is to ping.
+    active_output: usize,
+    input_data_fns: Vec<Arc<Mutex<InputDataFn>>>,
+    output_data_fns: Vec<Arc<Mutex<OutputDataFn>>>,
+    /// Frames in the latest callback of each stream, 0 before the first.
+    input_frames: Vec<Arc<AtomicUsize>>,
+    output_frames: Vec<Arc<AtomicUsize>>,
+    error_tx: Sender<StreamFailure>,
+    errors: Receiver<StreamFailure>,
+}
+
+impl Session {
+    /// Builds and starts the streams. Each measurement, tagged with the
+    /// output that played its probe and the input that heard it, is sent on
+    /// the returned channel.
+    pub fn start(config: SessionConfig) -> anyhow::Result<(Session, Receiver<Measurement>)> {
+        let plays = !config.detector.listen_only && config.detector.sync_channel.is_none();
+        if config.outputs.is_empty() && plays {
+            anyhow::bail!("a session needs at least one output unless it only listens");
+        }
+        if config.inputs.is_empty() {
+            anyhow::bail!("a session needs at least one input");
+        }
+        if config.inputs.len() > 1 && (config.outputs.len() > 1 || !plays) {
+            anyhow::bail!("a session with several inputs needs exactly one output to ping from");
+        }
+        // Read together so that callback times can be converted to wall
+        // clock times for timecodes.
+        let start_time = Instant::now();
+        let epoch_ns = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+        let input_channels = config.inputs[0].config.channels as usize;
+        let sample_rate = config
+            .outputs
+            .first()
+            .unwrap_or(&config.inputs[0])
+            .config
+            .sample_rate
+            .0;
+        let on_request =
+            config.detector.on_request || config.outputs.len() > 1 || config.inputs.len() > 1;
+        let mut output_channels: Vec<usize> = config
+            .outputs
+            .iter()
+            .map(|output| output.config.channels as usize)
+            .collect();
+        if output_channels.is_empty() {
+            output_channels.push(0);
+        }
+        let mut detectors: Vec<Arc<LatencyDetector>> = output_channels
+            .iter()
+            .map(|&output_channels| {
+                Arc::new(LatencyDetector::new(DetectorConfig {
+                    sample_rate: sample_rate as f32,
+                    input_channels,
+                    output_channels,
+                    on_request,
+                    epoch_ns,
+                    ..config.detector.clone()
+                }))
+            })
+            .collect();
+        // Each further input has a detector that follows the first one's
+        // pings, at its own sample rate.
+        let followers: Vec<Arc<LatencyDetector>> = config.inputs[1..]
+            .iter()
+            .map(|input| {
+                Arc::new(LatencyDetector::new(DetectorConfig {
+                    sample_rate: input.config.sample_rate.0 as f32,
+                    input_channels: input.config.channels as usize,
+                    output_channels: output_channels[0],
+                    on_request,
+                    epoch_ns,
+                    ..config.detector.clone()
+                }))
+            })
+            .collect();
+        let stream_clock = Arc::new(StreamClock::default());
+
+        let (measurement_tx, measurements) = channel();
+        let mut input_frames = Vec::new();
+        let mut input_data_fns = Vec::new();
+        let mut input_tap = config.input_tap;
+        for (index, input) in config.inputs.iter().enumerate() {
+            // With one input every detector follows it, even though at most
+            // one of them is waiting on a ping. With several, each input has
+            // a detector of its own.
+            let input_detectors: Vec<(usize, Arc<LatencyDetector>)> = if index == 0 {
+                detectors.iter().cloned().enumerate().collect()
+            } else {
+                vec![(0, Arc::clone(&followers[index - 1]))]
+            };
+            let mut input_detectors: Vec<_> = input_detectors
+                .into_iter()
+                .map(|(output, detector)| (output, detector.input_state(), detector))
+                .collect();
+            let input_stream_clock = Arc::clone(&stream_clock);
+            let frames = Arc::new(AtomicUsize::new(0));
+            let callback_frames = Arc::clone(&frames);
+            let input_channels = (input.config.channels as usize).max(1);
+            let mut input_tap = input_tap.take();
+            let measurement_tx = measurement_tx.clone();
+            let input_data_fn: InputDataFn =
+                Box::new(move |data: &[f32], info: &cpal::InputCallbackInfo| {
+                    let frame_start_ns = start_time.elapsed().as_nanos() as u64;
+                    let stream_ns = input_stream_clock.nanos(&info.timestamp().capture);
+                    callback_frames.store(data.len() / input_channels, Ordering::SeqCst);
+                    if let Some(input_tap) = &mut input_tap {
+                        input_tap(data, frame_start_ns);
+                    }
+                    for (output, state, detector) in &mut input_detectors {
+                        if let Some(measurement) =
+                            detector.process_input(state, data, frame_start_ns, stream_ns)
+                        {
+                            let _ = measurement_tx.send(Measurement {
+                                output: *output,
+                                input: index,
+                                ..measurement
+                            });
+                        }
+                    }
+                });
+            input_frames.push(frames);
+            input_data_fns.push(Arc::new(Mutex::new(input_data_fn)));
+        }
+
+        let mut output_frames = Vec::new();
+        let mut output_data_fns = Vec::new();
+        for (detector, output) in detectors.iter().zip(&config.outputs) {
+            let output_detector = Arc::clone(detector);
+            // Only the first output is ever followed.
+            let output_followers = followers.clone();
+            let output_stream_clock = Arc::clone(&stream_clock);
+            let frames = Arc::new(AtomicUsize::new(0));
+            let callback_frames = Arc::clone(&frames);
+            let output_channels = (output.config.channels as usize).max(1);
+            let output_data_fn: OutputDataFn =
+                Box::new(move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+                    let frame_start_ns = start_time.elapsed().as_nanos() as u64;
+                    let stream_ns = output_stream_clock.nanos(&info.timestamp().playback);
+                    callback_frames.store(data.len() / output_channels, Ordering::SeqCst);
+                    output_detector.fill_output(data, frame_start_ns, stream_ns);
+                    for follower in &output_followers {
+                        follower.follow(&output_detector);
+                    }
+                });
+            output_frames.push(frames);
+            output_data_fns.push(Arc::new(Mutex::new(output_data_fn)));
+        }
+
+        // So that the first turn goes to the first output.
+        let active_output = detectors.len() - 1;
+        detectors.extend(followers);
+        let (error_tx, errors) = channel();
+        let mut session = Session {
+            input_streams: Vec::new(),
+            output_streams: Vec::new(),
+            inputs: config.inputs,
+            outputs: config.outputs,
+            dither: config.dither,
+            start_time,
+            active_output,
+            detectors,
+            input_data_fns,
+            output_data_fns,
+            input_frames,
+            output_frames,
+            error_tx,
+            errors,
+        };
+        session.rebuild(Direction::Output)?;
+        session.rebuild(Direction::Input)?;
+        Ok((session, measurements))
+    }
+
+    /// One detector per output, in the order the outputs were given, or with
+    /// several inputs, one per input.
+    pub fn detectors(&self) -> &[Arc<LatencyDetector>] {
+        &self.detectors
+    }
+
+    /// The detectors that play the pings, one per output, without those
+    /// following them on further inputs.
+    pub fn output_detectors(&self) -> &[Arc<LatencyDetector>] {
+        &self.detectors[..self.outputs.len().max(1)]
+    }
+
+    /// Nanoseconds on the detectors' clock, which started with the session.
+    pub fn now_ns(&self) -> u64 {
+        self.start_time.elapsed().as_nanos() as u64
+    }
+
+    /// Errors reported by the streams. A stream that reports one has usually
+    /// stopped, and can be replaced with [`Session::rebuild`].
+    pub fn errors(&self) -> &Receiver<StreamFailure> {
+        &self.errors
+    }
+
+    /// Frames in the latest callback of input `index`, or 0 before the first.
+    pub fn input_buffer_frames(&self, index: usize) -> usize {
+        self.input_frames[index].load(Ordering::SeqCst)
+    }
+
+    /// Frames in the latest callback of output `index`, or 0 before the first.
+    pub fn output_buffer_frames(&self, index: usize) -> usize {
+        self.output_frames[index].load(Ordering::SeqCst)
+    }
+
+    /// Whether the current output's ping has been asked for and not yet been
+    /// detected or timed out, on every input if there are several.
+    pub fn ping_pending(&self) -> bool {
+        if self.inputs.len() > 1 {
+            return self
+                .detectors
+                .iter()
+                .any(|detector| detector.ping_pending());
+        }
+        self.detectors[self.active_output].ping_pending()
+    }
+
+    /// Hands the turn to the next output and asks it for a ping, when pings
+    /// are only made on request.
+    pub fn request_ping(&mut self) {
+        self.active_output = (self.active_output + 1) % self.outputs.len().max(1);
+        self.detectors[self.active_output].request_ping();
+    }
+
+    /// Abandons any ping emitted more than `timeout` ago without being
+    /// detected, and returns the index of its detector in
+    /// [`Session::detectors`].
+    pub fn check_timeout(&self, timeout: Duration) -> Option<usize> {
+        let now_ns = self.now_ns();
+        let mut timed_out = None;
+        for (index, detector) in self.detectors.iter().enumerate() {
+            if detector.check_timeout(now_ns, timeout.as_nanos() as u64) {
+                timed_out = Some(index);
+            }
+        }
+        timed_out
+    }
+
+    /// Closes and rebuilds the streams on `direction`, keeping every
+    /// detector's state. Which of several streams failed isn't known, so
+    /// they're all rebuilt.
+    pub fn rebuild(&mut self, direction: Direction) -> anyhow::Result<()> {
+        match direction {
+            Direction::Input => {
+                self.input_streams.clear();
+                let mut streams = Vec::new();
+                for (input, data_fn) in self.inputs.iter().zip(&self.input_data_fns) {
+                    streams.push(start_input_stream(
+                        &input.device,
+                        &input.config,
+                        input.format,
+                        data_fn,
+                        &self.error_tx,
+                    )?);
+                }
+                for stream in &streams {
+                    stream.play()?;
+                }
+                self.input_streams = streams;
+            }
+            Direction::Output => {
+                self.output_streams.clear();
+                let mut streams = Vec::new();
+                for (output, data_fn) in self.outputs.iter().zip(&self.output_data_fns) {
+                    streams.push(start_output_stream(
+                        &output.device,
+                        &output.config,
+                        output.format,
+                        data_fn,
+                        &self.error_tx,
+                        self.dither,
+                    )?);
+                }
+                for stream in &streams {
+                    stream.play()?;
+                }
+                self.output_streams = streams;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Converts the stream timestamps cpal reports into nanoseconds since an
+/// epoch shared by the input and output streams.
+#[derive(Default)]
+struct StreamClock {
+    epoch: OnceLock<cpal::StreamInstant>,
+}
+
+impl StreamClock {
+    /// How far before the first timestamp seen the epoch is placed, so that
+    /// slightly earlier timestamps from the other stream stay positive.
+    const MARGIN: Duration = Duration::from_secs(60);
+
+    fn nanos(&self, instant: &cpal::StreamInstant) -> Option<u64> {
+        let epoch = self
+            .epoch
+            .get_or_init(|| instant.sub(StreamClock::MARGIN).unwrap_or(*instant));
+        instant
+            .duration_since(epoch)
+            .map(|duration| duration.as_nanos() as u64)
+    }
+}
+
+/// Builds an input stream in `format` that hands its buffers to the shared
+/// `data_fn`, so that it can be rebuilt without losing the callback's state.
+pub fn start_input_stream<D>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    format: cpal::SampleFormat,
+    data_fn: &Arc<Mutex<D>>,
+    errors: &Sender<StreamFailure>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    D: FnMut(&[f32], &cpal::InputCallbackInfo) + Send + 'static,
+{
+    let data_fn = Arc::clone(data_fn);
+    let data_fn =
+        move |data: &[f32], info: &cpal::InputCallbackInfo| (data_fn.lock().unwrap())(data, info);
+    let errors = errors.clone();
+    match format {
+        cpal::SampleFormat::I16 => build_input_stream::<i16, _>(device, config, data_fn, errors),
+        cpal::SampleFormat::U16 => build_input_stream::<u16, _>(device, config, data_fn, errors),
+        cpal::SampleFormat::F32 => build_input_stream::<f32, _>(device, config, data_fn, errors),
+    }
+}
+
+/// Builds an output stream in `format` that lets the shared `data_fn` fill
+/// its buffers, so that it can be rebuilt without losing the callback's state.
+/// `dither` only applies to integer formats.
+pub fn start_output_stream<D>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    format: cpal::SampleFormat,
+    data_fn: &Arc<Mutex<D>>,
+    errors: &Sender<StreamFailure>,
+    dither: bool,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    D: FnMut(&mut [f32], &cpal::OutputCallbackInfo) + Send + 'static,
+{
+    let data_fn = Arc::clone(data_fn);
+    let data_fn = move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+        (data_fn.lock().unwrap())(data, info)
+    };
+    let errors = errors.clone();
+    match format {
+        cpal::SampleFormat::I16 => {
+            build_output_stream::<i16, _>(device, config, data_fn, errors, dither)
+        }
+        cpal::SampleFormat::U16 => {
+            build_output_stream::<u16, _>(device, config, data_fn, errors, dither)
+        }
+        // Nothing is lost converting to f32, so there's nothing to dither.
+        cpal::SampleFormat::F32 => {
+            build_output_stream::<f32, _>(device, config, data_fn, errors, false)
+        }
+    }
+}
+
+/// Builds an input stream of native sample type `T`, handing `data_fn` the
+/// buffer converted to `f32` in the range -1.0 to 1.0.
+fn build_input_stream<T, D>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut data_fn: D,
+    errors: Sender<StreamFailure>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: cpal::Sample,
+    D: FnMut(&[f32], &cpal::InputCallbackInfo) + Send + 'static,
+{
+    let mut buffer = Vec::<f32>::new();
+    device.build_input_stream(
+        config,
+        move |data: &[T], info: &cpal::InputCallbackInfo| {
+            buffer.clear();
+            buffer.extend(data.iter().map(|sample| sample.to_f32()));
+            data_fn(&buffer, info);
+        },
+        move |err| {
+            let _ = errors.send((Direction::Input, err));
+        },
+    )
+}
+
+/// Builds an output stream of native sample type `T`, letting `data_fn` fill
+/// an `f32` buffer that is then converted to the device format, with TPDF
+/// dither of one 16 bit step added first if `dither` is set.
+fn build_output_stream<T, D>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut data_fn: D,
+    errors: Sender<StreamFailure>,
+    dither: bool,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: cpal::Sample,
+    D: FnMut(&mut [f32], &cpal::OutputCallbackInfo) + Send + 'static,
+{
+    let mut buffer = Vec::<f32>::new();
+    // xorshift32 state for the dither, which only needs to be uncorrelated
+    // with the signal.
+    let mut random = 0x9e37_79b9u32;
+    let mut uniform = move || {
+        random ^= random << 13;
+        random ^= random >> 17;
+        random ^= random << 5;
+        random as f32 / u32::MAX as f32
+    };
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
+            buffer.resize(data.len(), 0f32);
+            data_fn(&mut buffer, info);
+            if dither {
+                for value in buffer.iter_mut() {
+                    *value += (uniform() - uniform()) / 32768f32;
+                }
+            }
+            for (sample, value) in data.iter_mut().zip(buffer.iter()) {
+                *sample = T::from(value);
+            }
+        },
+        move |err| {
+            let _ = errors.send((Direction::Output, err));
+        },
+    )
+}
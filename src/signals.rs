@@ -0,0 +1,99 @@
+//! Signals other than those `ctrlc` stops the run on: SIGHUP resets the
+//! statistics, as daemons commonly reload on it, and on Windows closing the
+//! console, logging off or shutting down stops the run as Ctrl-C does.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether a SIGHUP has arrived that the main loop hasn't acted on yet.
+static HANGUP: AtomicBool = AtomicBool::new(false);
+
+/// Whether a SIGHUP has arrived since this was last called.
+pub fn take_hangup() -> bool {
+    HANGUP.swap(false, Ordering::SeqCst)
+}
+
+/// Takes SIGHUP over from `ctrlc`, which would otherwise stop the run on it,
+/// so it's left for `take_hangup` instead. Call after `ctrlc::set_handler`.
+#[cfg(unix)]
+pub fn handle_hangup() -> anyhow::Result<()> {
+    extern "C" fn on_hangup(_signal: libc::c_int) {
+        HANGUP.store(true, Ordering::SeqCst);
+    }
+
+    // SAFETY: the handler only stores to an atomic, which is async-signal
+    // safe, and `action` is fully initialized before it's installed.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_hangup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut()) != 0 {
+            anyhow::bail!("can't handle SIGHUP: {}", std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// There's no SIGHUP outside Unix.
+#[cfg(not(unix))]
+pub fn handle_hangup() -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Runs `stop` when the console is closed, the user logs off or the system
+/// shuts down. Windows kills the process as soon as the handler returns, so
+/// it never does, leaving the run its few seconds' grace to shut down
+/// cleanly and exit. Call after `ctrlc::set_handler`, so that this handler is
+/// asked first.
+#[cfg(windows)]
+pub fn handle_console_close(stop: impl Fn() + Send + Sync + 'static) -> anyhow::Result<()> {
+    use std::sync::OnceLock;
+
+    const CTRL_CLOSE_EVENT: u32 = 2;
+    const CTRL_LOGOFF_EVENT: u32 = 5;
+    const CTRL_SHUTDOWN_EVENT: u32 = 6;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetConsoleCtrlHandler(
+            handler: Option<unsafe extern "system" fn(u32) -> i32>,
+            add: i32,
+        ) -> i32;
+    }
+
+    static STOP: OnceLock<Box<dyn Fn() + Send + Sync>> = OnceLock::new();
+
+    unsafe extern "system" fn on_console_event(event: u32) -> i32 {
+        match event {
+            CTRL_CLOSE_EVENT | CTRL_LOGOFF_EVENT | CTRL_SHUTDOWN_EVENT => {
+                if let Some(stop) = STOP.get() {
+                    stop();
+                }
+                loop {
+                    std::thread::park();
+                }
+            }
+            // Ctrl-C and Ctrl-Break are left to ctrlc.
+            _ => 0,
+        }
+    }
+
+    if STOP.set(Box::new(stop)).is_err() {
+        return Ok(());
+    }
+    // SAFETY: the handler is a plain function that stays valid for the life
+    // of the process.
+    if unsafe { SetConsoleCtrlHandler(Some(on_console_event), 1) } == 0 {
+        anyhow::bail!(
+            "can't handle the console closing: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+/// Elsewhere a managed stop sends SIGTERM, which `ctrlc` already handles.
+#[cfg(not(windows))]
+pub fn handle_console_close(_stop: impl Fn() + Send + Sync + 'static) -> anyhow::Result<()> {
+    Ok(())
+}
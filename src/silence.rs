@@ -0,0 +1,87 @@
+//! Low-level noise played under the output for
+//! `DetectorConfig::silence_level`, so that an amplifier that gates or
+//! thumps on silence stays open between pings.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Spectrum of the noise played between pings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SilenceNoise {
+    White,
+    /// Falling 3dB per octave, so that less of it is in the treble and more
+    /// in the bass that keeps an amplifier's gate open.
+    Pink,
+}
+
+/// How many times its RMS the noise's peaks are clipped to, so that a
+/// threshold above them is above all of it.
+pub const SILENCE_CREST_FACTOR: f32 = 4.0;
+
+/// RMS of uniform noise from -1.0 to 1.0.
+const WHITE_RMS: f32 = 0.57735;
+
+/// RMS of the pink filter's output for that noise, measured.
+const PINK_RMS: f32 = 1.717;
+
+/// Generates the noise, one sample per frame. Only the output callback
+/// draws on it, and it keeps its state in atomics between buffers so that
+/// the callback needn't lock it.
+pub(crate) struct NoiseFloor {
+    noise: SilenceNoise,
+    rms: f32,
+    random: AtomicU32,
+    /// Paul Kellet's three pole pink filter, as `f32` bits.
+    poles: [AtomicU32; 3],
+}
+
+impl NoiseFloor {
+    pub(crate) fn new(noise: SilenceNoise, rms: f32) -> NoiseFloor {
+        NoiseFloor {
+            noise,
+            rms,
+            random: AtomicU32::new(0x9e37_79b9),
+            poles: Default::default(),
+        }
+    }
+
+    /// Adds the next frames' noise to every channel of the interleaved
+    /// `data`, clipping the sum to -1.0 to 1.0.
+    pub(crate) fn add(&self, data: &mut [f32], channels: usize) {
+        let mut random = self.random.load(Ordering::SeqCst);
+        let mut poles = self
+            .poles
+            .each_ref()
+            .map(|pole| f32::from_bits(pole.load(Ordering::SeqCst)));
+        for frame in data.chunks_mut(channels) {
+            let noise = self.sample(&mut random, &mut poles);
+            for sample in frame {
+                *sample = (*sample + noise).clamp(-1.0, 1.0);
+            }
+        }
+        self.random.store(random, Ordering::SeqCst);
+        for (pole, value) in self.poles.iter().zip(poles) {
+            pole.store(value.to_bits(), Ordering::SeqCst);
+        }
+    }
+
+    /// The next frame's sample, from the generator's state in `random` and
+    /// `poles`.
+    fn sample(&self, random: &mut u32, poles: &mut [f32; 3]) -> f32 {
+        // xorshift32, scaled to -1.0 to 1.0.
+        *random ^= *random << 13;
+        *random ^= *random >> 17;
+        *random ^= *random << 5;
+        let white = *random as f32 / u32::MAX as f32 * 2.0 - 1.0;
+        let unit = match self.noise {
+            SilenceNoise::White => white / WHITE_RMS,
+            SilenceNoise::Pink => {
+                let [b0, b1, b2] = poles;
+                *b0 = 0.99765 * *b0 + white * 0.0990460;
+                *b1 = 0.96300 * *b1 + white * 0.2965164;
+                *b2 = 0.57000 * *b2 + white * 1.0526913;
+                (*b0 + *b1 + *b2 + white * 0.1848) / PINK_RMS
+            }
+        };
+        unit.clamp(-SILENCE_CREST_FACTOR, SILENCE_CREST_FACTOR) * self.rms
+    }
+}
@@ -0,0 +1,127 @@
+//! Wall clock timestamps carried by a probe, so the probe's one-way delay can
+//! be measured by a detector that didn't play it.
+//!
+//! A timecode is sent as continuous-phase FSK: a run of space bits to detect
+//! and align to, one mark bit, then 56 bits of the timestamp in microseconds
+//! and an 8 bit CRC, most significant bit first.
+
+use super::goertzel_amplitude;
+use std::f64::consts::PI;
+
+/// Frequencies of a timecode's space (0) and mark (1) bits in Hz, as used by
+/// Bell 202 modems.
+pub const TIMECODE_HZ: [f32; 2] = [2200.0, 1200.0];
+
+/// Bits sent per second.
+const BAUD: f32 = 1200.0;
+
+/// Space bits sent before the start bit.
+const PREAMBLE_BITS: u64 = 8;
+
+/// Bits of timestamp and CRC following the start bit.
+const DATA_BITS: u64 = 64;
+
+/// Number of frames each bit is played for.
+fn bit_frames(sample_rate: f32) -> u64 {
+    ((sample_rate / BAUD).round() as u64).max(1)
+}
+
+/// Length of a whole timecode in frames.
+pub fn frame_frames(sample_rate: f32) -> u64 {
+    (PREAMBLE_BITS + 1 + DATA_BITS) * bit_frames(sample_rate)
+}
+
+/// Length of the preamble and start bit in frames, which are the same in
+/// every timecode.
+pub fn sync_frames(sample_rate: f32) -> u64 {
+    (PREAMBLE_BITS + 1) * bit_frames(sample_rate)
+}
+
+/// The data sent for `timestamp_us`, which is truncated to 56 bits.
+pub fn encode(timestamp_us: u64) -> u64 {
+    let timestamp_us = timestamp_us & ((1 << 56) - 1);
+    timestamp_us << 8 | crc8(timestamp_us) as u64
+}
+
+/// Value of the timecode carrying `data`, from -1.0 to 1.0, at frame `clock`,
+/// counting its first frame as 1.
+pub fn value(data: u64, sample_rate: f32, clock: u64) -> f32 {
+    let bit_frames = bit_frames(sample_rate);
+    if clock == 0 || clock > frame_frames(sample_rate) {
+        return 0.0;
+    }
+    let bit = (clock - 1) / bit_frames;
+    // Count the whole bits played on each frequency so far, so that the
+    // phase carries on smoothly from one bit to the next.
+    let marks_before = match bit.checked_sub(PREAMBLE_BITS + 1) {
+        Some(data_bit) => {
+            1 + data
+                .checked_shr((DATA_BITS - data_bit) as u32)
+                .unwrap_or(0)
+                .count_ones() as u64
+        }
+        None => 0,
+    };
+    let spaces_before = bit - marks_before;
+    let into_bit = (clock - 1) % bit_frames + 1;
+    let current = bit_value(data, bit) as usize;
+    let cycles = ((spaces_before * bit_frames) as f64 * TIMECODE_HZ[0] as f64
+        + (marks_before * bit_frames) as f64 * TIMECODE_HZ[1] as f64
+        + into_bit as f64 * TIMECODE_HZ[current] as f64)
+        / sample_rate as f64;
+    (2.0 * PI * cycles).sin() as f32
+}
+
+/// Reads the timestamp, in microseconds, from a timecode starting at
+/// `samples[0]`. Returns `None` if `samples` is too short for it or it fails
+/// its CRC.
+pub fn decode(samples: &[f32], sample_rate: f32) -> Option<u64> {
+    let bit_frames = bit_frames(sample_rate) as usize;
+    if samples.len() < frame_frames(sample_rate) as usize {
+        return None;
+    }
+    let coeffs = TIMECODE_HZ.map(|hz| 2.0 * (2.0 * std::f32::consts::PI * hz / sample_rate).cos());
+    let read_bit = |bit: u64| {
+        let start = bit as usize * bit_frames;
+        let bit_samples = &samples[start..start + bit_frames];
+        let [space, mark] =
+            coeffs.map(|coeff| goertzel_amplitude(coeff, &mut bit_samples.iter().copied()));
+        mark > space
+    };
+    if !read_bit(PREAMBLE_BITS) {
+        return None;
+    }
+    let data = (0..DATA_BITS).fold(0u64, |data, bit| {
+        data << 1 | read_bit(PREAMBLE_BITS + 1 + bit) as u64
+    });
+    let timestamp_us = data >> 8;
+    if crc8(timestamp_us) as u64 != data & 0xff {
+        return None;
+    }
+    Some(timestamp_us)
+}
+
+/// Whether bit `bit` of the timecode carrying `data` is a mark.
+fn bit_value(data: u64, bit: u64) -> bool {
+    match bit.checked_sub(PREAMBLE_BITS + 1) {
+        Some(data_bit) => data >> (DATA_BITS - 1 - data_bit) & 1 == 1,
+        None => bit == PREAMBLE_BITS,
+    }
+}
+
+/// CRC-8 with polynomial 0x07 over the low 7 bytes of `timestamp_us`, most
+/// significant first.
+fn crc8(timestamp_us: u64) -> u8 {
+    let mut crc = 0u8;
+    for byte in timestamp_us.to_be_bytes()[1..].iter() {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                crc << 1 ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
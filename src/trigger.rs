@@ -0,0 +1,58 @@
+//! Pings fired by another process writing lines to a named pipe, for
+//! `--trigger-fifo`.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+
+/// Starts reading `path` on a thread and sends a `()` on the returned channel
+/// for every line read. A named pipe is reopened each time its writer closes
+/// it, so that any number of writers can take turns; a regular file is read
+/// once. Fails straight away if `path` doesn't exist.
+pub fn spawn(path: &str) -> anyhow::Result<Receiver<()>> {
+    check(path)?;
+    let path = PathBuf::from(path);
+    let (tx, rx) = channel();
+    std::thread::spawn(move || loop {
+        // Opening a named pipe waits until something opens it to write.
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) => {
+                eprintln!("Could not open trigger {}: {}", path.display(), err);
+                return;
+            }
+        };
+        for line in BufReader::new(file).lines() {
+            if line.is_err() || tx.send(()).is_err() {
+                return;
+            }
+        }
+        if !is_fifo(&path) {
+            return;
+        }
+    });
+    Ok(rx)
+}
+
+/// Fails if `path` doesn't exist, as `spawn` would, without reading it, for
+/// `--dry-run`.
+pub fn check(path: &str) -> anyhow::Result<()> {
+    if let Err(err) = std::fs::metadata(path) {
+        anyhow::bail!("can't open trigger {}: {}", path, err);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_fifo(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
+
+/// Only a Unix named pipe is reopened; elsewhere the path is read once.
+#[cfg(not(unix))]
+fn is_fifo(_path: &Path) -> bool {
+    false
+}
@@ -0,0 +1,109 @@
+//! Full-screen live view of a run, shown with `--tui`.
+
+use crate::StopReason;
+use audioping::DelayTrend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Sparkline};
+use std::collections::VecDeque;
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Number of recent measurements the rolling statistics and plot cover.
+const HISTORY: usize = 200;
+
+/// How long to wait for a key press between redraws.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Starts drawing `measurements` on a render thread until the sending side is
+/// dropped. Pressing `q` or Ctrl-C sends `StopReason::Interrupted` on `stop`.
+/// The terminal is restored before the thread exits.
+pub fn spawn(
+    measurements: Receiver<(f32, DelayTrend)>,
+    stop: Sender<StopReason>,
+) -> std::io::Result<JoinHandle<()>> {
+    let mut terminal = ratatui::try_init()?;
+    Ok(std::thread::spawn(move || {
+        let mut delays = VecDeque::with_capacity(HISTORY);
+        let mut jitter_ms = 0f32;
+        let mut count = 0u64;
+        loop {
+            loop {
+                match measurements.try_recv() {
+                    Ok((delay_ms, trend)) => {
+                        if delays.len() == HISTORY {
+                            delays.pop_front();
+                        }
+                        delays.push_back(delay_ms);
+                        jitter_ms = trend.jitter_ms;
+                        count += 1;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        ratatui::restore();
+                        return;
+                    }
+                }
+            }
+
+            let _ = terminal.draw(|frame| {
+                let [stats_area, plot_area] =
+                    Layout::vertical([Constraint::Length(6), Constraint::Min(3)])
+                        .areas(frame.area());
+                frame.render_widget(
+                    Paragraph::new(stats_lines(&delays, jitter_ms, count))
+                        .block(Block::bordered().title(" audioping - q to quit ")),
+                    stats_area,
+                );
+                // The sparkline only plots integers, so scale to hundredths of a ms.
+                let plot: Vec<u64> = delays
+                    .iter()
+                    .map(|delay_ms| (delay_ms.max(0.0) * 100.0) as u64)
+                    .collect();
+                let plot = &plot[plot.len().saturating_sub(plot_area.width as usize)..];
+                frame.render_widget(
+                    Sparkline::default()
+                        .block(Block::bordered().title(" delay "))
+                        .data(plot),
+                    plot_area,
+                );
+            });
+
+            if event::poll(REDRAW_INTERVAL).unwrap_or(false) {
+                if let Ok(Event::Key(key)) = event::read() {
+                    let ctrl_c = key.code == KeyCode::Char('c')
+                        && key.modifiers.contains(KeyModifiers::CONTROL);
+                    if key.kind == KeyEventKind::Press && (key.code == KeyCode::Char('q') || ctrl_c)
+                    {
+                        let _ = stop.send(StopReason::Interrupted);
+                    }
+                }
+            }
+        }
+    }))
+}
+
+/// Text block summarising the recent measurements.
+fn stats_lines(delays: &VecDeque<f32>, jitter_ms: f32, count: u64) -> Vec<Line<'static>> {
+    let current = match delays.back() {
+        Some(current) => current,
+        None => return vec![Line::from("Waiting for the first measurement...")],
+    };
+    let min = delays.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = delays.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mean = delays.iter().sum::<f32>() / delays.len() as f32;
+    vec![
+        Line::from(format!("Current: {:.2}ms", current)),
+        Line::from(format!(
+            "Last {}: min {:.2}ms, max {:.2}ms, avg {:.2}ms",
+            delays.len(),
+            min,
+            max,
+            mean
+        )),
+        Line::from(format!("Jitter: {:.2}ms", jitter_ms)),
+        Line::from(format!("Measurements: {}", count)),
+    ]
+}
@@ -0,0 +1,90 @@
+//! Writes on a thread of its own, so that the audio callbacks and the
+//! measurements only hand over what's to be written and never wait on a slow
+//! disk.
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::JoinHandle;
+
+/// The sending end of a writer thread.
+pub struct Writer<T> {
+    items: Sender<T>,
+    /// Items already written, sent back to be filled again, so that sending
+    /// doesn't allocate once writing is under way.
+    spare: Receiver<T>,
+}
+
+impl<T: Default> Writer<T> {
+    /// Fills an item with `fill` and queues it to be written. The item is one
+    /// already written when there is one, so `fill` replaces what it holds.
+    pub fn send(&mut self, fill: impl FnOnce(&mut T)) {
+        let mut item = self.spare.try_recv().unwrap_or_default();
+        fill(&mut item);
+        let _ = self.items.send(item);
+    }
+}
+
+/// Starts a thread that passes each item sent to `write`, in order, along with
+/// `state`. Once the `Writer` is dropped and everything sent is written, it
+/// hands `state` to `finish`, which joining the returned handle waits for.
+/// The first error stops the thread and is what joining returns.
+pub fn spawn<T, S, E>(
+    state: S,
+    write: impl FnMut(&mut S, &T) -> Result<(), E> + Send + 'static,
+    finish: impl FnOnce(S) -> Result<(), E> + Send + 'static,
+) -> (Writer<T>, JoinHandle<Result<(), E>>)
+where
+    T: Send + 'static,
+    S: Send + 'static,
+    E: Send + 'static,
+{
+    start(false, state, write, finish)
+}
+
+/// Like `spawn`, but items sent while the thread is still writing are skipped
+/// for the latest, for when only the latest matters.
+pub fn spawn_latest<T, S, E>(
+    state: S,
+    write: impl FnMut(&mut S, &T) -> Result<(), E> + Send + 'static,
+    finish: impl FnOnce(S) -> Result<(), E> + Send + 'static,
+) -> (Writer<T>, JoinHandle<Result<(), E>>)
+where
+    T: Send + 'static,
+    S: Send + 'static,
+    E: Send + 'static,
+{
+    start(true, state, write, finish)
+}
+
+fn start<T, S, E>(
+    latest_only: bool,
+    mut state: S,
+    mut write: impl FnMut(&mut S, &T) -> Result<(), E> + Send + 'static,
+    finish: impl FnOnce(S) -> Result<(), E> + Send + 'static,
+) -> (Writer<T>, JoinHandle<Result<(), E>>)
+where
+    T: Send + 'static,
+    S: Send + 'static,
+    E: Send + 'static,
+{
+    let (items_tx, items_rx) = channel::<T>();
+    let (spare_tx, spare_rx) = channel();
+    let thread = std::thread::spawn(move || {
+        while let Ok(mut item) = items_rx.recv() {
+            if latest_only {
+                while let Ok(latest) = items_rx.try_recv() {
+                    let _ = spare_tx.send(std::mem::replace(&mut item, latest));
+                }
+            }
+            write(&mut state, &item)?;
+            let _ = spare_tx.send(item);
+        }
+        finish(state)
+    });
+    (
+        Writer {
+            items: items_tx,
+            spare: spare_rx,
+        },
+        thread,
+    )
+}